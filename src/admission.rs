@@ -0,0 +1,144 @@
+// which client connections a node takes, and for how long.
+//
+// Every front end (native, RESP and HTTP) asks the same `Admission` before
+// serving a connection. Past `max_connections` in all, or
+// `max_connections_per_ip` from one address, a connection is refused
+// straight away, with an error in its own protocol, rather than left
+// waiting to be accepted. A connection that sends nothing for
+// `idle_timeout_ms` is closed. What was refused and closed is counted for
+// the status endpoint and INFO.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time;
+
+use crate::config::Config;
+
+/// Why a connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyConnections,
+    TooManyFromAddress,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::TooManyConnections => write!(f, "too many connections"),
+            Rejection::TooManyFromAddress => write!(f, "too many connections from this address"),
+        }
+    }
+}
+
+/// Counts since the node started, except `open`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AdmissionStats {
+    pub open: usize,
+    /// Refused for `max_connections`.
+    pub rejected: u64,
+    /// Refused for `max_connections_per_ip`.
+    pub rejected_per_ip: u64,
+    pub closed_idle: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    per_ip: HashMap<IpAddr, usize>,
+    stats: AdmissionStats,
+}
+
+#[derive(Debug)]
+struct Limits {
+    max_connections: usize,
+    /// 0 for no limit.
+    max_per_ip: usize,
+    idle_timeout: Option<Duration>,
+}
+
+/// The connection limits in force, shared by every front end.
+#[derive(Debug, Clone)]
+pub struct Admission {
+    limits: Arc<Limits>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Admission {
+    pub fn new(config: &Config) -> Admission {
+        let limits = Limits {
+            max_connections: config.max_connections,
+            max_per_ip: config.max_connections_per_ip,
+            idle_timeout: (config.idle_timeout_ms > 0).then(|| Duration::from_millis(config.idle_timeout_ms)),
+        };
+        let state = State {
+            per_ip: HashMap::new(),
+            stats: AdmissionStats::default(),
+        };
+        Admission {
+            limits: Arc::new(limits),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Lets a connection from `ip` in, for as long as the ticket is held.
+    pub fn admit(&self, ip: IpAddr) -> Result<Ticket, Rejection> {
+        let mut state = self.state.lock().unwrap();
+        if state.stats.open >= self.limits.max_connections {
+            state.stats.rejected += 1;
+            return Err(Rejection::TooManyConnections);
+        }
+        let from_ip = state.per_ip.entry(ip).or_default();
+        if self.limits.max_per_ip > 0 && *from_ip >= self.limits.max_per_ip {
+            state.stats.rejected_per_ip += 1;
+            return Err(Rejection::TooManyFromAddress);
+        }
+        *from_ip += 1;
+        state.stats.open += 1;
+        Ok(Ticket {
+            admission: self.clone(),
+            ip,
+        })
+    }
+
+    /// Completes once a connection has been idle too long, which is never
+    /// without an idle timeout. Start it afresh for every request.
+    pub async fn idle(&self) {
+        match self.limits.idle_timeout {
+            Some(timeout) => time::sleep(timeout).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// Counts a connection closed for idling.
+    pub fn closed_idle(&self) {
+        self.state.lock().unwrap().stats.closed_idle += 1;
+    }
+
+    pub fn stats(&self) -> AdmissionStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// A connection's place under the limits, given back when dropped.
+#[derive(Debug)]
+pub struct Ticket {
+    admission: Admission,
+    ip: IpAddr,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        state.stats.open -= 1;
+        if let Some(from_ip) = state.per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
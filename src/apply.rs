@@ -0,0 +1,38 @@
+// the state machine apply task.
+//
+// Committed entries arrive here in index order from the log loop. Applying
+// them on their own task means a slow client connection never holds up the
+// state machine: connection tasks only wait on a oneshot for their result,
+// or on `applied_index` to catch up before serving a read.
+
+use tokio::sync::{mpsc, oneshot, watch};
+use tracing::debug;
+
+use crate::command::Frame;
+use crate::handler::Db;
+use crate::log::{LogEntry, Reservation};
+
+/// A committed entry, and the client waiting on it if it was proposed
+/// here.
+#[derive(Debug)]
+pub(crate) struct Committed {
+    pub(crate) entry: LogEntry,
+    pub(crate) waiter: Option<(oneshot::Sender<Frame>, Reservation)>,
+}
+
+/// Applies committed entries until the log loop goes away. The channel is
+/// unbounded because the proposal limits already bound how much can be
+/// committed but not yet applied.
+pub(crate) async fn run(mut committed: mpsc::UnboundedReceiver<Committed>, db: Db, applied: watch::Sender<u64>) {
+    while let Some(Committed { entry, waiter }) = committed.recv().await {
+        let index = entry.index;
+        let result = db.apply_entry(index, entry.command);
+        applied.send_replace(index);
+        debug!(index, "applied entry");
+        if let Some((respond, reservation)) = waiter {
+            drop(reservation);
+            // the client may have hung up; the entry is applied regardless.
+            let _ = respond.send(result);
+        }
+    }
+}
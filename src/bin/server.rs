@@ -1,11 +1,36 @@
 use std::io;
+use std::process;
 use tokio::net::TcpListener;
 use tokio::signal;
 
+use scow::config::Config;
+use scow::server::{self, Listeners};
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:9999").await?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        println!("{}", Config::usage());
+        return Ok(());
+    }
+
+    let config = match Config::load(std::env::vars(), args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration: {}", err);
+            process::exit(2);
+        }
+    };
+
+    let mut listeners = Listeners::new(TcpListener::bind(config.client_addr).await?)
+        .with_peer(TcpListener::bind(config.peer_addr).await?);
+    if let Some(addr) = config.resp_addr {
+        listeners = listeners.with_resp(TcpListener::bind(addr).await?);
+    }
+    if let Some(addr) = config.http_addr {
+        listeners = listeners.with_http(TcpListener::bind(addr).await?);
+    }
 
-    scow::server::run(listener, signal::ctrl_c()).await;
+    server::run_with_config(listeners, config, signal::ctrl_c()).await;
     io::Result::Ok(())
 }
@@ -1,60 +1,521 @@
-use crate::command::Frame;
-use crate::connection::{Connection, Result};
+// native protocol client.
+//
+// Requests answer with the server's frame, or a `ClientError` for anything
+// that isn't a result: a missing key, a node that doesn't lead, an
+// overloaded leader, a deadline passed or a broken connection. Connecting,
+// and every request, has to finish within the client's timeout. Reads, which can
+// safely run twice, are retried under the client's `RetryPolicy` when the
+// connection breaks, the deadline passes or the leader is busy; nothing
+// else is, as the client has no session to make a repeat harmless.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::command::{
+    CasArgs, Event, FaultCommand, Frame, GetArgs, Hello, PrefixArgs, ScanArgs, Txn, WatchArgs, WatchCursor,
+};
+use crate::connection::{self, Connection};
 use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::{self, Instant};
 use tracing::debug;
 
+/// Why a request didn't get a result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    /// The key, or lease, doesn't exist.
+    NotFound,
+    /// This node doesn't lead, or its entry for the request was replaced
+    /// by another leader's. Nothing was written.
+    NotLeader,
+    /// The leader stepped down before the request was committed. The next
+    /// leader may still commit it, so a write may or may not have happened.
+    OutcomeUnknown,
+    /// No answer within the client's timeout. A write may or may not have
+    /// happened.
+    Timeout,
+    /// The leader is overloaded; try again after this long. Nothing was
+    /// written.
+    Busy(Duration),
+    /// The connection broke before the answer came. A write may or may not
+    /// have happened.
+    ConnectionClosed,
+    /// The server sent something the client can't make sense of.
+    Protocol(String),
+    /// The server refused the request for some other reason, given here.
+    Rejected(String),
+}
+
+impl ClientError {
+    /// Server errors are still strings on the wire; this is the one place
+    /// that reads them.
+    fn from_server(msg: String) -> ClientError {
+        let lease_not_found = msg.starts_with("lease ") && msg.ends_with(" not found");
+        if msg == "Key not found." || lease_not_found {
+            ClientError::NotFound
+        } else if msg == "not the leader" || msg.starts_with("proposal lost to a new leader") {
+            ClientError::NotLeader
+        } else if msg == "leader stepped down, outcome unknown" {
+            ClientError::OutcomeUnknown
+        } else {
+            ClientError::Rejected(msg)
+        }
+    }
+
+    /// A connection that failed under a request: I/O errors mean it broke,
+    /// anything else that the server made no sense.
+    fn from_connection(err: connection::Error) -> ClientError {
+        if err.downcast_ref::<io::Error>().is_some() {
+            debug!(cause = %err, "connection broke");
+            ClientError::ConnectionClosed
+        } else {
+            ClientError::Protocol(err.to_string())
+        }
+    }
+
+    /// Whether trying again could help. Only safe for idempotent requests.
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Timeout | ClientError::Busy(_) | ClientError::ConnectionClosed
+        )
+    }
+}
+
+impl Error for ClientError {}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::NotFound => write!(f, "not found"),
+            ClientError::NotLeader => write!(f, "not the leader"),
+            ClientError::OutcomeUnknown => write!(f, "leader stepped down, outcome unknown"),
+            ClientError::Timeout => write!(f, "request timed out"),
+            ClientError::Busy(retry_after) => write!(f, "server busy, retry after {:?}", retry_after),
+            ClientError::ConnectionClosed => write!(f, "connection closed"),
+            ClientError::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            ClientError::Rejected(msg) => msg.fmt(f),
+        }
+    }
+}
+
+/// How often, and how patiently, idempotent requests are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries in all, the first included. 1 never retries.
+    pub max_attempts: u32,
+    /// Wait before the first retry, doubled for every one after.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// How long to wait after failed try number `attempt`, counting from 1.
+    fn delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16);
+        (self.backoff * 2u32.pow(doublings)).min(self.max_backoff)
+    }
+}
+
+/// Requests only a timeout or a broken connection could make go wrong
+/// twice: they change nothing.
+fn idempotent(frame: &Frame) -> bool {
+    matches!(
+        frame,
+        Frame::Read(_)
+            | Frame::Get(_)
+            | Frame::Exists(_)
+            | Frame::Scan(_)
+            | Frame::Prefix(_)
+            | Frame::LeaseKeepAlive(_)
+    )
+}
+
+/// How long connecting and each request may take, unless `with_timeout`
+/// says otherwise.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Client {
-    connection: Connection,
+    addr: SocketAddr,
+    /// None after a request failed halfway, leaving the connection in an
+    /// unknown state; the next request dials again.
+    connection: Option<Connection>,
+    timeout: Duration,
+    retry: RetryPolicy,
 }
 
 impl Client {
-    pub async fn connect<T: ToSocketAddrs>(addr: T) -> Result<Client> {
-        let socket = TcpStream::connect(addr).await?;
-        let connection = Connection::new(socket);
-        Ok(Client { connection })
+    /// Connects with a 5 second timeout and the default `RetryPolicy`. The
+    /// handshake has to be done within the timeout too.
+    pub async fn connect<T: ToSocketAddrs>(addr: T) -> Result<Client, ClientError> {
+        let (addr, connection) = time::timeout(DEFAULT_TIMEOUT, open(addr))
+            .await
+            .unwrap_or(Err(ClientError::Timeout))?;
+        Ok(Client {
+            addr,
+            connection: Some(connection),
+            timeout: DEFAULT_TIMEOUT,
+            retry: RetryPolicy::default(),
+        })
+    }
+
+    /// How long each request may take in all, retries included.
+    pub fn with_timeout(mut self, timeout: Duration) -> Client {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Client {
+        self.retry = retry;
+        self
+    }
+
+    /// Answers with a `Frame::Value`.
+    pub async fn read(&mut self, key: &str) -> Result<Frame, ClientError> {
+        debug!("client writing READ command");
+        self.request(Frame::Read(key.to_string())).await
+    }
+
+    pub async fn write(&mut self, key: &str, val: &str) -> Result<Frame, ClientError> {
+        debug!("client writing WRITE command");
+        self.request(Frame::Write(key.to_string(), val.to_string())).await
     }
 
-    pub async fn read(&mut self, key: &str) -> Result<Frame> {
+    /// Like `read`, but answers with a `Frame::Entry` carrying the key's
+    /// revisions.
+    pub async fn get(&mut self, key: &str) -> Result<Frame, ClientError> {
         debug!("client writing GET command");
-        let frame = Frame::Read(key.to_string());
-        println!("sending {} over the wire!", frame);
-        self.connection
-            .write(format!("{}", frame).as_str())
-            .await?;
+        self.request(Frame::Get(GetArgs {
+            key: key.to_string(),
+            revision: None,
+        }))
+        .await
+    }
 
-        debug!("client wrote GET. waiting on response.");
-        self.read_response_frame().await
+    /// Reads `key` as it was at `revision`.
+    pub async fn get_at(&mut self, key: &str, revision: u64) -> Result<Frame, ClientError> {
+        debug!("client writing GET command at revision {}", revision);
+        self.request(Frame::Get(GetArgs {
+            key: key.to_string(),
+            revision: Some(revision),
+        }))
+        .await
     }
 
-    pub async fn write(&mut self, key: &str, val: &str) -> Result<Frame> {
-        debug!("client writing SET command");
-        let frame = Frame::Write(key.to_string(), val.to_string());
-        println!("sending {} over the wire!", frame);
-        self.connection
-            .write(format!("{}", frame).as_str())
-            .await?;
+    /// Drops history older than `revision`; reads before it fail afterwards.
+    pub async fn compact(&mut self, revision: u64) -> Result<Frame, ClientError> {
+        debug!("client writing COMPACT command");
+        self.request(Frame::Compact(revision)).await
+    }
 
-        debug!("client wrote SET. waiting on response.");
-        self.read_response_frame().await
+    pub async fn delete(&mut self, key: &str) -> Result<Frame, ClientError> {
+        debug!("client writing DELETE command");
+        self.request(Frame::Delete(key.to_string())).await
     }
 
-    async fn read_response_frame(&mut self) -> Result<Frame> {
-        loop {
-            debug!("client read_response");
+    pub async fn exists(&mut self, key: &str) -> Result<Frame, ClientError> {
+        debug!("client writing EXISTS command");
+        self.request(Frame::Exists(key.to_string())).await
+    }
 
-            let maybe_frame = tokio::select! {
-                res = self.connection.read_frame() => res?
+    /// Sets `key` to `new` only if it currently holds `expected` (`None`
+    /// meaning the key must be absent). Answers with a `Frame::CasResult`.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        new: &str,
+    ) -> Result<Frame, ClientError> {
+        debug!("client writing CAS command");
+        self.request(Frame::CompareAndSwap(CasArgs {
+            key: key.to_string(),
+            expected: expected.map(String::from),
+            new: new.to_string(),
+        }))
+        .await
+    }
+
+    /// Reads keys in `[start, end)` in order, answering with a `Frame::Page`.
+    /// To continue, call again with the page's `next` as `start`.
+    pub async fn scan(&mut self, start: &str, end: Option<&str>, limit: u32) -> Result<Frame, ClientError> {
+        debug!("client writing SCAN command");
+        self.request(Frame::Scan(ScanArgs {
+            start: start.to_string(),
+            end: end.map(String::from),
+            limit,
+        }))
+        .await
+    }
+
+    /// Reads keys starting with `prefix` in order, answering with a
+    /// `Frame::Page`. To continue, call again with the page's `next` as `from`.
+    pub async fn prefix(&mut self, prefix: &str, from: Option<&str>, limit: u32) -> Result<Frame, ClientError> {
+        debug!("client writing PREFIX command");
+        self.request(Frame::Prefix(PrefixArgs {
+            prefix: prefix.to_string(),
+            from: from.map(String::from),
+            limit,
+        }))
+        .await
+    }
+
+    /// Writes every key in `batch` in one round trip, committed as a single
+    /// entry and applied atomically.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<Frame, ClientError> {
+        debug!("client writing WRITEBATCH command with {} keys", batch.len());
+        self.request(Frame::WriteBatch(batch.entries)).await
+    }
+
+    /// Runs a compare-then-apply transaction, answering with a
+    /// `Frame::TxnResult`.
+    pub async fn txn(&mut self, txn: Txn) -> Result<Frame, ClientError> {
+        debug!("client writing TXN command");
+        self.request(Frame::Txn(txn)).await
+    }
+
+    /// Grants a lease that lives for `ttl` seconds unless kept alive.
+    /// Answers with a `Frame::Lease` carrying its id.
+    pub async fn lease_grant(&mut self, ttl: u64) -> Result<Frame, ClientError> {
+        debug!("client writing LEASEGRANT command");
+        self.request(Frame::LeaseGrant(ttl)).await
+    }
+
+    pub async fn lease_keep_alive(&mut self, lease: u64) -> Result<Frame, ClientError> {
+        debug!("client writing LEASEKEEPALIVE command");
+        self.request(Frame::LeaseKeepAlive(lease)).await
+    }
+
+    /// Revokes a lease right away, deleting every key attached to it.
+    pub async fn lease_revoke(&mut self, lease: u64) -> Result<Frame, ClientError> {
+        debug!("client writing LEASEREVOKE command");
+        self.request(Frame::LeaseRevoke(lease)).await
+    }
+
+    /// Changes the faults the node injects into its consensus traffic. The
+    /// node has to be running with `fault_injection`.
+    pub async fn fault(&mut self, command: FaultCommand) -> Result<Frame, ClientError> {
+        debug!("client writing FAULT command");
+        self.request(Frame::Fault(command)).await
+    }
+
+    /// Like `write`, but the key is deleted when `lease` goes away.
+    pub async fn write_with_lease(&mut self, key: &str, val: &str, lease: u64) -> Result<Frame, ClientError> {
+        debug!("client writing LEASEPUT command");
+        self.request(Frame::LeasePut(key.to_string(), lease, val.to_string()))
+            .await
+    }
+
+    /// Turns this connection into a stream of changes to `key` (or to every
+    /// key under it, if `prefix`), starting at `start_revision`. Zero means
+    /// only changes made from now on. The watch has to start within the
+    /// timeout.
+    pub async fn watch(self, key: &str, prefix: bool, start_revision: u64) -> Result<Watcher, ClientError> {
+        let args = WatchArgs {
+            key: key.to_string(),
+            prefix,
+            start_revision,
+        };
+        self.start_watch(args, None).await
+    }
+
+    async fn start_watch(self, args: WatchArgs, cursor: Option<WatchCursor>) -> Result<Watcher, ClientError> {
+        let timeout = self.timeout;
+        let start = async {
+            let connection = match self.connection {
+                Some(connection) => connection,
+                None => self.dial().await?,
             };
+            Watcher::start(connection, args, cursor).await
+        };
+        time::timeout(timeout, start).await.unwrap_or(Err(ClientError::Timeout))
+    }
 
-            let frame = match maybe_frame {
-                Some(frame) => frame,
-                None => {
-                    debug!("didn't get a response frame?");
-                    todo!();
-                }
+    /// Sends `frame` and waits for the answer, within the timeout and
+    /// retrying as the policy allows.
+    async fn request(&mut self, frame: Frame) -> Result<Frame, ClientError> {
+        let deadline = Instant::now() + self.timeout;
+        let retry = idempotent(&frame);
+        let mut attempt = 1;
+        loop {
+            let result = time::timeout_at(deadline, self.attempt(&frame))
+                .await
+                .unwrap_or(Err(ClientError::Timeout));
+            let err = match result {
+                Err(err) if retry && err.is_transient() && attempt < self.retry.max_attempts => err,
+                result => return result,
             };
+            let mut delay = self.retry.delay(attempt);
+            if let ClientError::Busy(retry_after) = err {
+                delay = delay.max(retry_after);
+            }
+            if Instant::now() + delay >= deadline {
+                return Err(err);
+            }
+            debug!(cause = %err, ?delay, attempt, "retrying request");
+            time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn attempt(&mut self, frame: &Frame) -> Result<Frame, ClientError> {
+        // taken for the duration, so a request cut short by the deadline
+        // takes the connection, and the answer still on its way, with it.
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => self.dial().await?,
+        };
+        if let Err(err) = connection.write(&frame.to_string()).await {
+            debug!(cause = %err, "failed to send request");
+            return Err(ClientError::ConnectionClosed);
+        }
+        let answer = match connection.read_frame().await {
+            Ok(Some(answer)) => answer,
+            Ok(None) => return Err(ClientError::ConnectionClosed),
+            Err(err) => return Err(ClientError::from_connection(err)),
+        };
+        self.connection = Some(connection);
+        match answer {
+            Frame::Error(msg) => Err(ClientError::from_server(msg)),
+            Frame::Busy(ms) => Err(ClientError::Busy(Duration::from_millis(ms))),
+            Frame::Hello(_) | Frame::Raft(_) => Err(ClientError::Protocol(format!("unexpected response {:?}", answer))),
+            answer => Ok(answer),
+        }
+    }
+
+    async fn dial(&self) -> Result<Connection, ClientError> {
+        debug!(addr = %self.addr, "redialling");
+        let (_, connection) = open(self.addr).await?;
+        Ok(connection)
+    }
+}
+
+/// Connects to `addr` and shakes hands. A server that answers the HELLO
+/// with an error has turned the client away.
+async fn open<T: ToSocketAddrs>(addr: T) -> Result<(SocketAddr, Connection), ClientError> {
+    let socket = TcpStream::connect(addr).await.map_err(|err| {
+        debug!(cause = %err, "failed to connect");
+        ClientError::ConnectionClosed
+    })?;
+    let addr = socket.peer_addr().map_err(|_| ClientError::ConnectionClosed)?;
+    let mut connection = Connection::new(socket);
+    match connection.handshake(&Hello::client()).await {
+        Ok(_) => Ok((addr, connection)),
+        Err(err) if err.downcast_ref::<io::Error>().is_some() => Err(ClientError::ConnectionClosed),
+        Err(err) => Err(ClientError::Rejected(err.to_string())),
+    }
+}
+
+/// Puts to send together with `Client::write_batch`.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    entries: Vec<(String, String)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn put(mut self, key: &str, val: &str) -> WriteBatch {
+        self.push(key, val);
+        self
+    }
+
+    /// Same as `put`, for filling a batch in a loop.
+    pub fn push(&mut self, key: &str, val: &str) {
+        self.entries.push((key.to_string(), val.to_string()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
 
-            return Ok(frame);
+/// A stream of changes, opened with `Client::watch`.
+pub struct Watcher {
+    connection: Connection,
+    args: WatchArgs,
+    cursor: WatchCursor,
+}
+
+impl Watcher {
+    /// Opens the watch. A resumed one carries on with its `cursor`, a new
+    /// one starts at the revision the server answers with.
+    async fn start(
+        mut connection: Connection,
+        args: WatchArgs,
+        cursor: Option<WatchCursor>,
+    ) -> Result<Watcher, ClientError> {
+        debug!("client writing WATCH command");
+        if let Err(err) = connection.write(&Frame::Watch(args.clone()).to_string()).await {
+            debug!(cause = %err, "failed to write WATCH");
+            return Err(ClientError::ConnectionClosed);
+        }
+        match connection.read_frame().await.map_err(ClientError::from_connection)? {
+            Some(Frame::Watch(opened)) => Ok(Watcher {
+                connection,
+                args,
+                cursor: cursor.unwrap_or(WatchCursor::starting_at(opened.start_revision)),
+            }),
+            Some(Frame::Error(e)) => Err(ClientError::from_server(e)),
+            Some(other) => Err(ClientError::Protocol(format!("unexpected response to WATCH: {:?}", other))),
+            None => Err(ClientError::ConnectionClosed),
         }
     }
+
+    /// Waits for the next change. Returns None if the server ended the watch.
+    pub async fn next_event(&mut self) -> Result<Option<Event>, ClientError> {
+        loop {
+            match self.connection.read_frame().await.map_err(ClientError::from_connection)? {
+                Some(Frame::Event(event)) => {
+                    if self.cursor.advance(&event) {
+                        return Ok(Some(event));
+                    }
+                }
+                Some(Frame::Error(e)) => return Err(ClientError::from_server(e)),
+                Some(other) => return Err(ClientError::Protocol(format!("unexpected frame in watch: {:?}", other))),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Revision of the last event handed out, or the one the watch started
+    /// at if there wasn't one.
+    pub fn last_revision(&self) -> u64 {
+        self.cursor.revision
+    }
+
+    /// Reopens the watch on `addr`, which can be any node, carrying on
+    /// after the last event seen here.
+    pub async fn resume<T: ToSocketAddrs>(self, addr: T) -> Result<Watcher, ClientError> {
+        let client = Client::connect(addr).await?;
+        let mut args = self.args;
+        let mut cursor = self.cursor;
+        args.start_revision = cursor.revision;
+        cursor.resume();
+        client.start_watch(args, Some(cursor)).await
+    }
 }
@@ -0,0 +1,105 @@
+// time, as the server sees it.
+//
+// Everything that waits on a timeout (consensus ticks, lease deadlines)
+// asks a `Clock` rather than the runtime, so a test can swap in a
+// `ManualClock` and move time forward itself: elections and expiries then
+// happen exactly when the test says, without sleeping.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::watch;
+
+/// A timer from `Clock::sleep_until`.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// Monotonic: never goes backwards.
+    fn now(&self) -> Instant;
+
+    /// Completes once `now()` reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// The runtime's clock. Follows tokio's notion of time, so it also works
+/// with paused or simulated time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<watch::Sender<Instant>>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Arc::new(watch::channel(Instant::now()).0),
+        }
+    }
+
+    /// Moves time forward, firing every timer that comes due.
+    pub fn advance(&self, by: Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // the sender lives as long as any clone of the clock; if they
+            // are all gone, time has stopped and so does the timer.
+            if now.wait_for(|now| *now >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+/// Ticks every `period` of a clock's time. Each tick is a period after the
+/// last one fired, so ticks missed while busy are skipped rather than
+/// bursting to catch up.
+#[derive(Debug)]
+pub struct Interval {
+    clock: Arc<dyn Clock>,
+    period: Duration,
+    next: Instant,
+}
+
+impl Interval {
+    /// The first tick is one `period` from now.
+    pub fn new(clock: Arc<dyn Clock>, period: Duration) -> Interval {
+        let next = clock.now() + period;
+        Interval { clock, period, next }
+    }
+
+    pub async fn tick(&mut self) {
+        self.clock.sleep_until(self.next).await;
+        self.next = self.clock.now() + self.period;
+    }
+}
@@ -4,9 +4,12 @@
 use std::fmt;
 use std::io::Cursor;
 use std::string::FromUtf8Error;
+use std::time::Duration;
 
 use crate::connection::Error;
-use crate::consensus::ServerId;
+use crate::consensus::Message;
+use crate::fault::LinkFaults;
+use crate::log::LogEntry;
 use bytes::Buf;
 use tracing::debug;
 
@@ -17,15 +20,367 @@ pub enum Frame {
     Success,
     Value(String),
     Error(String),
-    RequestVote(RequestVoteArgs),
-    Vote(String),
+    Hello(Hello),
+    Delete(String),
+    Exists(String),
+    CompareAndSwap(CasArgs),
+    Bool(bool),
+    CasResult(CasResult),
+    Scan(ScanArgs),
+    Prefix(PrefixArgs),
+    Page(Page),
+    Get(GetArgs),
+    Entry(KeyValue),
+    Compact(u64),
+    Watch(WatchArgs),
+    Event(Event),
+    LeaseGrant(u64),
+    LeaseKeepAlive(u64),
+    LeaseRevoke(u64),
+    LeasePut(String, u64, String),
+    Lease(LeaseInfo),
+    Txn(Txn),
+    TxnResult(TxnResult),
+    WriteBatch(Vec<(String, String)>),
+    /// The server is overloaded; try again after this many milliseconds.
+    Busy(u64),
+    /// Liveness check, answered with OK.
+    Ping,
+    /// Node to node consensus traffic. One way, never answered directly.
+    Raft(Message),
+    /// Changes the faults a node injects into its consensus traffic. Only
+    /// accepted with `fault_injection` on.
+    Fault(FaultCommand),
 }
 
+/// Replace `key` with `new` only if its current value is `expected`
+/// (`None` meaning the key must not exist).
+#[derive(Clone, Debug, PartialEq)]
+pub struct CasArgs {
+    pub key: String,
+    pub expected: Option<String>,
+    pub new: String,
+}
+
+/// Keys in `[start, end)`, in order. Pass a page's `next` back in as
+/// `start` to get the following page.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanArgs {
+    pub start: String,
+    pub end: Option<String>,
+    pub limit: u32,
+}
+
+/// Keys beginning with `prefix`, in order, starting at `from` if set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixArgs {
+    pub prefix: String,
+    pub from: Option<String>,
+    pub limit: u32,
+}
+
+/// One page of scan results. `next` is the continuation token: the first
+/// key that didn't fit, or `None` once the range is exhausted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    pub entries: Vec<(String, String)>,
+    pub next: Option<String>,
+}
+
+/// Read a key with its revisions, optionally as of an older `revision`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetArgs {
+    pub key: String,
+    pub revision: Option<u64>,
+}
+
+/// A key's value and revisions. Every command applied from the log is the
+/// store's next revision, i.e. its log index less the empty entries leaders
+/// start their terms with. `create_revision` is where the key was
+/// (re)created, `mod_revision` where it was last written, and `version`
+/// counts writes since it was created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: String,
+    pub create_revision: u64,
+    pub mod_revision: u64,
+    pub version: u64,
+}
+
+/// Stream changes to `key` (or every key under it, if `prefix`) starting
+/// at `start_revision`. Zero means only changes from now on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WatchArgs {
+    pub key: String,
+    pub prefix: bool,
+    pub start_revision: u64,
+}
+
+impl WatchArgs {
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        if self.prefix {
+            key.starts_with(&self.key)
+        } else {
+            key == self.key
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Put,
+    Delete,
+}
+
+/// A change applied to the state machine. For deletes, `kv` only carries
+/// the key and the revision of the delete.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub kv: KeyValue,
+}
+
+/// How far through the event stream a watcher has got. Several events can
+/// share a revision, so it also counts how many of those were seen.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct WatchCursor {
+    pub(crate) revision: u64,
+    seen: usize,
+    skip: usize,
+}
+
+impl WatchCursor {
+    /// A cursor for a watch opened at `revision`, with nothing delivered
+    /// yet. Events before it are skipped.
+    pub(crate) fn starting_at(revision: u64) -> WatchCursor {
+        WatchCursor {
+            revision,
+            ..WatchCursor::default()
+        }
+    }
+
+    /// Call before replaying events from `self.revision` onwards, so the
+    /// ones already delivered at that revision are skipped.
+    pub(crate) fn resume(&mut self) {
+        self.skip = self.seen;
+    }
+
+    /// Records `event`, returning false if it was already delivered.
+    pub(crate) fn advance(&mut self, event: &Event) -> bool {
+        let revision = event.kv.mod_revision;
+        if revision < self.revision {
+            return false;
+        }
+        if revision == self.revision {
+            if self.skip > 0 {
+                self.skip -= 1;
+                return false;
+            }
+            self.seen += 1;
+        } else {
+            self.revision = revision;
+            self.seen = 1;
+            self.skip = 0;
+        }
+        true
+    }
+}
+
+/// Compare-then-apply: if every comparison holds, run `success`, otherwise
+/// run `failure`. The whole thing is applied as one entry at one revision.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Txn {
+    pub compares: Vec<Compare>,
+    pub success: Vec<TxnOp>,
+    pub failure: Vec<TxnOp>,
+}
+
+impl Txn {
+    pub fn new() -> Txn {
+        Txn::default()
+    }
+
+    pub fn when(mut self, compare: Compare) -> Txn {
+        self.compares.push(compare);
+        self
+    }
+
+    pub fn and_then(mut self, op: TxnOp) -> Txn {
+        self.success.push(op);
+        self
+    }
+
+    pub fn or_else(mut self, op: TxnOp) -> Txn {
+        self.failure.push(op);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compare {
+    pub key: String,
+    pub target: CompareTarget,
+}
+
+/// What a comparison checks the key's current state against. A missing
+/// key has version 0 and never matches a value.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompareTarget {
+    Value(String),
+    Version(u64),
+    Exists(bool),
+}
+
+impl Compare {
+    pub fn value(key: &str, value: &str) -> Compare {
+        Compare {
+            key: key.to_string(),
+            target: CompareTarget::Value(value.to_string()),
+        }
+    }
+
+    pub fn version(key: &str, version: u64) -> Compare {
+        Compare {
+            key: key.to_string(),
+            target: CompareTarget::Version(version),
+        }
+    }
+
+    pub fn exists(key: &str, exists: bool) -> Compare {
+        Compare {
+            key: key.to_string(),
+            target: CompareTarget::Exists(exists),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnOp {
+    Put(String, String),
+    Delete(String),
+    Get(String),
+}
+
+impl TxnOp {
+    pub fn put(key: &str, value: &str) -> TxnOp {
+        TxnOp::Put(key.to_string(), value.to_string())
+    }
+
+    pub fn delete(key: &str) -> TxnOp {
+        TxnOp::Delete(key.to_string())
+    }
+
+    pub fn get(key: &str) -> TxnOp {
+        TxnOp::Get(key.to_string())
+    }
+}
+
+/// Which branch ran, and one response per op in that branch.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxnResult {
+    pub succeeded: bool,
+    pub responses: Vec<TxnResponse>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnResponse {
+    /// The revision the put was written at.
+    Put(u64),
+    /// Whether there was anything to delete.
+    Delete(bool),
+    Get(Option<KeyValue>),
+}
+
+/// A lease and its time-to-live in seconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LeaseInfo {
+    pub id: u64,
+    pub ttl: u64,
+}
+
+/// Outcome of a compare-and-swap, with the value the key holds afterwards.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CasResult {
+    pub succeeded: bool,
+    pub current: Option<String>,
+}
+
+/// First frame sent in each direction on a new connection. Clients leave
+/// `node_id` and `cluster_id` empty, peers always fill them in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hello {
+    pub version: u32,
+    pub node_id: Option<u32>,
+    pub cluster_id: Option<String>,
+    pub features: Vec<String>,
+}
+
+/// Wire protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional capabilities advertised in HELLO.
+pub const FEATURES: &[&str] = &["kv", "cas", "scan", "mvcc", "watch", "lease", "txn", "batch"];
+
+impl Hello {
+    pub fn client() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            node_id: None,
+            cluster_id: None,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    pub fn node(node_id: u32, cluster_id: &str) -> Hello {
+        Hello {
+            node_id: Some(node_id),
+            cluster_id: Some(cluster_id.to_string()),
+            ..Hello::client()
+        }
+    }
+
+    /// Checks whether `remote` can talk to us. A missing cluster id is
+    /// allowed (plain clients don't know it), a different one is not.
+    pub fn check_compatible(&self, remote: &Hello) -> Result<(), String> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&remote.version) {
+            return Err(format!(
+                "incompatible protocol version {} (supported: {}..={})",
+                remote.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+        if let (Some(ours), Some(theirs)) = (&self.cluster_id, &remote.cluster_id) {
+            if ours != theirs {
+                return Err(format!(
+                    "cluster id mismatch: expected {}, got {}",
+                    ours, theirs
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// An admin command for `fault::Faults`, as
+/// `FAULT CUT <from> <to>`,
+/// `FAULT LINK <from|*> <to|*> <drop> <duplicate> <reorder> <delay ms> <jitter ms>`
+/// or `FAULT HEAL`.
 #[derive(Debug, Clone, PartialEq)]
-pub struct RequestVoteArgs {
-    term: u64,
-    candidate_id: ServerId,
-    last_log: u64,
+pub enum FaultCommand {
+    Cut { from: u32, to: u32 },
+    Link {
+        from: Option<u32>,
+        to: Option<u32>,
+        faults: LinkFaults,
+    },
+    Heal,
 }
 
 #[derive(Debug)]
@@ -37,13 +392,191 @@ pub enum CmdError {
 impl std::fmt::Display for Frame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Frame::Read(s) => write!(f, "READ {}\r\n", s),
-            Frame::Write(s, v) => write!(f, "WRITE {} {}\r\n", s, v),
+            Frame::Read(k) => write!(f, "READ {}\r\n", lp(k)),
+            Frame::Write(k, v) => write!(f, "WRITE {} {}\r\n", lp(k), lp(v)),
             Frame::Success => write!(f, "OK\r\n"),
-            Frame::Value(s) => write!(f, "VALUE {}\r\n", s),
-            Frame::Error(e) => write!(f, "ERR {}\r\n", e),
-            Frame::RequestVote(a ) => write!(f, "REQVOTE {} {} {}\r\n", a.term, a.candidate_id, a.last_log),
-            Frame::Vote(s) => write!(f, "VOTE {}\r\n", s),
+            Frame::Value(v) => write!(f, "VALUE {}\r\n", lp(v)),
+            // escaped like any other string, so a line break can't end the frame early.
+            Frame::Error(e) => write!(f, "ERR {}\r\n", escape(e)),
+            Frame::Hello(h) => {
+                let node_id = h.node_id.map_or("-".to_string(), |id| id.to_string());
+                let cluster_id = h.cluster_id.as_deref().unwrap_or("-");
+                let features = if h.features.is_empty() {
+                    "-".to_string()
+                } else {
+                    h.features.join(",")
+                };
+                write!(f, "HELLO {} {} {} {}\r\n", h.version, node_id, cluster_id, features)
+            }
+            Frame::Delete(k) => write!(f, "DELETE {}\r\n", lp(k)),
+            Frame::Exists(k) => write!(f, "EXISTS {}\r\n", lp(k)),
+            Frame::CompareAndSwap(c) => {
+                write!(f, "CAS {} {} {}\r\n", lp(&c.key), opt_lp(&c.expected), lp(&c.new))
+            }
+            Frame::Bool(b) => write!(f, "BOOL {}\r\n", *b as u8),
+            Frame::CasResult(r) => {
+                write!(f, "CASRESULT {} {}\r\n", r.succeeded as u8, opt_lp(&r.current))
+            }
+            Frame::Scan(a) => write!(f, "SCAN {} {} {}\r\n", lp(&a.start), opt_lp(&a.end), a.limit),
+            Frame::Prefix(a) => {
+                write!(f, "PREFIX {} {} {}\r\n", lp(&a.prefix), opt_lp(&a.from), a.limit)
+            }
+            Frame::Get(a) => match a.revision {
+                Some(rev) => write!(f, "GET {} {}\r\n", lp(&a.key), rev),
+                None => write!(f, "GET {} -\r\n", lp(&a.key)),
+            },
+            Frame::Entry(kv) => write!(
+                f,
+                "ENTRY {} {} {} {} {}\r\n",
+                lp(&kv.key),
+                lp(&kv.value),
+                kv.create_revision,
+                kv.mod_revision,
+                kv.version
+            ),
+            Frame::Compact(rev) => write!(f, "COMPACT {}\r\n", rev),
+            Frame::LeaseGrant(ttl) => write!(f, "LEASEGRANT {}\r\n", ttl),
+            Frame::LeaseKeepAlive(id) => write!(f, "LEASEKEEPALIVE {}\r\n", id),
+            Frame::LeaseRevoke(id) => write!(f, "LEASEREVOKE {}\r\n", id),
+            Frame::LeasePut(k, lease, v) => write!(f, "LEASEPUT {} {} {}\r\n", lp(k), lease, lp(v)),
+            Frame::Lease(l) => write!(f, "LEASE {} {}\r\n", l.id, l.ttl),
+            Frame::Busy(ms) => write!(f, "BUSY {}\r\n", ms),
+            Frame::Ping => write!(f, "PING\r\n"),
+            Frame::Raft(Message::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            }) => write!(f, "RAFT REQVOTE {} {} {}\r\n", term, last_log_index, last_log_term),
+            Frame::Raft(Message::Vote { term, granted }) => {
+                write!(f, "RAFT VOTE {} {}\r\n", term, u8::from(*granted))
+            }
+            Frame::Raft(Message::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            }) => {
+                write!(
+                    f,
+                    "RAFT APPEND {} {} {} {} {}",
+                    term,
+                    prev_log_index,
+                    prev_log_term,
+                    leader_commit,
+                    entries.len()
+                )?;
+                for entry in entries {
+                    write!(f, " {} {} {}", entry.term, entry.index, lp(&entry.command.to_string()))?;
+                }
+                write!(f, "\r\n")
+            }
+            Frame::Raft(Message::AppendResponse {
+                term,
+                success,
+                match_index,
+            }) => write!(f, "RAFT APPENDED {} {} {}\r\n", term, u8::from(*success), match_index),
+            Frame::Raft(Message::TimeoutNow { term }) => write!(f, "RAFT TIMEOUTNOW {}\r\n", term),
+            Frame::Raft(Message::ReadIndex { term, seq }) => write!(f, "RAFT READINDEX {} {}\r\n", term, seq),
+            Frame::Raft(Message::ReadIndexResponse { term, seq }) => {
+                write!(f, "RAFT READINDEXED {} {}\r\n", term, seq)
+            }
+            Frame::Fault(FaultCommand::Cut { from, to }) => write!(f, "FAULT CUT {} {}\r\n", from, to),
+            Frame::Fault(FaultCommand::Link { from, to, faults }) => {
+                let node = |id: &Option<u32>| id.map_or("*".to_string(), |id| id.to_string());
+                write!(
+                    f,
+                    "FAULT LINK {} {} {} {} {} {} {}\r\n",
+                    node(from),
+                    node(to),
+                    faults.drop,
+                    faults.duplicate,
+                    faults.reorder,
+                    faults.delay.as_millis(),
+                    faults.jitter.as_millis()
+                )
+            }
+            Frame::Fault(FaultCommand::Heal) => write!(f, "FAULT HEAL\r\n"),
+            Frame::WriteBatch(entries) => {
+                write!(f, "WRITEBATCH {}", entries.len())?;
+                for (k, v) in entries {
+                    write!(f, " {} {}", lp(k), lp(v))?;
+                }
+                write!(f, "\r\n")
+            }
+            Frame::Txn(t) => {
+                write!(f, "TXN {}", t.compares.len())?;
+                for c in &t.compares {
+                    match &c.target {
+                        CompareTarget::Value(v) => write!(f, " {} VALUE {}", lp(&c.key), lp(v))?,
+                        CompareTarget::Version(n) => write!(f, " {} VERSION {}", lp(&c.key), n)?,
+                        CompareTarget::Exists(e) => {
+                            write!(f, " {} EXISTS {}", lp(&c.key), *e as u8)?
+                        }
+                    }
+                }
+                for branch in [&t.success, &t.failure] {
+                    write!(f, " {}", branch.len())?;
+                    for op in branch {
+                        match op {
+                            TxnOp::Put(k, v) => write!(f, " PUT {} {}", lp(k), lp(v))?,
+                            TxnOp::Delete(k) => write!(f, " DEL {}", lp(k))?,
+                            TxnOp::Get(k) => write!(f, " GET {}", lp(k))?,
+                        }
+                    }
+                }
+                write!(f, "\r\n")
+            }
+            Frame::TxnResult(r) => {
+                write!(f, "TXNRESULT {} {}", r.succeeded as u8, r.responses.len())?;
+                for response in &r.responses {
+                    match response {
+                        TxnResponse::Put(rev) => write!(f, " PUT {}", rev)?,
+                        TxnResponse::Delete(d) => write!(f, " DEL {}", *d as u8)?,
+                        TxnResponse::Get(None) => write!(f, " GET -")?,
+                        TxnResponse::Get(Some(kv)) => write!(
+                            f,
+                            " GET {} {} {} {} {}",
+                            lp(&kv.key),
+                            lp(&kv.value),
+                            kv.create_revision,
+                            kv.mod_revision,
+                            kv.version
+                        )?,
+                    }
+                }
+                write!(f, "\r\n")
+            }
+            Frame::Watch(w) => write!(
+                f,
+                "WATCH {} {} {}\r\n",
+                lp(&w.key),
+                w.prefix as u8,
+                w.start_revision
+            ),
+            Frame::Event(e) => {
+                let kind = match e.kind {
+                    EventKind::Put => "PUT",
+                    EventKind::Delete => "DELETE",
+                };
+                write!(
+                    f,
+                    "EVENT {} {} {} {} {} {}\r\n",
+                    kind,
+                    lp(&e.kv.key),
+                    lp(&e.kv.value),
+                    e.kv.create_revision,
+                    e.kv.mod_revision,
+                    e.kv.version
+                )
+            }
+            Frame::Page(p) => {
+                write!(f, "PAGE {} {}", opt_lp(&p.next), p.entries.len())?;
+                for (k, v) in &p.entries {
+                    write!(f, " {} {}", lp(k), lp(v))?;
+                }
+                write!(f, "\r\n")
+            }
         }
     }
 }
@@ -78,88 +611,426 @@ impl From<FromUtf8Error> for CmdError {
 }
 
 impl Frame {
+    /// Whether this command changes the state machine, and so has to go
+    /// through the log rather than being answered from local state.
+    pub(crate) fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Frame::Write(_, _)
+                | Frame::Delete(_)
+                | Frame::CompareAndSwap(_)
+                | Frame::Compact(_)
+                | Frame::Txn(_)
+                | Frame::WriteBatch(_)
+                | Frame::LeaseGrant(_)
+                | Frame::LeaseRevoke(_)
+                | Frame::LeasePut(_, _, _)
+        )
+    }
+}
+
+const COMMANDS: &[&str] = &[
+    "READ", "WRITE", "OK", "VALUE", "ERR", "HELLO", "DELETE", "EXISTS", "CAS",
+    "BOOL", "CASRESULT", "SCAN", "PREFIX", "PAGE", "GET", "ENTRY", "COMPACT",
+    "WATCH", "EVENT", "LEASEGRANT", "LEASEKEEPALIVE", "LEASEREVOKE", "LEASEPUT", "LEASE",
+    "TXN", "TXNRESULT", "WRITEBATCH", "BUSY", "PING", "RAFT", "FAULT",
+];
+
+impl Frame {
+    /// Parses a buffer holding exactly one frame, for transports that carry
+    /// frames in datagrams rather than a stream.
+    pub fn decode(src: &[u8]) -> Result<Frame, CmdError> {
+        let mut cursor = Cursor::new(src);
+        Frame::check(&mut cursor)?;
+        cursor.set_position(0);
+        Frame::parse(&mut cursor)
+    }
+
     pub(crate) fn check(src: &mut Cursor<&[u8]>) -> Result<(), CmdError> {
         debug!("check");
-        match get_u8(src)? {
-            b'R' => {
-                debug!("u8 read READ command");
-                let line = get_line(src)?;
-                let _linestr = match String::from_utf8(line.to_vec()) {
-                    Ok(v) => v,
-                    Err(e) => panic!("invalid utf-8 syntax in read cmd: {:?}", e),
+        // every frame is a single line, so just make sure the whole line is
+        // here and starts with a command we know about.
+        let line = get_line(src)?;
+        let cmd = line.split(|b| *b == b' ').next().unwrap_or_default();
+        if COMMANDS.iter().any(|c| c.as_bytes() == cmd) {
+            Ok(())
+        } else {
+            debug!("check - other = {:?}", cmd);
+            Err(format!(
+                "protocol error, unexpected command `{}`",
+                String::from_utf8_lossy(cmd)
+            )
+            .into())
+        }
+    }
+
+    pub(crate) fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, CmdError> {
+        debug!("parse");
+        let line = get_line(src)?.to_vec();
+        let string = String::from_utf8(line)?;
+        debug!("got line off the wire: {}", string);
+        let (cmd, rest) = string.split_once(' ').unwrap_or((&string, ""));
+        let mut args = Args { rest };
+
+        match cmd {
+            "READ" => Ok(Frame::Read(args.string("key")?)),
+            "WRITE" => Ok(Frame::Write(args.string("key")?, args.string("value")?)),
+            "OK" => Ok(Frame::Success),
+            "VALUE" => Ok(Frame::Value(args.string("value")?)),
+            "ERR" => unescape(rest)
+                .map(Frame::Error)
+                .ok_or_else(|| CmdError::from("protocol error, invalid escape in error")),
+            "HELLO" => {
+                let version = args.number("version")? as u32;
+                let node_id = match args.word("node id")? {
+                    "-" => None,
+                    id => Some(id.parse::<u32>().map_err(|_| {
+                        CmdError::from("protocol error, invalid HELLO node id")
+                    })?),
+                };
+                let cluster_id = match args.word("cluster id")? {
+                    "-" => None,
+                    id => Some(id.to_string()),
                 };
-                Ok(())
+                let features = match args.word("features")? {
+                    "-" => vec![],
+                    list => list.split(',').map(String::from).collect(),
+                };
+                Ok(Frame::Hello(Hello {
+                    version,
+                    node_id,
+                    cluster_id,
+                    features,
+                }))
+            }
+            "DELETE" => Ok(Frame::Delete(args.string("key")?)),
+            "EXISTS" => Ok(Frame::Exists(args.string("key")?)),
+            "CAS" => Ok(Frame::CompareAndSwap(CasArgs {
+                key: args.string("key")?,
+                expected: args.opt_string("expected value")?,
+                new: args.string("new value")?,
+            })),
+            "BOOL" => Ok(Frame::Bool(args.word("flag")? == "1")),
+            "CASRESULT" => Ok(Frame::CasResult(CasResult {
+                succeeded: args.word("flag")? == "1",
+                current: args.opt_string("current value")?,
+            })),
+            "SCAN" => Ok(Frame::Scan(ScanArgs {
+                start: args.string("start")?,
+                end: args.opt_string("end")?,
+                limit: args.number("limit")? as u32,
+            })),
+            "PREFIX" => Ok(Frame::Prefix(PrefixArgs {
+                prefix: args.string("prefix")?,
+                from: args.opt_string("from")?,
+                limit: args.number("limit")? as u32,
+            })),
+            "PAGE" => {
+                let next = args.opt_string("next")?;
+                let count = args.number("count")?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push((args.string("key")?, args.string("value")?));
+                }
+                Ok(Frame::Page(Page { entries, next }))
+            }
+            "GET" => {
+                let key = args.string("key")?;
+                let revision = match args.word("revision")? {
+                    "-" => None,
+                    rev => Some(rev.parse::<u64>().map_err(|_| {
+                        CmdError::from("protocol error, invalid revision")
+                    })?),
+                };
+                Ok(Frame::Get(GetArgs { key, revision }))
+            }
+            "ENTRY" => Ok(Frame::Entry(args.key_value()?)),
+            "COMPACT" => Ok(Frame::Compact(args.number("revision")?)),
+            "LEASEGRANT" => Ok(Frame::LeaseGrant(args.number("ttl")?)),
+            "LEASEKEEPALIVE" => Ok(Frame::LeaseKeepAlive(args.number("lease id")?)),
+            "LEASEREVOKE" => Ok(Frame::LeaseRevoke(args.number("lease id")?)),
+            "LEASEPUT" => Ok(Frame::LeasePut(
+                args.string("key")?,
+                args.number("lease id")?,
+                args.string("value")?,
+            )),
+            "LEASE" => Ok(Frame::Lease(LeaseInfo {
+                id: args.number("lease id")?,
+                ttl: args.number("ttl")?,
+            })),
+            "BUSY" => Ok(Frame::Busy(args.number("retry after")?)),
+            "PING" => Ok(Frame::Ping),
+            "RAFT" => {
+                let message = match args.word("message type")? {
+                    "REQVOTE" => Message::RequestVote {
+                        term: args.number("term")?,
+                        last_log_index: args.number("last log index")?,
+                        last_log_term: args.number("last log term")?,
+                    },
+                    "VOTE" => Message::Vote {
+                        term: args.number("term")?,
+                        granted: args.word("flag")? == "1",
+                    },
+                    "APPEND" => {
+                        let term = args.number("term")?;
+                        let prev_log_index = args.number("prev log index")?;
+                        let prev_log_term = args.number("prev log term")?;
+                        let leader_commit = args.number("leader commit")?;
+                        let mut entries = Vec::new();
+                        for _ in 0..args.number("entry count")? {
+                            let term = args.number("entry term")?;
+                            let index = args.number("entry index")?;
+                            let command = args.string("entry command")?;
+                            let command = Frame::decode(command.as_bytes())?;
+                            entries.push(LogEntry { term, index, command });
+                        }
+                        Message::AppendEntries {
+                            term,
+                            prev_log_index,
+                            prev_log_term,
+                            entries,
+                            leader_commit,
+                        }
+                    }
+                    "APPENDED" => Message::AppendResponse {
+                        term: args.number("term")?,
+                        success: args.word("flag")? == "1",
+                        match_index: args.number("match index")?,
+                    },
+                    "TIMEOUTNOW" => Message::TimeoutNow {
+                        term: args.number("term")?,
+                    },
+                    "READINDEX" => Message::ReadIndex {
+                        term: args.number("term")?,
+                        seq: args.number("read seq")?,
+                    },
+                    "READINDEXED" => Message::ReadIndexResponse {
+                        term: args.number("term")?,
+                        seq: args.number("read seq")?,
+                    },
+                    other => return Err(format!("protocol error, unknown raft message {}", other).into()),
+                };
+                Ok(Frame::Raft(message))
             }
-            b'W' => {
-                debug!("u8 read WRITE command");
-                get_line(src)?;
-                Ok(())
+            "FAULT" => {
+                let command = match args.word("fault command")? {
+                    "CUT" => FaultCommand::Cut {
+                        from: args.number("from node")? as u32,
+                        to: args.number("to node")? as u32,
+                    },
+                    "LINK" => FaultCommand::Link {
+                        from: args.node("from node")?,
+                        to: args.node("to node")?,
+                        faults: LinkFaults {
+                            drop: args.chance("drop chance")?,
+                            duplicate: args.chance("duplicate chance")?,
+                            reorder: args.chance("reorder chance")?,
+                            delay: Duration::from_millis(args.number("delay")?),
+                            jitter: Duration::from_millis(args.number("jitter")?),
+                        },
+                    },
+                    "HEAL" => FaultCommand::Heal,
+                    other => return Err(format!("protocol error, unknown fault command `{}`", other).into()),
+                };
+                Ok(Frame::Fault(command))
             }
-            b'O' => {
-                debug!("u8 read OK response");
-                get_line(src)?;
-                Ok(())
+            "WRITEBATCH" => {
+                let count = args.number("count")?;
+                let mut entries = Vec::new();
+                for _ in 0..count {
+                    entries.push((args.string("key")?, args.string("value")?));
+                }
+                Ok(Frame::WriteBatch(entries))
             }
-            b'V' => {
-                debug!("u8 read VALUE response");
-                get_line(src)?;
-                Ok(())
+            "TXN" => {
+                let mut txn = Txn::new();
+                for _ in 0..args.number("compare count")? {
+                    let key = args.string("compare key")?;
+                    let target = match args.word("compare target")? {
+                        "VALUE" => CompareTarget::Value(args.string("compare value")?),
+                        "VERSION" => CompareTarget::Version(args.number("compare version")?),
+                        "EXISTS" => CompareTarget::Exists(args.word("compare flag")? == "1"),
+                        other => {
+                            return Err(format!("protocol error, unknown compare `{}`", other).into())
+                        }
+                    };
+                    txn.compares.push(Compare { key, target });
+                }
+                txn.success = args.txn_ops()?;
+                txn.failure = args.txn_ops()?;
+                Ok(Frame::Txn(txn))
             }
-            b'E' => {
-                debug!("u8 read ERR response");
-                get_line(src)?;
-                Ok(())
+            "TXNRESULT" => {
+                let succeeded = args.word("flag")? == "1";
+                let mut responses = vec![];
+                for _ in 0..args.number("response count")? {
+                    let response = match args.word("response kind")? {
+                        "PUT" => TxnResponse::Put(args.number("revision")?),
+                        "DEL" => TxnResponse::Delete(args.word("flag")? == "1"),
+                        "GET" if args.rest == "-" || args.rest.starts_with("- ") => {
+                            args.word("missing value")?;
+                            TxnResponse::Get(None)
+                        }
+                        "GET" => TxnResponse::Get(Some(args.key_value()?)),
+                        other => {
+                            return Err(format!("protocol error, unknown response `{}`", other).into())
+                        }
+                    };
+                    responses.push(response);
+                }
+                Ok(Frame::TxnResult(TxnResult {
+                    succeeded,
+                    responses,
+                }))
             }
-            other => {
-                debug!("check - other = {}", other);
-                Err(format!("protocol error, unexpected byte `{}`", other).into())
+            "WATCH" => Ok(Frame::Watch(WatchArgs {
+                key: args.string("key")?,
+                prefix: args.word("prefix flag")? == "1",
+                start_revision: args.number("start revision")?,
+            })),
+            "EVENT" => {
+                let kind = match args.word("event kind")? {
+                    "PUT" => EventKind::Put,
+                    "DELETE" => EventKind::Delete,
+                    other => {
+                        return Err(format!("protocol error, unknown event kind `{}`", other).into())
+                    }
+                };
+                Ok(Frame::Event(Event {
+                    kind,
+                    kv: args.key_value()?,
+                }))
             }
+            _ => Err(format!("protocol error, unknown command `{}`", cmd).into()),
         }
     }
+}
 
-    pub(crate) fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, CmdError> {
-        debug!("parse");
-        match get_u8(src)? {
-            b'R' => {
-                // TODO: DRY this out?
-                let line = get_line(src)?.to_vec();
-                let string = String::from_utf8(line)?;
-                let (_cmd, key) = string.split_once(' ').unwrap();
-                Ok(Frame::Read(key.to_string()))
-            }
-            b'W' => {
-                let line = get_line(src)?.to_vec();
-                let string = String::from_utf8(line)?;
-                let mut split = string.split(' ');
-                let _cmd = split.next().expect("missing command from write split.");
-                let key = split.next().expect("missing key from write split.");
-                let val = split.collect::<Vec<&str>>().join(" ");
-                Ok(Frame::Write(String::from(key), val))
-            }
-            b'O' => {
-                // OK response
-                Ok(Frame::Success)
-            }
-            b'V' => {
-                let line = get_line(src)?.to_vec();
-                let string = String::from_utf8(line)?;
-                debug!("got read result line off the wire: {}", string);
-                let (_, two) = string.split_once(' ').unwrap();
-                Ok(Frame::Value(String::from(two)))
-            }
-            b'E' => {
-                let line = get_line(src)?.to_vec();
-                let string = String::from_utf8(line)?;
-                debug!("got error line off the wire: {}", string);
-                let (_, msg) = string.split_once(' ').unwrap();
-                Ok(Frame::Error(String::from(msg)))
-            }
-            _ => unimplemented!("implement parse frame for this"),
-        }
-    }
-}
-
-fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, CmdError> {
+/// Arguments after the command word. Keys and values are length-prefixed
+/// as `<len>:<bytes>`, with backslash, CR and LF escaped so a frame stays
+/// on one line, and a missing optional value is `-`.
+struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    fn word(&mut self, name: &str) -> Result<&'a str, CmdError> {
+        if self.rest.is_empty() {
+            return Err(format!("protocol error, missing {}", name).into());
+        }
+        let (word, rest) = self.rest.split_once(' ').unwrap_or((self.rest, ""));
+        self.rest = rest;
+        Ok(word)
+    }
+
+    fn number(&mut self, name: &str) -> Result<u64, CmdError> {
+        self.word(name)?
+            .parse::<u64>()
+            .map_err(|_| format!("protocol error, invalid {}", name).into())
+    }
+
+    /// A node id, or `*` for any node.
+    fn node(&mut self, name: &str) -> Result<Option<u32>, CmdError> {
+        match self.word(name)? {
+            "*" => Ok(None),
+            id => id
+                .parse::<u32>()
+                .map(Some)
+                .map_err(|_| format!("protocol error, invalid {}", name).into()),
+        }
+    }
+
+    /// A probability, from 0 to 1.
+    fn chance(&mut self, name: &str) -> Result<f64, CmdError> {
+        match self.word(name)?.parse::<f64>() {
+            Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(chance),
+            _ => Err(format!("protocol error, invalid {}", name).into()),
+        }
+    }
+
+    fn string(&mut self, name: &str) -> Result<String, CmdError> {
+        let (len, rest) = self
+            .rest
+            .split_once(':')
+            .ok_or_else(|| CmdError::from(format!("protocol error, missing {}", name)))?;
+        let len = len
+            .parse::<usize>()
+            .map_err(|_| CmdError::from(format!("protocol error, invalid length for {}", name)))?;
+        let value = rest
+            .get(..len)
+            .ok_or_else(|| CmdError::from(format!("protocol error, truncated {}", name)))?;
+        self.rest = rest[len..].strip_prefix(' ').unwrap_or(&rest[len..]);
+        unescape(value).ok_or_else(|| format!("protocol error, invalid escape in {}", name).into())
+    }
+
+    fn key_value(&mut self) -> Result<KeyValue, CmdError> {
+        Ok(KeyValue {
+            key: self.string("key")?,
+            value: self.string("value")?,
+            create_revision: self.number("create revision")?,
+            mod_revision: self.number("mod revision")?,
+            version: self.number("version")?,
+        })
+    }
+
+    fn txn_ops(&mut self) -> Result<Vec<TxnOp>, CmdError> {
+        let mut ops = vec![];
+        for _ in 0..self.number("op count")? {
+            let op = match self.word("op")? {
+                "PUT" => TxnOp::Put(self.string("key")?, self.string("value")?),
+                "DEL" => TxnOp::Delete(self.string("key")?),
+                "GET" => TxnOp::Get(self.string("key")?),
+                other => return Err(format!("protocol error, unknown op `{}`", other).into()),
+            };
+            ops.push(op);
+        }
+        Ok(ops)
+    }
+
+    fn opt_string(&mut self, name: &str) -> Result<Option<String>, CmdError> {
+        if self.rest == "-" || self.rest.starts_with("- ") {
+            self.word(name)?;
+            Ok(None)
+        } else {
+            self.string(name).map(Some)
+        }
+    }
+}
+
+/// Length-prefixes a value so it can sit in the middle of a frame. The
+/// length counts the escaped bytes.
+fn lp(s: &str) -> String {
+    let escaped = escape(s);
+    format!("{}:{}", escaped.len(), escaped)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\r', "\\r").replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'r' => unescaped.push('\r'),
+            'n' => unescaped.push('\n'),
+            _ => return None,
+        }
+    }
+    Some(unescaped)
+}
+
+fn opt_lp(s: &Option<String>) -> String {
+    s.as_deref().map_or("-".to_string(), lp)
+}
+
+pub(crate) fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, CmdError> {
     debug!("get_u8");
     if !src.has_remaining() {
         debug!("get_u8 has no remaining data, returning Incomplete.");
@@ -169,10 +1040,10 @@ fn get_u8(src: &mut Cursor<&[u8]>) -> Result<u8, CmdError> {
     Ok(src.get_u8())
 }
 
-fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CmdError> {
+pub(crate) fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CmdError> {
     debug!("get line");
     let start = src.position() as usize;
-    let end = src.get_ref().len() - 1;
+    let end = src.get_ref().len().saturating_sub(1);
 
     debug!("start: {} end: {}", start, end);
     for i in start..end {
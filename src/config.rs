@@ -0,0 +1,338 @@
+// settings for a single scow node.
+//
+// The server binary layers them: defaults, then a TOML file (`--config`
+// or SCOW_CONFIG), then SCOW_* environment variables, then command line
+// flags. Every setting has the same name everywhere, e.g. `client_addr` in
+// the file, SCOW_CLIENT_ADDR in the environment and `--client-addr` on the
+// command line.
+
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub node_id: u32,
+    pub cluster_id: String,
+    /// Where clients speak the native protocol.
+    pub client_addr: SocketAddr,
+    /// Where other nodes of the cluster connect.
+    pub peer_addr: SocketAddr,
+    /// Optional redis (RESP2) listener.
+    pub resp_addr: Option<SocketAddr>,
+    /// Optional HTTP/JSON listener.
+    pub http_addr: Option<SocketAddr>,
+    /// Where the log is persisted. Without one it only lives in memory and
+    /// a restarted node comes back empty.
+    pub data_dir: Option<PathBuf>,
+    /// The other members of the cluster.
+    pub peers: Vec<Peer>,
+    /// Followers wait a random time in this range without hearing from a
+    /// leader before standing for election.
+    pub election_timeout_min_ms: u64,
+    pub election_timeout_max_ms: u64,
+    pub heartbeat_interval_ms: u64,
+    /// Most proposals the leader will fold into one log append.
+    pub max_batch_size: usize,
+    /// AppendEntries the leader may have outstanding to one follower.
+    pub max_inflight_appends: usize,
+    /// Proposals accepted but not yet applied. Past this, writes get BUSY.
+    pub max_inflight_proposals: usize,
+    /// Bytes of proposals accepted but not yet applied. Past this, writes
+    /// get BUSY.
+    pub max_uncommitted_bytes: usize,
+    /// Bytes of AppendEntries the leader may have outstanding to one follower.
+    pub max_follower_inflight_bytes: usize,
+    /// Accept FAULT admin commands, which make this node drop, delay,
+    /// duplicate or reorder its consensus traffic. For testing only.
+    pub fault_injection: bool,
+    /// How long a node stopping gives open connections to finish and the
+    /// log to hand leadership over before it gives up on them.
+    pub shutdown_timeout_ms: u64,
+    /// Client connections taken at once, across the native, RESP and HTTP
+    /// ports. Past this, new ones are refused.
+    pub max_connections: usize,
+    /// Client connections taken at once from one address; 0 for no limit.
+    pub max_connections_per_ip: usize,
+    /// Client connections that send nothing for this long are closed; 0
+    /// to keep them forever.
+    pub idle_timeout_ms: u64,
+}
+
+/// Another node, as `id=address/client=client_addr[/resp=resp_addr][/http=http_addr]`
+/// on the command line or
+/// `{ id = 1, address = "127.0.0.1:9991", client_addr = "127.0.0.1:9999" }`
+/// in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Peer {
+    pub id: u32,
+    /// The peer's `peer_addr`.
+    pub address: SocketAddr,
+    /// The peer's `client_addr`, where clients are sent while it leads.
+    pub client_addr: SocketAddr,
+    /// The peer's `resp_addr`, if it has one, where redis clients are sent
+    /// while it leads.
+    #[serde(default)]
+    pub resp_addr: Option<SocketAddr>,
+    /// The peer's `http_addr`, if it has one, where HTTP writes are
+    /// redirected while it leads.
+    #[serde(default)]
+    pub http_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(String);
+
+impl std::error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl From<String> for ConfigError {
+    fn from(src: String) -> ConfigError {
+        ConfigError(src)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            node_id: 0,
+            cluster_id: String::from("scow"),
+            client_addr: SocketAddr::from(([127, 0, 0, 1], 9999)),
+            peer_addr: SocketAddr::from(([127, 0, 0, 1], 9990)),
+            resp_addr: None,
+            http_addr: None,
+            data_dir: None,
+            peers: Vec::new(),
+            election_timeout_min_ms: 150,
+            election_timeout_max_ms: 300,
+            heartbeat_interval_ms: 50,
+            max_batch_size: 256,
+            max_inflight_appends: 8,
+            max_inflight_proposals: 1024,
+            max_uncommitted_bytes: 64 * 1024 * 1024,
+            max_follower_inflight_bytes: 8 * 1024 * 1024,
+            fault_injection: false,
+            shutdown_timeout_ms: 5000,
+            max_connections: 100,
+            max_connections_per_ip: 0,
+            idle_timeout_ms: 0,
+        }
+    }
+}
+
+const USAGE: &str = "usage: server [--config FILE] [--SETTING VALUE]...
+
+settings (also SCOW_<SETTING> in the environment, or SETTING in the file):
+  --node-id N                     --cluster-id NAME
+  --client-addr ADDR              --peer-addr ADDR
+  --resp-addr ADDR                --http-addr ADDR
+  --data-dir DIR                  --peers PEER[,PEER...]
+  --election-timeout-min-ms MS    --election-timeout-max-ms MS
+  --heartbeat-interval-ms MS      --max-batch-size N
+  --max-inflight-appends N        --max-inflight-proposals N
+  --max-uncommitted-bytes N       --max-follower-inflight-bytes N
+  --fault-injection BOOL          --shutdown-timeout-ms MS
+  --max-connections N             --max-connections-per-ip N
+  --idle-timeout-ms MS
+
+a PEER is ID=PEER_ADDR/client=CLIENT_ADDR[/resp=RESP_ADDR][/http=HTTP_ADDR]";
+
+impl Config {
+    pub fn usage() -> &'static str {
+        USAGE
+    }
+
+    pub fn from_toml(src: &str) -> Result<Config, ConfigError> {
+        toml::from_str(src).map_err(|e| ConfigError(e.to_string()))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let src = std::fs::read_to_string(path)
+            .map_err(|e| format!("reading {}: {}", path.display(), e))?;
+        Config::from_toml(&src).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Builds the binary's config from its environment and arguments (not
+    /// including the program name), and validates it.
+    pub fn load(
+        env: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Config, ConfigError> {
+        let env: Vec<(String, String)> = env
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.strip_prefix("SCOW_")?.to_ascii_lowercase(), v)))
+            .collect();
+        let flags = parse_flags(args)?;
+
+        // the file comes first, everything else overrides it.
+        let file = flags
+            .iter()
+            .chain(env.iter())
+            .rev()
+            .find(|(name, _)| name == "config")
+            .map(|(_, path)| PathBuf::from(path));
+        let mut config = match file {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+        for (name, value) in env.iter().chain(flags.iter()) {
+            if name != "config" {
+                config.set(name, value)?;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Overrides one setting by name, as given in the file.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        match name {
+            "node_id" => self.node_id = parse(name, value)?,
+            "cluster_id" => self.cluster_id = value.to_string(),
+            "client_addr" => self.client_addr = parse(name, value)?,
+            "peer_addr" => self.peer_addr = parse(name, value)?,
+            "resp_addr" => self.resp_addr = Some(parse(name, value)?),
+            "http_addr" => self.http_addr = Some(parse(name, value)?),
+            "data_dir" => self.data_dir = Some(PathBuf::from(value)),
+            "peers" => {
+                self.peers = value
+                    .split(',')
+                    .filter(|p| !p.is_empty())
+                    .map(parse_peer)
+                    .collect::<Result<_, _>>()?
+            }
+            "election_timeout_min_ms" => self.election_timeout_min_ms = parse(name, value)?,
+            "election_timeout_max_ms" => self.election_timeout_max_ms = parse(name, value)?,
+            "heartbeat_interval_ms" => self.heartbeat_interval_ms = parse(name, value)?,
+            "max_batch_size" => self.max_batch_size = parse(name, value)?,
+            "max_inflight_appends" => self.max_inflight_appends = parse(name, value)?,
+            "max_inflight_proposals" => self.max_inflight_proposals = parse(name, value)?,
+            "max_uncommitted_bytes" => self.max_uncommitted_bytes = parse(name, value)?,
+            "max_follower_inflight_bytes" => self.max_follower_inflight_bytes = parse(name, value)?,
+            "fault_injection" => self.fault_injection = parse(name, value)?,
+            "shutdown_timeout_ms" => self.shutdown_timeout_ms = parse(name, value)?,
+            "max_connections" => self.max_connections = parse(name, value)?,
+            "max_connections_per_ip" => self.max_connections_per_ip = parse(name, value)?,
+            "idle_timeout_ms" => self.idle_timeout_ms = parse(name, value)?,
+            _ => return Err(ConfigError(format!("unknown setting `{}`", name))),
+        }
+        Ok(())
+    }
+
+    /// Catches settings that would only fail later, or fail strangely.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.cluster_id.is_empty() || self.cluster_id.contains(char::is_whitespace) {
+            problems.push(String::from("cluster_id must be a single non-empty word"));
+        }
+        if self.client_addr == self.peer_addr {
+            problems.push(String::from("client_addr and peer_addr must differ"));
+        }
+        if self.election_timeout_min_ms == 0 || self.election_timeout_min_ms > self.election_timeout_max_ms {
+            problems.push(format!(
+                "election timeout range {}..{}ms is empty",
+                self.election_timeout_min_ms, self.election_timeout_max_ms
+            ));
+        }
+        // a follower has to hear a few heartbeats before it gives up on
+        // the leader.
+        if self.heartbeat_interval_ms == 0 || self.heartbeat_interval_ms * 2 > self.election_timeout_min_ms {
+            problems.push(format!(
+                "heartbeat_interval_ms {} must be non-zero and at most half of election_timeout_min_ms",
+                self.heartbeat_interval_ms
+            ));
+        }
+        for (name, value) in [
+            ("max_batch_size", self.max_batch_size),
+            ("max_inflight_appends", self.max_inflight_appends),
+            ("max_inflight_proposals", self.max_inflight_proposals),
+            ("max_uncommitted_bytes", self.max_uncommitted_bytes),
+            ("max_follower_inflight_bytes", self.max_follower_inflight_bytes),
+            ("max_connections", self.max_connections),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be at least 1", name));
+            }
+        }
+        for (i, peer) in self.peers.iter().enumerate() {
+            if peer.id == self.node_id {
+                problems.push(format!("peer {} has this node's own id", peer.id));
+            }
+            if self.peers[..i].iter().any(|p| p.id == peer.id) {
+                problems.push(format!("peer {} is listed twice", peer.id));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems.join("; ")))
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| ConfigError(format!("invalid {} `{}`: {}", name, value, e)))
+}
+
+/// A peer as `ID=PEER_ADDR/client=CLIENT_ADDR[/resp=RESP_ADDR][/http=HTTP_ADDR]`.
+fn parse_peer(peer: &str) -> Result<Peer, ConfigError> {
+    let invalid = || ConfigError(format!("invalid peer `{}`, expected ID=PEER_ADDR/client=CLIENT_ADDR[/resp=RESP_ADDR][/http=HTTP_ADDR]", peer));
+    let (id, addresses) = peer.split_once('=').ok_or_else(invalid)?;
+    let mut addresses = addresses.split('/');
+    let address = addresses.next().ok_or_else(invalid)?;
+    let mut client_addr = None;
+    let mut resp_addr = None;
+    let mut http_addr = None;
+    for address in addresses {
+        match address.split_once('=') {
+            Some(("client", client)) => client_addr = Some(parse("peer client address", client)?),
+            Some(("resp", resp)) => resp_addr = Some(parse("peer resp address", resp)?),
+            Some(("http", http)) => http_addr = Some(parse("peer http address", http)?),
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(Peer {
+        id: parse("peer id", id)?,
+        address: parse("peer address", address)?,
+        client_addr: client_addr.ok_or_else(|| ConfigError(format!("peer `{}` has no client address", peer)))?,
+        resp_addr,
+        http_addr,
+    })
+}
+
+/// `--some-setting value` and `--some-setting=value` pairs, as
+/// `("some_setting", "value")`.
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError(format!("unexpected argument `{}`\n{}", arg, USAGE)));
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError(format!("--{} needs a value", flag)))?;
+                (flag.to_string(), value)
+            }
+        };
+        flags.push((name.replace('-', "_"), value));
+    }
+    Ok(flags)
+}
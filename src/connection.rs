@@ -5,21 +5,24 @@ use tracing::debug;
 
 use tokio::io::AsyncReadExt;
 use tokio::io::BufWriter;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 
-use crate::command::{CmdError, Frame};
+use crate::command::{CmdError, Frame, Hello};
 
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Frames over a byte stream: a TCP connection, unless a simulation hands
+/// out streams of its own.
 #[derive(Debug)]
-pub struct Connection {
-    pub stream: BufWriter<TcpStream>,
+pub struct Connection<S = TcpStream> {
+    pub stream: BufWriter<S>,
     pub buffer: BytesMut,
 }
 
-impl Connection {
-    pub fn new(socket: TcpStream) -> Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(socket: S) -> Connection<S> {
         Connection {
             stream: BufWriter::new(socket),
             buffer: BytesMut::with_capacity(4 * 1024),
@@ -65,6 +68,51 @@ impl Connection {
         }
     }
 
+    /// Dialing side of the handshake: send our HELLO, then wait for the
+    /// remote's. Returns the remote HELLO once both sides agree.
+    pub async fn handshake(&mut self, local: &Hello) -> Result<Hello> {
+        self.write(&Frame::Hello(local.clone()).to_string()).await?;
+        match self.read_frame().await? {
+            Some(Frame::Hello(remote)) => {
+                local.check_compatible(&remote)?;
+                Ok(remote)
+            }
+            Some(Frame::Error(msg)) => Err(format!("handshake rejected: {}", msg).into()),
+            Some(other) => Err(format!("expected HELLO, got {:?}", other).into()),
+            None => Err("connection closed during handshake".into()),
+        }
+    }
+
+    /// Accepting side of the handshake: wait for the dialer's HELLO and
+    /// answer with ours, or with an ERR if the dialer is incompatible.
+    pub async fn accept_handshake(&mut self, local: &Hello) -> Result<Hello> {
+        self.accept_handshake_from(local, |_| Ok(())).await
+    }
+
+    /// Like `accept_handshake`, but also turns the dialer away unless
+    /// `allowed` accepts its HELLO.
+    pub async fn accept_handshake_from(
+        &mut self,
+        local: &Hello,
+        allowed: impl FnOnce(&Hello) -> std::result::Result<(), String>,
+    ) -> Result<Hello> {
+        let remote = match self.read_frame().await? {
+            Some(Frame::Hello(remote)) => remote,
+            Some(other) => {
+                self.write(&Frame::Error("expected HELLO".to_string()).to_string())
+                    .await?;
+                return Err(format!("expected HELLO, got {:?}", other).into());
+            }
+            None => return Err("connection closed during handshake".into()),
+        };
+        if let Err(msg) = local.check_compatible(&remote).and_then(|()| allowed(&remote)) {
+            self.write(&Frame::Error(msg.clone()).to_string()).await?;
+            return Err(msg.into());
+        }
+        self.write(&Frame::Hello(local.clone()).to_string()).await?;
+        Ok(remote)
+    }
+
     pub async fn write(&mut self, src: &str) -> std::io::Result<()> {
         self.stream.write_all(src.as_bytes()).await?;
         self.stream.flush().await
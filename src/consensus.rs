@@ -4,23 +4,76 @@
 use std::fmt::Display;
 use std::net::SocketAddr;
 
-pub struct Entry {
-    key: String,
-    value: String,
+use crate::log::LogEntry;
+
+/// What nodes say to each other. The sender isn't part of the message, the
+/// transport knows who it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// Entries after `prev_log_index`, or none at all as a heartbeat.
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    /// On success the follower's log matches the leader's up to
+    /// `match_index`. On failure `match_index` is a hint: nothing after it
+    /// can match.
+    AppendResponse {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+    /// From a leader handing over: stand for election now, without waiting
+    /// for the timeout.
+    TimeoutNow {
+        term: u64,
+    },
+    /// From a leader serving reads: do you still follow me? Answered with
+    /// the same `seq`, which confirms every read asked for up to it.
+    ReadIndex {
+        term: u64,
+        seq: u64,
+    },
+    ReadIndexResponse {
+        term: u64,
+        seq: u64,
+    },
 }
 
-// pub enum ServerCommand {
-//     AppendEntries(Box<[Entry]>),
-//     RequestVote,
-// }
+impl Message {
+    pub fn term(&self) -> u64 {
+        match self {
+            Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::AppendEntries { term, .. }
+            | Message::AppendResponse { term, .. }
+            | Message::TimeoutNow { term }
+            | Message::ReadIndex { term, .. }
+            | Message::ReadIndexResponse { term, .. } => *term,
+        }
+    }
+}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ServerState {
     Leader,
+    Candidate,
     Follower,
 }
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Copy)]
 pub struct ServerId {
     pub id: u32,
     pub address: SocketAddr,
@@ -32,13 +85,29 @@ impl Display for ServerId {
     }
 }
 
-#[derive(Debug)]
+/// A member of the cluster as clients see it: where its front ends listen.
+/// Only the native one is always there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Member {
+    pub id: u32,
+    pub client: SocketAddr,
+    pub resp: Option<SocketAddr>,
+    pub http: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone)]
 pub struct TermState {
     pub current_term: u64,
     pub server_state: ServerState,
     pub leader: Option<ServerId>,
 }
 
+impl Default for TermState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TermState {
     pub fn new() -> TermState {
         TermState {
@@ -47,4 +116,139 @@ impl TermState {
             leader: None,
         }
     }
-}
\ No newline at end of file
+}
+/// How the leader is replicating to one follower.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressState {
+    /// We don't know where the follower's log ends: send one AppendEntries
+    /// at a time and walk `next_index` back on each rejection.
+    Probe,
+    /// The follower is caught up to `match_index`: stream AppendEntries
+    /// back to back, up to the in-flight window.
+    Replicate,
+}
+
+/// The leader's view of one follower's log, and the AppendEntries it has
+/// sent but not had answered yet.
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub next_index: u64,
+    pub match_index: u64,
+    pub state: ProgressState,
+    /// Last index and size in bytes of each unacknowledged AppendEntries,
+    /// oldest first.
+    inflight: std::collections::VecDeque<(u64, usize)>,
+    inflight_bytes: usize,
+    max_inflight: usize,
+    max_inflight_bytes: usize,
+    /// The oldest unacknowledged append at the last `stalled` check.
+    oldest_checked: Option<u64>,
+}
+
+impl Progress {
+    pub fn new(last_index: u64, max_inflight: usize, max_inflight_bytes: usize) -> Progress {
+        Progress {
+            next_index: last_index + 1,
+            match_index: 0,
+            state: ProgressState::Probe,
+            inflight: std::collections::VecDeque::new(),
+            inflight_bytes: 0,
+            max_inflight: max_inflight.max(1),
+            max_inflight_bytes,
+            oldest_checked: None,
+        }
+    }
+
+    /// Whether another AppendEntries may go out now. Something is always
+    /// allowed when nothing is outstanding, however big it is.
+    pub fn can_send(&self) -> bool {
+        if self.inflight.is_empty() {
+            return true;
+        }
+        match self.state {
+            ProgressState::Probe => false,
+            ProgressState::Replicate => {
+                self.inflight.len() < self.max_inflight
+                    && self.inflight_bytes < self.max_inflight_bytes
+            }
+        }
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.len()
+    }
+
+    pub fn inflight_bytes(&self) -> usize {
+        self.inflight_bytes
+    }
+
+    /// Records an AppendEntries of `bytes` carrying entries up to
+    /// `last_index`. In replicate mode we optimistically assume it lands.
+    pub fn sent(&mut self, last_index: u64, bytes: usize) {
+        self.inflight.push_back((last_index, bytes));
+        self.inflight_bytes += bytes;
+        if self.state == ProgressState::Replicate {
+            self.next_index = last_index + 1;
+        }
+    }
+
+    /// The follower has everything up to `index`. Returns false for stale
+    /// acks that don't move anything forward.
+    pub fn ack(&mut self, index: u64) -> bool {
+        while let Some(&(last, bytes)) = self.inflight.front() {
+            if last > index {
+                break;
+            }
+            self.inflight.pop_front();
+            self.inflight_bytes -= bytes;
+        }
+        if index <= self.match_index {
+            return false;
+        }
+        self.match_index = index;
+        self.next_index = self.next_index.max(index + 1);
+        if self.state == ProgressState::Probe {
+            self.state = ProgressState::Replicate;
+            self.clear_inflight();
+        }
+        true
+    }
+
+    /// The follower's log didn't match at `next_index - 1`. Drop back to
+    /// probing from `hint` (the follower's last index, if it told us), and
+    /// forget the pipelined sends, they'll all be rejected too.
+    pub fn reject(&mut self, hint: Option<u64>) {
+        let fallback = match self.state {
+            ProgressState::Probe => self.next_index.saturating_sub(1),
+            ProgressState::Replicate => self.match_index + 1,
+        };
+        let next = hint.map_or(fallback, |last| (last + 1).min(fallback));
+        self.next_index = next.max(self.match_index + 1);
+        self.state = ProgressState::Probe;
+        self.clear_inflight();
+    }
+
+    /// Gives up on an unanswered probe, so the next one can go out. Called
+    /// every heartbeat, since the probe or its answer may have been lost.
+    pub fn resume(&mut self) {
+        if self.state == ProgressState::Probe {
+            self.clear_inflight();
+        }
+    }
+
+    /// Whether the oldest unacknowledged append was already outstanding at
+    /// the previous check. Called every heartbeat: an append that goes a
+    /// whole heartbeat without an answer, or whose answer did, is lost.
+    pub fn stalled(&mut self) -> bool {
+        let oldest = self.inflight.front().map(|&(last, _)| last);
+        let stalled = oldest.is_some() && oldest == self.oldest_checked;
+        self.oldest_checked = oldest;
+        stalled
+    }
+
+    fn clear_inflight(&mut self) {
+        self.inflight.clear();
+        self.inflight_bytes = 0;
+        self.oldest_checked = None;
+    }
+}
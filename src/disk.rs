@@ -0,0 +1,112 @@
+// where the log is persisted.
+//
+// The log only needs a handful of files in one place, each read whole on
+// boot and then appended to, cut short or replaced. `FileDisk` keeps them
+// in a directory; a simulation can keep them on a simulated disk that
+// outlives the node, so a restarted node picks up where it left off. Every
+// change is durable by the time it returns.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub trait Disk: Debug + Send + 'static {
+    /// The whole of file `name`, or None if there is no such file.
+    fn read(&mut self, name: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Adds `data` to the end of file `name`, creating it if need be.
+    fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()>;
+
+    /// Cuts file `name` down to its first `len` bytes.
+    fn truncate(&mut self, name: &str, len: u64) -> io::Result<()>;
+
+    /// Replaces file `name` with `data`. A crash part way through leaves
+    /// the old contents or the new, whole.
+    fn replace(&mut self, name: &str, data: &[u8]) -> io::Result<()>;
+}
+
+/// Files in a directory, fsynced before anything returns.
+#[derive(Debug)]
+pub struct FileDisk {
+    dir: PathBuf,
+    /// Files opened for appending so far.
+    files: HashMap<String, File>,
+}
+
+impl FileDisk {
+    /// The files in `dir`, which is created if need be.
+    pub fn open(dir: &Path) -> io::Result<FileDisk> {
+        fs::create_dir_all(dir)?;
+        Ok(FileDisk {
+            dir: dir.to_path_buf(),
+            files: HashMap::new(),
+        })
+    }
+
+    fn file(&mut self, name: &str) -> io::Result<&mut File> {
+        if !self.files.contains_key(name) {
+            let file = OpenOptions::new().append(true).create(true).open(self.dir.join(name))?;
+            // make sure the file itself survives a crash.
+            self.sync_dir()?;
+            self.files.insert(name.to_string(), file);
+        }
+        Ok(self.files.get_mut(name).unwrap())
+    }
+
+    fn sync_dir(&self) -> io::Result<()> {
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+impl Disk for FileDisk {
+    fn read(&mut self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let file = self.file(name)?;
+        file.write_all(data)?;
+        file.sync_data()
+    }
+
+    fn truncate(&mut self, name: &str, len: u64) -> io::Result<()> {
+        let file = self.file(name)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn replace(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        // written beside the old file and renamed over it.
+        let next = self.dir.join(format!("{}.next", name));
+        let mut file = File::create(&next)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&next, self.dir.join(name))?;
+        self.files.remove(name);
+        self.sync_dir()
+    }
+}
+
+impl Disk for Box<dyn Disk> {
+    fn read(&mut self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        (**self).read(name)
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        (**self).append(name, data)
+    }
+
+    fn truncate(&mut self, name: &str, len: u64) -> io::Result<()> {
+        (**self).truncate(name, len)
+    }
+
+    fn replace(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        (**self).replace(name, data)
+    }
+}
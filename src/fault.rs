@@ -0,0 +1,298 @@
+// deliberately unreliable consensus traffic, for testing.
+//
+// `FaultTransport` wraps any transport and misbehaves on request: it drops,
+// delays, duplicates and reorders messages, and cuts links, one direction
+// at a time if need be. What it does is set through a `Faults` handle, by
+// test code or with the FAULT admin command on a node running with
+// `fault_injection`. Links are (from, to) pairs of node ids. A cut holds
+// at either end of the link; the other faults are done by the sender, so
+// they belong on the sending node.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use crate::command::FaultCommand;
+use crate::consensus::{Message, ServerId};
+use crate::transport::Transport;
+
+/// How a link misbehaves. Chances are between 0 and 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkFaults {
+    /// Chance a message is lost.
+    pub drop: f64,
+    /// Chance a message is sent twice.
+    pub duplicate: f64,
+    /// Chance a message is held back until the next one to the same node
+    /// has gone.
+    pub reorder: f64,
+    /// How long every message takes...
+    pub delay: Duration,
+    /// ...plus up to this much more, so messages overtake each other.
+    pub jitter: Duration,
+}
+
+#[derive(Debug)]
+struct Rules {
+    cut: HashSet<(u32, u32)>,
+    /// By (from, to); None stands for any node.
+    links: HashMap<(Option<u32>, Option<u32>), LinkFaults>,
+    rng: u64,
+}
+
+impl Rules {
+    /// The most specific rule for a link.
+    fn link(&self, from: u32, to: u32) -> Option<&LinkFaults> {
+        [(Some(from), Some(to)), (Some(from), None), (None, Some(to)), (None, None)]
+            .iter()
+            .find_map(|key| self.links.get(key))
+    }
+
+    /// xorshift, scaled to [0, 1).
+    fn chance(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// What becomes of one message.
+struct Fate {
+    /// 0 when it is lost.
+    copies: usize,
+    delay: Duration,
+    hold: bool,
+}
+
+/// The faults in force, shared by every transport it was handed to.
+#[derive(Debug, Clone)]
+pub struct Faults {
+    rules: Arc<Mutex<Rules>>,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults::new(0)
+    }
+}
+
+impl Faults {
+    /// No faults yet. `seed` picks which messages the chances hit.
+    pub fn new(seed: u64) -> Faults {
+        let rules = Rules {
+            cut: HashSet::new(),
+            links: HashMap::new(),
+            // xorshift never leaves zero.
+            rng: seed | 1,
+        };
+        Faults {
+            rules: Arc::new(Mutex::new(rules)),
+        }
+    }
+
+    /// Drops everything `from` sends to `to`, but not the other way.
+    pub fn cut(&self, from: u32, to: u32) {
+        self.rules.lock().unwrap().cut.insert((from, to));
+    }
+
+    /// Cuts every link between the two groups, both ways.
+    pub fn partition(&self, a: &[u32], b: &[u32]) {
+        let mut rules = self.rules.lock().unwrap();
+        for &x in a {
+            for &y in b {
+                rules.cut.insert((x, y));
+                rules.cut.insert((y, x));
+            }
+        }
+    }
+
+    /// Applies `faults` to messages from `from` to `to`; None matches any
+    /// node. A rule naming both nodes beats one naming the sender, which
+    /// beats one naming the receiver, which beats one naming neither.
+    pub fn set(&self, from: Option<u32>, to: Option<u32>, faults: LinkFaults) {
+        self.rules.lock().unwrap().links.insert((from, to), faults);
+    }
+
+    /// Restores every link and removes every rule.
+    pub fn heal(&self) {
+        let mut rules = self.rules.lock().unwrap();
+        rules.cut.clear();
+        rules.links.clear();
+    }
+
+    pub fn is_cut(&self, from: u32, to: u32) -> bool {
+        self.rules.lock().unwrap().cut.contains(&(from, to))
+    }
+
+    /// Carries out a FAULT admin command.
+    pub fn apply(&self, command: &FaultCommand) {
+        match command {
+            FaultCommand::Cut { from, to } => self.cut(*from, *to),
+            FaultCommand::Link { from, to, faults } => self.set(*from, *to, *faults),
+            FaultCommand::Heal => self.heal(),
+        }
+    }
+
+    fn fate(&self, from: u32, to: u32) -> Fate {
+        let mut rules = self.rules.lock().unwrap();
+        let mut fate = Fate {
+            copies: 1,
+            delay: Duration::ZERO,
+            hold: false,
+        };
+        if rules.cut.contains(&(from, to)) {
+            fate.copies = 0;
+            return fate;
+        }
+        let Some(link) = rules.link(from, to).copied() else {
+            return fate;
+        };
+        if rules.chance() < link.drop {
+            fate.copies = 0;
+            return fate;
+        }
+        if rules.chance() < link.duplicate {
+            fate.copies = 2;
+        }
+        fate.delay = link.delay + link.jitter.mul_f64(rules.chance());
+        fate.hold = rules.chance() < link.reorder;
+        fate
+    }
+}
+
+/// A message waiting out its delay.
+#[derive(Debug)]
+struct Delayed {
+    at: Instant,
+    /// Keeps messages due at the same time in the order they were sent.
+    seq: u64,
+    to: ServerId,
+    message: Message,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.seq).cmp(&(other.at, other.seq))
+    }
+}
+
+/// `inner`, misbehaving as `faults` say. Delayed messages go out from
+/// `recv`, which the log loop always has waiting.
+#[derive(Debug)]
+pub struct FaultTransport<T> {
+    inner: T,
+    faults: Faults,
+    delay: mpsc::UnboundedSender<(Instant, ServerId, Message)>,
+    delayed: mpsc::UnboundedReceiver<(Instant, ServerId, Message)>,
+    queue: BinaryHeap<Reverse<Delayed>>,
+    seq: u64,
+    /// A message per node, held back for reordering.
+    held: Mutex<HashMap<u32, Message>>,
+}
+
+impl<T: Transport> FaultTransport<T> {
+    pub fn new(inner: T, faults: Faults) -> FaultTransport<T> {
+        let (delay, delayed) = mpsc::unbounded_channel();
+        FaultTransport {
+            inner,
+            faults,
+            delay,
+            delayed,
+            queue: BinaryHeap::new(),
+            seq: 0,
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+
+    fn send_after(&self, delay: Duration, to: ServerId, message: Message) {
+        if delay.is_zero() {
+            self.inner.send(to, message);
+        } else {
+            let _ = self.delay.send((Instant::now() + delay, to, message));
+        }
+    }
+}
+
+impl<T: Transport> Transport for FaultTransport<T> {
+    fn local(&self) -> ServerId {
+        self.inner.local()
+    }
+
+    fn send(&self, to: ServerId, message: Message) {
+        let fate = self.faults.fate(self.local().id, to.id);
+        if fate.copies == 0 {
+            return;
+        }
+        let released = {
+            let mut held = self.held.lock().unwrap();
+            match held.remove(&to.id) {
+                None if fate.hold => {
+                    held.insert(to.id, message);
+                    return;
+                }
+                released => released,
+            }
+        };
+        for _ in 1..fate.copies {
+            self.send_after(fate.delay, to, message.clone());
+        }
+        self.send_after(fate.delay, to, message);
+        // whatever was held back goes right behind.
+        if let Some(released) = released {
+            self.send_after(fate.delay, to, released);
+        }
+    }
+
+    /// Messages still waiting out a delay are lost.
+    async fn close(self) {
+        self.inner.close().await
+    }
+
+    async fn recv(&mut self) -> Option<(ServerId, Message)> {
+        loop {
+            let now = Instant::now();
+            while self.queue.peek().is_some_and(|Reverse(d)| d.at <= now) {
+                let Reverse(due) = self.queue.pop().unwrap();
+                self.inner.send(due.to, due.message);
+            }
+            let next = self.queue.peek().map(|Reverse(d)| d.at);
+
+            tokio::select! {
+                received = self.inner.recv() => {
+                    let (from, message) = received?;
+                    if !self.faults.is_cut(from.id, self.inner.local().id) {
+                        return Some((from, message));
+                    }
+                }
+                Some((at, to, message)) = self.delayed.recv() => {
+                    self.seq += 1;
+                    self.queue.push(Reverse(Delayed { at, seq: self.seq, to, message }));
+                }
+                _ = tokio::time::sleep_until(next.unwrap_or(now)), if next.is_some() => {}
+            }
+        }
+    }
+}
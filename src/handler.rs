@@ -1,5 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::command::{
+    CasArgs, CasResult, CompareTarget, Event, EventKind, Frame, GetArgs, KeyValue, LeaseInfo, Page,
+    PrefixArgs, ScanArgs, Txn, TxnOp, TxnResponse, TxnResult, WatchArgs,
+};
+use crate::consensus::{Member, ServerId, TermState};
 
 #[derive(Debug, Clone)]
 pub(crate) struct DbDropGuard {
@@ -24,11 +31,18 @@ pub(crate) struct Db {
 
 impl Db {
     pub(crate) fn new() -> Db {
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
         Db {
             shared: Arc::new(Shared {
+                events,
                 state: Mutex::new(State {
-                    entries: HashMap::new(),
-                    servers: HashMap::new(),
+                    entries: BTreeMap::new(),
+                    members: HashMap::new(),
+                    term: TermState::new(),
+                    revision: 0,
+                    noops: 0,
+                    compacted: 0,
+                    leases: BTreeMap::new(),
                 }),
             }),
         }
@@ -36,28 +50,549 @@ impl Db {
 
     pub(crate) fn get(&self, key: &str) -> Option<String> {
         let state = self.shared.state.lock().unwrap();
-        println!("i have some keys: {:?}", state.entries.keys());
-        state.entries.get(key).map(|entry| entry.clone())
+        state.current(key).and_then(|r| r.value.clone())
+    }
+
+    /// Reads a key with its revisions, either as it is now or as it was at
+    /// `revision`, as long as that revision hasn't been compacted away.
+    pub(crate) fn get_versioned(&self, args: GetArgs) -> Result<Option<KeyValue>, String> {
+        let state = self.shared.state.lock().unwrap();
+        let revision = args.revision.unwrap_or(state.revision);
+        if revision < state.compacted {
+            return Err(format!("revision {} has been compacted", revision));
+        }
+        if revision > state.revision {
+            return Err(format!("revision {} is a future revision", revision));
+        }
+        let record = state
+            .entries
+            .get(&args.key)
+            .and_then(|history| history.iter().rev().find(|r| r.mod_revision <= revision));
+        Ok(record.and_then(|r| r.to_key_value(&args.key)))
+    }
+
+    pub(crate) fn set(&self, revision: u64, key: String, value: String) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        let event = state.put(key, value, revision, None);
+        self.publish(event);
+    }
+
+    /// Writes `key` attached to `lease`, so it goes away when the lease is
+    /// revoked or expires.
+    pub(crate) fn set_with_lease(
+        &self,
+        revision: u64,
+        key: String,
+        value: String,
+        lease: u64,
+    ) -> Result<(), String> {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        if !state.leases.contains_key(&lease) {
+            return Err(format!("lease {} not found", lease));
+        }
+        let event = state.put(key, value, revision, Some(lease));
+        self.publish(event);
+        Ok(())
+    }
+
+    /// Creates a lease. Its id is the revision that created it, so every
+    /// replica applying the same log hands out the same id.
+    pub(crate) fn grant_lease(&self, revision: u64, ttl: u64) -> LeaseInfo {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        let id = revision;
+        state.leases.insert(
+            id,
+            Lease {
+                ttl,
+                keys: BTreeSet::new(),
+            },
+        );
+        LeaseInfo { id, ttl }
+    }
+
+    /// Looks a lease up for a keep-alive. Deadlines live with the leader's
+    /// `LeaseTracker`, not here, so this doesn't change any state.
+    pub(crate) fn lease(&self, id: u64) -> Option<LeaseInfo> {
+        let state = self.shared.state.lock().unwrap();
+        state.leases.get(&id).map(|l| LeaseInfo { id, ttl: l.ttl })
+    }
+
+    pub(crate) fn leases(&self) -> Vec<LeaseInfo> {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .leases
+            .iter()
+            .map(|(id, l)| LeaseInfo { id: *id, ttl: l.ttl })
+            .collect()
+    }
+
+    /// Drops a lease and deletes every key attached to it, all at one
+    /// revision. Returns false if there was no such lease.
+    pub(crate) fn revoke_lease(&self, revision: u64, id: u64) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        let lease = match state.leases.remove(&id) {
+            Some(lease) => lease,
+            None => return false,
+        };
+        for key in lease.keys {
+            if let Some(event) = state.remove(&key, revision) {
+                self.publish(event);
+            }
+        }
+        true
+    }
+
+    pub(crate) fn delete(&self, revision: u64, key: &str) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        match state.remove(key, revision) {
+            Some(event) => {
+                self.publish(event);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub(crate) fn exists(&self, key: &str) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.current(key).is_some()
+    }
+
+    /// Swaps in `new` if the key currently holds `expected`. Checked and
+    /// written under one lock so concurrent writers can't interleave.
+    pub(crate) fn compare_and_swap(&self, revision: u64, cas: CasArgs) -> CasResult {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        let current = state.current(&cas.key).and_then(|r| r.value.clone());
+        if current == cas.expected {
+            let event = state.put(cas.key, cas.new.clone(), revision, None);
+            self.publish(event);
+            CasResult {
+                succeeded: true,
+                current: Some(cas.new),
+            }
+        } else {
+            CasResult {
+                succeeded: false,
+                current,
+            }
+        }
+    }
+
+    /// Writes every pair at one revision, as one entry.
+    pub(crate) fn write_batch(&self, revision: u64, entries: Vec<(String, String)>) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+        for (key, value) in entries {
+            let event = state.put(key, value, revision, None);
+            self.publish(event);
+        }
+    }
+
+    /// Evaluates the comparisons and runs one branch, all under one lock
+    /// and at one revision, so nobody sees the txn half done.
+    pub(crate) fn txn(&self, revision: u64, txn: Txn) -> TxnResult {
+        let mut state = self.shared.state.lock().unwrap();
+        state.advance(revision);
+
+        let succeeded = txn.compares.iter().all(|c| {
+            let current = state.current(&c.key);
+            match &c.target {
+                CompareTarget::Value(v) => current.and_then(|r| r.value.as_ref()) == Some(v),
+                CompareTarget::Version(n) => current.map_or(0, |r| r.version) == *n,
+                CompareTarget::Exists(e) => current.is_some() == *e,
+            }
+        });
+        let ops = if succeeded { txn.success } else { txn.failure };
+
+        let mut responses = Vec::with_capacity(ops.len());
+        for op in ops {
+            let response = match op {
+                TxnOp::Put(k, v) => {
+                    let event = state.put(k, v, revision, None);
+                    self.publish(event);
+                    TxnResponse::Put(revision)
+                }
+                TxnOp::Delete(k) => match state.remove(&k, revision) {
+                    Some(event) => {
+                        self.publish(event);
+                        TxnResponse::Delete(true)
+                    }
+                    None => TxnResponse::Delete(false),
+                },
+                TxnOp::Get(k) => TxnResponse::Get(state.current(&k).and_then(|r| r.to_key_value(&k))),
+            };
+            responses.push(response);
+        }
+        TxnResult {
+            succeeded,
+            responses,
+        }
+    }
+
+    pub(crate) fn scan(&self, args: ScanArgs) -> Page {
+        let state = self.shared.state.lock().unwrap();
+        let range = state
+            .live(args.start..)
+            .take_while(|(k, _)| args.end.as_ref().is_none_or(|end| *k < end));
+        page(range, args.limit)
+    }
+
+    pub(crate) fn prefix(&self, args: PrefixArgs) -> Page {
+        let state = self.shared.state.lock().unwrap();
+        let start = match args.from {
+            Some(from) if from > args.prefix => from,
+            _ => args.prefix.clone(),
+        };
+        let range = state
+            .live(start..)
+            .take_while(|(k, _)| k.starts_with(&args.prefix));
+        page(range, args.limit)
+    }
+
+    /// Starts a watch: returns the revision it starts at (the next one, for
+    /// a `start_revision` of zero), the already-applied events from there
+    /// on, and a receiver for everything applied later. All three are taken
+    /// under the state lock so nothing falls between them.
+    pub(crate) fn watch(
+        &self,
+        args: &WatchArgs,
+    ) -> Result<(u64, Vec<Event>, broadcast::Receiver<Event>), String> {
+        let state = self.shared.state.lock().unwrap();
+        let start = match args.start_revision {
+            0 => state.revision + 1,
+            rev => rev,
+        };
+        if start < state.compacted {
+            return Err(format!("revision {} has been compacted", start));
+        }
+        let receiver = self.shared.events.subscribe();
+
+        let mut backlog: Vec<Event> = state
+            .entries
+            .iter()
+            .filter(|(k, _)| args.matches(k))
+            .flat_map(|(k, history)| {
+                history
+                    .iter()
+                    .filter(|r| r.mod_revision >= start)
+                    .map(move |r| r.to_event(k))
+            })
+            .collect();
+        backlog.sort_by_key(|e| e.kv.mod_revision);
+        Ok((start, backlog, receiver))
+    }
+
+    /// Hands an applied change to any watchers. Called with the state lock
+    /// held so events go out in revision order.
+    fn publish(&self, event: Event) {
+        // no receivers just means nobody is watching.
+        let _ = self.shared.events.send(event);
+    }
+
+    /// Forgets history older than `revision`. Every key keeps the record
+    /// that was current at `revision`, so reads at or after it still work.
+    /// `at` is the revision of the compaction itself.
+    pub(crate) fn compact(&self, at: u64, revision: u64) -> Result<(), String> {
+        let mut state = self.shared.state.lock().unwrap();
+        let current = state.revision;
+        state.advance(at);
+        if revision > current {
+            return Err(format!("revision {} is a future revision", revision));
+        }
+        if revision <= state.compacted {
+            return Ok(());
+        }
+        state.compacted = revision;
+        state.entries.retain(|_, history| {
+            let keep_from = history
+                .iter()
+                .rposition(|r| r.mod_revision <= revision)
+                .unwrap_or(0);
+            history.drain(..keep_from);
+            // a key whose newest record is an old tombstone is gone for good.
+            !(history.len() == 1 && history[0].value.is_none() && history[0].mod_revision <= revision)
+        });
+        Ok(())
+    }
+
+    /// Answers a read-only command from the current state.
+    pub(crate) fn read(&self, frame: Frame) -> Frame {
+        match frame {
+            Frame::Read(k) => match self.get(&k) {
+                Some(v) => Frame::Value(v),
+                None => Frame::Error(String::from("Key not found.")),
+            },
+            Frame::Exists(k) => Frame::Bool(self.exists(&k)),
+            Frame::Scan(args) => Frame::Page(self.scan(args)),
+            Frame::Prefix(args) => Frame::Page(self.prefix(args)),
+            Frame::Get(args) => match self.get_versioned(args) {
+                Ok(Some(kv)) => Frame::Entry(kv),
+                Ok(None) => Frame::Error(String::from("Key not found.")),
+                Err(e) => Frame::Error(e),
+            },
+            // keep-alives only touch the leader's deadlines, see
+            // `LeaseTracker`. Like any read they are only answered once a
+            // quorum confirms this node leads, so a follower never claims
+            // to have kept a lease alive.
+            Frame::LeaseKeepAlive(id) => match self.lease(id) {
+                Some(lease) => Frame::Lease(lease),
+                None => Frame::Error(format!("lease {} not found", id)),
+            },
+            other => Frame::Error(format!("not a read command: {}", other.to_string().trim_end())),
+        }
+    }
+
+    /// Applies a committed log entry. Entries must arrive in index order;
+    /// each command is the store's next revision, whether or not it
+    /// changes anything, and a leader's empty entry is none.
+    pub(crate) fn apply_entry(&self, index: u64, frame: Frame) -> Frame {
+        let revision = index - self.shared.state.lock().unwrap().noops;
+        match frame {
+            Frame::Write(k, v) => {
+                self.set(revision, k, v);
+                Frame::Success
+            }
+            Frame::Delete(k) => Frame::Bool(self.delete(revision, &k)),
+            Frame::CompareAndSwap(cas) => Frame::CasResult(self.compare_and_swap(revision, cas)),
+            Frame::Compact(to) => match self.compact(revision, to) {
+                Ok(()) => Frame::Success,
+                Err(e) => Frame::Error(e),
+            },
+            Frame::Txn(txn) => Frame::TxnResult(self.txn(revision, txn)),
+            Frame::WriteBatch(entries) => {
+                self.write_batch(revision, entries);
+                Frame::Success
+            }
+            Frame::LeaseGrant(ttl) => Frame::Lease(self.grant_lease(revision, ttl)),
+            Frame::LeaseRevoke(id) => Frame::Bool(self.revoke_lease(revision, id)),
+            Frame::LeasePut(k, lease, v) => match self.set_with_lease(revision, k, v, lease) {
+                Ok(()) => Frame::Success,
+                Err(e) => Frame::Error(e),
+            },
+            // the empty entry a new leader starts its term with.
+            Frame::Ping => {
+                self.shared.state.lock().unwrap().noops += 1;
+                Frame::Success
+            }
+            other => {
+                self.shared.state.lock().unwrap().noops += 1;
+                Frame::Error(format!("not a state machine command: {}", other.to_string().trim_end()))
+            }
+        }
+    }
+
+    pub(crate) fn leader(&self) -> Option<ServerId> {
+        let state = self.shared.state.lock().unwrap();
+        state.term.leader
+    }
+
+    pub(crate) fn term_state(&self) -> TermState {
+        let state = self.shared.state.lock().unwrap();
+        state.term.clone()
     }
 
-    pub(crate) fn set(&self, key: String, value: String) {
+    /// Records who leads, as the consensus core last reported it.
+    pub(crate) fn set_term_state(&self, term: TermState) {
         let mut state = self.shared.state.lock().unwrap();
-        let _prev = state.entries.insert(key, value);
+        state.term = term;
     }
 
-    pub(crate) fn add_server(&self, address: String, name: String) {
+    /// The store's revision: how many writes it has applied.
+    pub(crate) fn revision(&self) -> u64 {
+        let state = self.shared.state.lock().unwrap();
+        state.revision
+    }
+
+    pub(crate) fn add_member(&self, member: Member) {
         let mut state = self.shared.state.lock().unwrap();
-        state.servers.insert(address, name);
+        state.members.insert(member.id, member);
+    }
+
+    pub(crate) fn member(&self, id: u32) -> Option<Member> {
+        let state = self.shared.state.lock().unwrap();
+        state.members.get(&id).copied()
+    }
+
+    pub(crate) fn members(&self) -> Vec<Member> {
+        let state = self.shared.state.lock().unwrap();
+        let mut members: Vec<Member> = state.members.values().copied().collect();
+        members.sort_by_key(|m| m.id);
+        members
     }
 }
 
+/// Page size when a scan doesn't ask for one, and the most a scan may ask for.
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Collects up to `limit` entries, remembering the first key left over as
+/// the continuation token.
+fn page<'a>(mut range: impl Iterator<Item = (&'a String, &'a String)>, limit: u32) -> Page {
+    let limit = match limit as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        n => n.min(MAX_PAGE_SIZE),
+    };
+    let entries = range
+        .by_ref()
+        .take(limit)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    let next = range.next().map(|(k, _)| k.clone());
+    Page { entries, next }
+}
+
+/// How many events a slow watcher can fall behind before it has to catch
+/// up from history.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 struct Shared {
     state: Mutex<State>,
+    events: broadcast::Sender<Event>,
 }
 
 #[derive(Debug)]
 struct State {
-    entries: HashMap<String, String>,
-    servers: HashMap<String, String>,
+    /// Every key's history, oldest first.
+    entries: BTreeMap<String, Vec<Record>>,
+    members: HashMap<u32, Member>,
+    term: TermState,
+    /// Revision of the last write applied.
+    revision: u64,
+    /// Log entries applied that weren't commands: leaders' empty entries.
+    /// Every other entry is a revision, so a revision is its entry's index
+    /// less these.
+    noops: u64,
+    /// Oldest revision that can still be read.
+    compacted: u64,
+    leases: BTreeMap<u64, Lease>,
+}
+
+#[derive(Debug)]
+struct Lease {
+    /// Seconds without a keep-alive before the leader revokes it.
+    ttl: u64,
+    keys: BTreeSet<String>,
+}
+
+/// One version of a key. Deletes are recorded as a tombstone (`value` is
+/// `None`) so reads at older revisions still see what was there before.
+#[derive(Debug)]
+struct Record {
+    value: Option<String>,
+    create_revision: u64,
+    mod_revision: u64,
+    version: u64,
+    lease: Option<u64>,
+}
+
+impl Record {
+    fn to_key_value(&self, key: &str) -> Option<KeyValue> {
+        self.value.as_ref().map(|value| KeyValue {
+            key: key.to_string(),
+            value: value.clone(),
+            create_revision: self.create_revision,
+            mod_revision: self.mod_revision,
+            version: self.version,
+        })
+    }
+
+    fn to_event(&self, key: &str) -> Event {
+        match self.to_key_value(key) {
+            Some(kv) => Event {
+                kind: EventKind::Put,
+                kv,
+            },
+            None => Event {
+                kind: EventKind::Delete,
+                kv: KeyValue {
+                    key: key.to_string(),
+                    value: String::new(),
+                    create_revision: 0,
+                    mod_revision: self.mod_revision,
+                    version: 0,
+                },
+            },
+        }
+    }
+}
+
+impl State {
+    /// Moves the store to the revision of the write being applied.
+    fn advance(&mut self, revision: u64) {
+        debug_assert!(revision > self.revision, "entries applied out of order");
+        self.revision = revision;
+    }
+
+    /// The key's latest record, unless the key is deleted.
+    fn current(&self, key: &str) -> Option<&Record> {
+        self.entries
+            .get(key)
+            .and_then(|history| history.last())
+            .filter(|r| r.value.is_some())
+    }
+
+    fn put(&mut self, key: String, value: String, revision: u64, lease: Option<u64>) -> Event {
+        let (create_revision, version) = match self.current(&key) {
+            Some(prev) => (prev.create_revision, prev.version + 1),
+            None => (revision, 1),
+        };
+        self.detach(&key);
+        if let Some(lease) = lease.and_then(|id| self.leases.get_mut(&id)) {
+            lease.keys.insert(key.clone());
+        }
+        let record = Record {
+            value: Some(value),
+            create_revision,
+            mod_revision: revision,
+            version,
+            lease,
+        };
+        let event = record.to_event(&key);
+        self.entries.entry(key).or_default().push(record);
+        event
+    }
+
+    /// Tombstones the key, returning None if it didn't exist.
+    fn remove(&mut self, key: &str, revision: u64) -> Option<Event> {
+        self.current(key)?;
+        self.detach(key);
+        let record = Record {
+            value: None,
+            create_revision: 0,
+            mod_revision: revision,
+            version: 0,
+            lease: None,
+        };
+        let event = record.to_event(key);
+        self.entries.get_mut(key).unwrap().push(record);
+        Some(event)
+    }
+
+    /// Unhooks the key from whatever lease its current value was written with.
+    fn detach(&mut self, key: &str) {
+        let lease = self.current(key).and_then(|r| r.lease);
+        if let Some(lease) = lease.and_then(|id| self.leases.get_mut(&id)) {
+            lease.keys.remove(key);
+        }
+    }
+
+    /// Live keys and their current values, in order, starting at `start`.
+    fn live(
+        &self,
+        start: std::ops::RangeFrom<String>,
+    ) -> impl Iterator<Item = (&String, &String)> {
+        self.entries.range(start).filter_map(|(k, history)| {
+            history
+                .last()
+                .and_then(|r| r.value.as_ref())
+                .map(|v| (k, v))
+        })
+    }
 }
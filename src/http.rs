@@ -0,0 +1,426 @@
+// HTTP/JSON gateway for services that can't speak the native protocol.
+//
+//   GET    /v1/kv/{key}   read a key
+//   PUT    /v1/kv/{key}   write the request body as the value
+//   DELETE /v1/kv/{key}   delete a key
+//   GET    /v1/status     term, role, leader, commit/applied index, members
+//                         and connection counts
+//
+// Key-value requests go through the same state machine path as READ and
+// WRITE frames. Followers answer writes with a 307 pointing at the leader,
+// and an overloaded leader answers with a 503 and Retry-After. Request
+// heads over 8 KiB get a 431 and bodies over 1 MiB a 413. Bodies have to
+// come with a Content-Length: a Transfer-Encoding gets a 501. A query
+// string is ignored.
+
+use bytes::{Buf, BytesMut};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, error, info};
+
+use crate::admission::{Admission, Rejection};
+use crate::command::Frame;
+use crate::connection::Result;
+use crate::consensus::{Member, ServerId, ServerState};
+use crate::handler::Db;
+use crate::log::Proposer;
+use crate::server::{Shutdown, ShutdownSignal};
+
+/// The most a request line and headers may take.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+
+/// The largest body taken, which bounds the values written over HTTP.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    keep_alive: bool,
+    body: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    reason: &'static str,
+    location: Option<String>,
+    /// Seconds, for the Retry-After header.
+    retry_after: Option<u64>,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, reason: &'static str, body: String) -> Response {
+        Response {
+            status,
+            reason,
+            location: None,
+            retry_after: None,
+            body,
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, msg: &str) -> Response {
+        Response::json(status, reason, format!("{{\"error\":{}}}", json_string(msg)))
+    }
+
+    /// Retry-After only has whole seconds, so round up.
+    fn busy(retry_after_ms: u64) -> Response {
+        Response {
+            retry_after: Some(retry_after_ms.div_ceil(1000).max(1)),
+            ..Response::error(503, "Service Unavailable", "server busy")
+        }
+    }
+}
+
+pub(crate) async fn serve(
+    listener: TcpListener,
+    proposer: Proposer,
+    local: ServerId,
+    admission: Admission,
+    signal: ShutdownSignal,
+) -> Result<()> {
+    info!("Accepting HTTP connections on {}", listener.local_addr()?);
+    loop {
+        let (socket, address) = listener.accept().await?;
+        let ticket = match admission.admit(address.ip()) {
+            Ok(ticket) => ticket,
+            Err(rejection) => {
+                debug!(%address, %rejection, "refusing HTTP connection");
+                tokio::spawn(async move { HttpConnection::new(socket).refuse(rejection).await });
+                continue;
+            }
+        };
+        let proposer = proposer.clone();
+        let admission = admission.clone();
+        let mut shutdown = signal.subscribe();
+
+        tokio::spawn(async move {
+            let mut connection = HttpConnection::new(socket);
+            if let Err(err) = connection.run(&proposer, local, &admission, &mut shutdown).await {
+                error!(cause = ?err, "http connection error");
+            }
+            drop(ticket);
+        });
+    }
+}
+
+struct HttpConnection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+}
+
+impl HttpConnection {
+    fn new(socket: TcpStream) -> HttpConnection {
+        HttpConnection {
+            stream: BufWriter::new(socket),
+            buffer: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// Serves until the client hangs up, or the server shuts down between
+    /// requests.
+    async fn run(
+        &mut self,
+        proposer: &Proposer,
+        local: ServerId,
+        admission: &Admission,
+        shutdown: &mut Shutdown,
+    ) -> Result<()> {
+        loop {
+            let request = tokio::select! {
+                res = self.read_request() => res?,
+                _ = shutdown.recv() => break,
+                _ = admission.idle() => {
+                    debug!("closing idle HTTP connection");
+                    admission.closed_idle();
+                    break;
+                }
+            };
+            let Some(request) = request else { break };
+            debug!(method = %request.method, path = %request.path, "http request");
+            let keep_alive = request.keep_alive;
+            let response = handle(proposer, local, admission, request).await;
+            self.write_response(&response, keep_alive).await?;
+            if !keep_alive {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a connection that was not let in with a 503. Its request is
+    /// read first: closing with it unread would reset the connection under
+    /// the answer.
+    async fn refuse(mut self, rejection: Rejection) {
+        if let Ok(Err(_)) = time::timeout(Duration::from_secs(1), self.read_request()).await {
+            // it has had its answer.
+            return;
+        }
+        let response = Response::error(503, "Service Unavailable", &rejection.to_string());
+        let _ = self.write_response(&response, false).await;
+    }
+
+    async fn read_request(&mut self) -> Result<Option<Request>> {
+        loop {
+            match parse_request(&mut self.buffer) {
+                Ok(Some(request)) => return Ok(Some(request)),
+                Ok(None) => {}
+                Err(response) => {
+                    // nothing after a bad request can be framed, so answer
+                    // it and hang up.
+                    self.write_response(&response, false).await?;
+                    return Err(format!("bad request: {}", response.reason).into());
+                }
+            }
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+
+    async fn write_response(&mut self, response: &Response, keep_alive: bool) -> Result<()> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            response.status,
+            response.reason,
+            response.body.len()
+        );
+        if let Some(location) = &response.location {
+            head.push_str(&format!("Location: {}\r\n", location));
+        }
+        if let Some(seconds) = response.retry_after {
+            head.push_str(&format!("Retry-After: {}\r\n", seconds));
+        }
+        if !keep_alive {
+            head.push_str("Connection: close\r\n");
+        }
+        head.push_str("\r\n");
+        self.stream.write_all(head.as_bytes()).await?;
+        self.stream.write_all(response.body.as_bytes()).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+/// Pulls one complete request off the front of `buffer`, or returns None if
+/// the headers or body haven't fully arrived yet. A request that can't be
+/// taken comes back as the response to answer it with.
+fn parse_request(buffer: &mut BytesMut) -> std::result::Result<Option<Request>, Response> {
+    let too_large = || Response::error(431, "Request Header Fields Too Large", "request head too large");
+    let head_end = match buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) if pos > MAX_HEAD_BYTES => return Err(too_large()),
+        Some(pos) => pos,
+        None if buffer.len() > MAX_HEAD_BYTES => return Err(too_large()),
+        None => return Ok(None),
+    };
+    let bad_request = |msg: &str| Response::error(400, "Bad Request", msg);
+    let head = String::from_utf8(buffer[..head_end].to_vec()).map_err(|_| bad_request("request head must be utf-8"))?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, path, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(p), Some(v)) => {
+            let path = p.split_once('?').map_or(p, |(path, _)| path);
+            (m.to_string(), path.to_string(), v)
+        }
+        _ => return Err(bad_request(&format!("malformed request line `{}`", request_line))),
+    };
+
+    let mut content_length = 0;
+    let mut keep_alive = version == "HTTP/1.1";
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => {
+                    content_length = value.parse().map_err(|_| bad_request("invalid content-length"))?
+                }
+                "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+                // without decoding it there is no telling where the body
+                // ends, or where the next request starts.
+                "transfer-encoding" => {
+                    return Err(Response::error(501, "Not Implemented", "transfer-encoding is not supported"))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err(Response::error(413, "Content Too Large", "request body too large"));
+    }
+
+    let body_start = head_end + 4;
+    if buffer.len() < body_start + content_length {
+        return Ok(None);
+    }
+    let body = buffer[body_start..body_start + content_length].to_vec();
+    buffer.advance(body_start + content_length);
+
+    Ok(Some(Request {
+        method,
+        path,
+        keep_alive,
+        body,
+    }))
+}
+
+async fn handle(proposer: &Proposer, local: ServerId, admission: &Admission, request: Request) -> Response {
+    let db = proposer.db();
+    if request.path == "/v1/status" {
+        return match request.method.as_str() {
+            "GET" => Response::json(200, "OK", status(proposer, admission)),
+            _ => Response::error(405, "Method Not Allowed", "method not allowed"),
+        };
+    }
+
+    let key = match request.path.strip_prefix("/v1/kv/") {
+        Some(key) if !key.is_empty() => match percent_decode(key) {
+            Some(key) => key,
+            None => return Response::error(400, "Bad Request", "invalid key encoding"),
+        },
+        _ => return Response::error(404, "Not Found", "not found"),
+    };
+
+    match request.method.as_str() {
+        "GET" => match proposer.execute(Frame::Read(key.clone())).await {
+            Frame::Value(v) => Response::json(200, "OK", kv_json(&key, &v)),
+            Frame::Error(e) if e == "Key not found." => Response::error(404, "Not Found", &e),
+            // only the leader reads, and this node isn't it or can't tell.
+            Frame::Error(e) if e == "not the leader" => Response::error(503, "Service Unavailable", &e),
+            Frame::Busy(ms) => Response::busy(ms),
+            other => Response::error(500, "Internal Server Error", other.to_string().trim_end()),
+        },
+        "PUT" | "DELETE" => {
+            if let Some(redirect) = redirect(db, local, &request.path) {
+                return redirect;
+            }
+            if request.method == "DELETE" {
+                return match proposer.execute(Frame::Delete(key)).await {
+                    Frame::Bool(deleted) => Response::json(200, "OK", format!("{{\"deleted\":{}}}", deleted)),
+                    Frame::Busy(ms) => Response::busy(ms),
+                    other => Response::error(500, "Internal Server Error", other.to_string().trim_end()),
+                };
+            }
+            let value = match String::from_utf8(request.body) {
+                Ok(value) => value,
+                Err(_) => return Response::error(400, "Bad Request", "value must be utf-8"),
+            };
+            match proposer.execute(Frame::Write(key.clone(), value.clone())).await {
+                Frame::Success => Response::json(200, "OK", kv_json(&key, &value)),
+                Frame::Busy(ms) => Response::busy(ms),
+                other => Response::error(500, "Internal Server Error", other.to_string().trim_end()),
+            }
+        }
+        _ => Response::error(405, "Method Not Allowed", "method not allowed"),
+    }
+}
+
+fn redirect(db: &Db, local: ServerId, path: &str) -> Option<Response> {
+    match db.leader() {
+        Some(leader) if leader == local => None,
+        Some(leader) => match db.member(leader.id).and_then(|m| m.http) {
+            Some(http) => Some(Response {
+                location: Some(format!("http://{}{}", http, path)),
+                ..Response::error(307, "Temporary Redirect", "not the leader")
+            }),
+            None => Some(Response::error(503, "Service Unavailable", "the leader has no http address")),
+        },
+        None => Some(Response::error(503, "Service Unavailable", "no leader elected")),
+    }
+}
+
+fn status(proposer: &Proposer, admission: &Admission) -> String {
+    let db = proposer.db();
+    let commit_index = *proposer.commit_index().borrow();
+    let applied_index = *proposer.applied_index().borrow();
+    let term = db.term_state();
+    let role = match term.server_state {
+        ServerState::Leader => "leader",
+        ServerState::Candidate => "candidate",
+        ServerState::Follower => "follower",
+    };
+    let leader = term.leader.map_or("null".to_string(), |l| server_json(&l));
+    let members: Vec<String> = db.members().iter().map(member_json).collect();
+    let connections = admission.stats();
+    format!(
+        "{{\"term\":{},\"role\":\"{}\",\"leader\":{},\"commit_index\":{},\"applied_index\":{},\"revision\":{},\"members\":[{}],\"connections\":{{\"open\":{},\"rejected\":{},\"rejected_per_ip\":{},\"closed_idle\":{}}}}}",
+        term.current_term,
+        role,
+        leader,
+        commit_index,
+        applied_index,
+        db.revision(),
+        members.join(","),
+        connections.open,
+        connections.rejected,
+        connections.rejected_per_ip,
+        connections.closed_idle
+    )
+}
+
+fn member_json(member: &Member) -> String {
+    format!(
+        "{{\"id\":{},\"address\":{},\"resp_address\":{},\"http_address\":{}}}",
+        member.id,
+        json_string(&member.client.to_string()),
+        member.resp.map_or("null".to_string(), |a| json_string(&a.to_string())),
+        member.http.map_or("null".to_string(), |a| json_string(&a.to_string()))
+    )
+}
+
+fn server_json(server: &ServerId) -> String {
+    format!(
+        "{{\"id\":{},\"address\":{}}}",
+        server.id,
+        json_string(&server.address.to_string())
+    )
+}
+
+fn kv_json(key: &str, value: &str) -> String {
+    format!("{{\"key\":{},\"value\":{}}}", json_string(key), json_string(value))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
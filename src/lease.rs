@@ -0,0 +1,83 @@
+// lease expiry. The replicated state in `Db` only knows each lease's ttl and
+// attached keys; when a lease runs out is decided here, by the leader alone,
+// and acted on by applying a LEASEREVOKE like any other command. Replicas
+// never expire anything off their own clocks.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::clock::{Clock, Interval};
+use crate::command::{Frame, LeaseInfo};
+use crate::consensus::ServerId;
+use crate::log::Proposer;
+
+/// How often the leader looks for expired leases.
+const CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Leader-local deadlines for every lease.
+#[derive(Debug, Clone)]
+pub(crate) struct LeaseTracker {
+    clock: Arc<dyn Clock>,
+    deadlines: Arc<Mutex<HashMap<u64, Instant>>>,
+}
+
+impl LeaseTracker {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> LeaseTracker {
+        LeaseTracker {
+            clock,
+            deadlines: Arc::default(),
+        }
+    }
+
+    /// Pushes the lease's deadline out by its ttl. Called on grant and on
+    /// every keep-alive.
+    pub(crate) fn keep_alive(&self, lease: LeaseInfo) {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        deadlines.insert(lease.id, self.clock.now() + Duration::from_secs(lease.ttl));
+    }
+
+    /// Lines the tracked deadlines up with the leases that exist, and
+    /// returns the ids that have run out. Leases we haven't seen before
+    /// (say, granted under a previous leader) get a full ttl from now.
+    fn expired(&self, leases: &[LeaseInfo], now: Instant) -> Vec<u64> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        deadlines.retain(|id, _| leases.iter().any(|l| l.id == *id));
+        leases
+            .iter()
+            .filter(|lease| {
+                let deadline = deadlines
+                    .entry(lease.id)
+                    .or_insert_with(|| now + Duration::from_secs(lease.ttl));
+                *deadline <= now
+            })
+            .map(|lease| lease.id)
+            .collect()
+    }
+
+    fn clear(&self) {
+        self.deadlines.lock().unwrap().clear();
+    }
+}
+
+/// Revokes expired leases for as long as the server runs. Only does
+/// anything while this node is the leader; followers drop their deadlines
+/// so they start fresh if they're elected.
+pub(crate) async fn expire_leases(proposer: Proposer, tracker: LeaseTracker, local: ServerId) {
+    let db = proposer.db();
+    let mut interval = Interval::new(tracker.clock.clone(), CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if db.leader() != Some(local) {
+            tracker.clear();
+            continue;
+        }
+
+        for id in tracker.expired(&db.leases(), tracker.clock.now()) {
+            info!(lease = id, "lease expired, revoking");
+            let result = proposer.execute(Frame::LeaseRevoke(id)).await;
+            debug!(?result, "revoked lease {}", id);
+        }
+    }
+}
@@ -1,6 +1,20 @@
+pub mod admission;
+mod apply;
 pub mod client;
+pub mod clock;
 pub mod command;
+pub mod config;
 pub mod connection;
 pub mod consensus;
+pub mod disk;
+pub mod fault;
 pub mod handler;
+pub mod http;
+pub mod lease;
+pub mod log;
+pub mod peer;
+pub mod raft;
+pub mod resp;
 pub mod server;
+mod storage;
+pub mod transport;
@@ -0,0 +1,543 @@
+// the replicated log, and the leader's proposal pipeline in front of it.
+//
+// Every write from every front end becomes a proposal. One task owns the
+// log and drives the consensus core (see raft.rs): it proposes whatever
+// has queued up as a single batch (one flush for the lot, i.e. group
+// commit), persists and sends what the core asks for, then hands committed
+// entries to the apply task (see apply.rs), which answers each waiting
+// client. Reads skip the log: the leader confirms with a quorum that it
+// still leads (ReadIndex), then serves them from local state once that has
+// caught up.
+//
+// Admission is bounded: a proposal holds an in-flight slot and its size in
+// uncommitted bytes until it is applied. When either runs out the client
+// gets BUSY straight away instead of queueing without limit.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error, info};
+
+use crate::apply::Committed;
+use crate::clock::{Clock, Interval};
+use crate::command::Frame;
+use crate::consensus::{ServerId, ServerState, TermState};
+use crate::disk::{Disk, FileDisk};
+use crate::handler::Db;
+use crate::raft::{HardState, RaftNode};
+use crate::storage::Storage;
+use crate::transport::Transport;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Frame,
+}
+
+/// The log, kept in memory and, when it is opened on a directory or a
+/// `Disk`, on disk too (see storage.rs). Indexes start at 1, like the
+/// paper.
+#[derive(Debug, Default)]
+pub struct Log {
+    entries: Vec<LogEntry>,
+    commit_index: u64,
+    hard_state: HardState,
+    flushes: u64,
+    storage: Option<Storage>,
+}
+
+impl Log {
+    /// A log that only lives in memory.
+    pub fn new() -> Log {
+        Log::default()
+    }
+
+    /// The log persisted in `dir`, with whatever was there already.
+    pub fn open(dir: &Path) -> io::Result<Log> {
+        Log::with_disk(FileDisk::open(dir)?)
+    }
+
+    /// The log persisted on `disk`, with whatever was there already.
+    pub fn with_disk(disk: impl Disk) -> io::Result<Log> {
+        let (storage, hard_state, entries) = Storage::open(Box::new(disk))?;
+        let mut log = Log {
+            entries,
+            hard_state,
+            storage: Some(storage),
+            ..Log::default()
+        };
+        log.commit_to(hard_state.commit);
+        Ok(log)
+    }
+
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.entries
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.entries.last().map_or(0, |e| e.index)
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(0, |e| e.term)
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    /// How many times the log has been flushed. With group commit this
+    /// grows with the number of batches, not the number of entries.
+    pub fn flushes(&self) -> u64 {
+        self.flushes
+    }
+
+    /// Appends a batch of commands at `term` with a single flush, and
+    /// returns the new entries.
+    pub fn append(&mut self, term: u64, commands: Vec<Frame>) -> io::Result<Vec<LogEntry>> {
+        let first = self.last_index() + 1;
+        let entries: Vec<LogEntry> = commands
+            .into_iter()
+            .enumerate()
+            .map(|(i, command)| LogEntry {
+                term,
+                index: first + i as u64,
+                command,
+            })
+            .collect();
+        self.append_entries(entries.clone())?;
+        Ok(entries)
+    }
+
+    /// Stores entries handed out by the consensus core with a single flush.
+    /// They replace anything already in the log from the first one's index.
+    pub fn append_entries(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        if let Some(storage) = &mut self.storage {
+            storage.append(&entries)?;
+        }
+        self.flushes += 1;
+        self.entries.truncate((first.index - 1) as usize);
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    pub fn hard_state(&self) -> HardState {
+        self.hard_state
+    }
+
+    pub fn set_hard_state(&mut self, hard_state: HardState) -> io::Result<()> {
+        if let Some(storage) = &mut self.storage {
+            storage.set_hard_state(hard_state)?;
+        }
+        self.flushes += 1;
+        self.hard_state = hard_state;
+        self.commit_to(hard_state.commit);
+        Ok(())
+    }
+
+    /// Up to `max` entries starting at `from`, for an AppendEntries.
+    pub fn entries_from(&self, from: u64, max: usize) -> &[LogEntry] {
+        let start = (from.max(1) - 1) as usize;
+        let end = (start + max).min(self.entries.len());
+        self.entries.get(start..end).unwrap_or_default()
+    }
+
+    pub fn commit_to(&mut self, index: u64) {
+        self.commit_index = self.commit_index.max(index.min(self.last_index()));
+    }
+}
+
+/// How long an overloaded leader asks clients to back off.
+const RETRY_AFTER_MS: u64 = 50;
+
+#[derive(Debug)]
+pub(crate) struct Proposal {
+    command: Frame,
+    respond: oneshot::Sender<Frame>,
+    reservation: Reservation,
+}
+
+/// Flow-control limits on proposals that haven't been applied yet.
+#[derive(Debug)]
+pub(crate) struct Limits {
+    slots: Arc<Semaphore>,
+    uncommitted: Arc<AtomicUsize>,
+    max_uncommitted: usize,
+}
+
+impl Limits {
+    pub(crate) fn new(max_inflight: usize, max_uncommitted_bytes: usize) -> Limits {
+        Limits {
+            slots: Arc::new(Semaphore::new(max_inflight)),
+            uncommitted: Arc::new(AtomicUsize::new(0)),
+            max_uncommitted: max_uncommitted_bytes,
+        }
+    }
+
+    /// Takes a slot and `bytes` of budget, or None if the leader is full. A
+    /// proposal bigger than the whole budget still goes through when nothing
+    /// else is outstanding, otherwise it could never be written.
+    fn reserve(&self, bytes: usize) -> Option<Reservation> {
+        let permit = self.slots.clone().try_acquire_owned().ok()?;
+        let before = self.uncommitted.fetch_add(bytes, Ordering::AcqRel);
+        if before > 0 && before + bytes > self.max_uncommitted {
+            self.uncommitted.fetch_sub(bytes, Ordering::AcqRel);
+            return None;
+        }
+        Some(Reservation {
+            _permit: permit,
+            bytes,
+            uncommitted: self.uncommitted.clone(),
+        })
+    }
+}
+
+/// A proposal's share of the limits, handed back when it is dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    _permit: OwnedSemaphorePermit,
+    bytes: usize,
+    uncommitted: Arc<AtomicUsize>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.uncommitted.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// Asks the log loop for a read index; None if this node doesn't lead.
+pub(crate) type ReadRequest = oneshot::Sender<Option<u64>>;
+
+/// Handle front ends use to run client commands.
+#[derive(Debug, Clone)]
+pub(crate) struct Proposer {
+    proposals: mpsc::Sender<Proposal>,
+    reads: mpsc::Sender<ReadRequest>,
+    limits: Arc<Limits>,
+    committed: watch::Receiver<u64>,
+    applied: watch::Receiver<u64>,
+    db: Db,
+}
+
+/// The task ends of a `Proposer`: proposals for the log loop, and the
+/// commit and apply indexes the log and apply tasks publish.
+#[derive(Debug)]
+pub(crate) struct Pipeline {
+    pub(crate) proposals: mpsc::Receiver<Proposal>,
+    pub(crate) reads: mpsc::Receiver<ReadRequest>,
+    pub(crate) committed: watch::Sender<u64>,
+    pub(crate) applied: watch::Sender<u64>,
+}
+
+impl Proposer {
+    pub(crate) fn new(db: Db, capacity: usize, limits: Limits) -> (Proposer, Pipeline) {
+        let (proposals, receiver) = mpsc::channel(capacity);
+        let (reads, read_requests) = mpsc::channel(capacity);
+        let (committed_tx, committed) = watch::channel(0);
+        let (applied_tx, applied) = watch::channel(0);
+        let proposer = Proposer {
+            proposals,
+            reads,
+            limits: Arc::new(limits),
+            committed,
+            applied,
+            db,
+        };
+        let pipeline = Pipeline {
+            proposals: receiver,
+            reads: read_requests,
+            committed: committed_tx,
+            applied: applied_tx,
+        };
+        (proposer, pipeline)
+    }
+
+    pub(crate) fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// Index of the last entry committed to the log.
+    pub(crate) fn commit_index(&self) -> watch::Receiver<u64> {
+        self.committed.clone()
+    }
+
+    /// Index of the last entry the state machine has applied.
+    pub(crate) fn applied_index(&self) -> watch::Receiver<u64> {
+        self.applied.clone()
+    }
+
+    /// Confirms with a quorum that this node still leads, then waits until
+    /// everything committed by then has been applied, and returns that
+    /// index. A read served after this sees every write that was
+    /// acknowledged before it started, even on a leader that has since been
+    /// cut off and replaced. None if this node doesn't lead.
+    pub(crate) async fn read_index(&self) -> Option<u64> {
+        let (respond, index) = oneshot::channel();
+        self.reads.send(respond).await.ok()?;
+        let index = index.await.ok()??;
+        let mut applied = self.applied.clone();
+        // if the apply task is gone there is nothing left to wait for.
+        let _ = applied.wait_for(|applied| *applied >= index).await;
+        Some(index)
+    }
+
+    /// Runs a client command: writes are proposed to the log and answered
+    /// once applied, reads are answered by the leader from local state once
+    /// it has a read index. Writes over the flow-control limits are
+    /// answered with BUSY.
+    pub(crate) async fn execute(&self, command: Frame) -> Frame {
+        if !command.is_write() {
+            if self.read_index().await.is_none() {
+                return Frame::Error(String::from("not the leader"));
+            }
+            return self.db.read(command);
+        }
+        let Some(reservation) = self.limits.reserve(command.to_string().len()) else {
+            debug!("proposal limits reached, answering busy");
+            return Frame::Busy(RETRY_AFTER_MS);
+        };
+        let (respond, response) = oneshot::channel();
+        let proposal = Proposal {
+            command,
+            respond,
+            reservation,
+        };
+        if self.proposals.send(proposal).await.is_err() {
+            return Frame::Error(String::from("log is shut down"));
+        }
+        response
+            .await
+            .unwrap_or_else(|_| Frame::Error(String::from("proposal dropped")))
+    }
+}
+
+/// A proposal appended to the log, waiting for its entry to commit.
+#[derive(Debug)]
+pub(crate) struct Pending {
+    term: u64,
+    respond: oneshot::Sender<Frame>,
+    reservation: Reservation,
+}
+
+/// The task that owns the log and drives the consensus core: it ticks the
+/// core's clock, feeds it proposals and peer messages, and carries out
+/// each `Ready` it hands back.
+#[derive(Debug)]
+pub(crate) struct LogLoop<T> {
+    pub(crate) node: RaftNode,
+    pub(crate) transport: T,
+    /// Every other node by id, as the transport knows them.
+    pub(crate) peers: HashMap<u32, ServerId>,
+    /// Every node by id, as clients know them, for redirects.
+    pub(crate) members: HashMap<u32, ServerId>,
+    pub(crate) proposals: mpsc::Receiver<Proposal>,
+    pub(crate) reads: mpsc::Receiver<ReadRequest>,
+    pub(crate) committed: watch::Sender<u64>,
+    pub(crate) apply: mpsc::UnboundedSender<Committed>,
+    pub(crate) db: Db,
+    pub(crate) log: Log,
+    pub(crate) max_batch: usize,
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Time per tick of the core's clock.
+    pub(crate) tick: Duration,
+    /// Proposals by log index.
+    pub(crate) pending: HashMap<u64, Pending>,
+    /// Reads waiting for a quorum, by read id.
+    pub(crate) pending_reads: HashMap<u64, ReadRequest>,
+    pub(crate) next_read: u64,
+}
+
+impl<T: Transport> LogLoop<T> {
+    /// Runs until every `Proposer` is dropped, the transport shuts down,
+    /// the apply task goes away or the log can't be persisted, or until
+    /// told to `stop`.
+    pub(crate) async fn run(mut self, mut stop: oneshot::Receiver<()>) {
+        let mut ticker = Interval::new(self.clock.clone(), self.tick);
+        loop {
+            if !self.handle_ready() {
+                return;
+            }
+            tokio::select! {
+                _ = &mut stop => break,
+                _ = ticker.tick() => self.node.tick(),
+                proposal = self.proposals.recv() => match proposal {
+                    Some(first) => self.propose(first),
+                    None => return,
+                },
+                read = self.reads.recv() => match read {
+                    Some(respond) => self.read_index(respond),
+                    None => return,
+                },
+                message = self.transport.recv() => match message {
+                    Some((from, message)) => self.node.step(from.id, message),
+                    None => return,
+                },
+            }
+        }
+        self.stop().await
+    }
+
+    /// Hands leadership over if this node has it, so the cluster needn't
+    /// wait out an election timeout, then persists and sends what is left.
+    /// The log is synced to disk by the time this returns.
+    async fn stop(mut self) {
+        if let Some(to) = self.node.transfer_leadership() {
+            info!(to, "handing leadership over");
+        }
+        if self.handle_ready() {
+            debug!(last_index = self.log.last_index(), "log persisted");
+        }
+        self.transport.close().await;
+    }
+
+    /// Appends `first` and whatever queued up behind it as one batch, so a
+    /// burst of writes costs a single flush (group commit).
+    fn propose(&mut self, first: Proposal) {
+        let mut batch = vec![first];
+        while batch.len() < self.max_batch {
+            match self.proposals.try_recv() {
+                Ok(proposal) => batch.push(proposal),
+                Err(_) => break,
+            }
+        }
+
+        let term = self.node.term();
+        let (commands, waiters): (Vec<Frame>, Vec<_>) = batch
+            .into_iter()
+            .map(|p| (p.command, (p.respond, p.reservation)))
+            .unzip();
+        let Some(first_index) = self.node.propose(commands) else {
+            for (respond, _) in waiters {
+                let _ = respond.send(Frame::Error(String::from("not the leader")));
+            }
+            return;
+        };
+        // reservations ride along with the waiters so the budget is only
+        // handed back once the entry has been applied.
+        for (i, (respond, reservation)) in waiters.into_iter().enumerate() {
+            let waiter = Pending {
+                term,
+                respond,
+                reservation,
+            };
+            self.pending.insert(first_index + i as u64, waiter);
+        }
+    }
+
+    fn read_index(&mut self, respond: ReadRequest) {
+        let id = self.next_read;
+        self.next_read += 1;
+        if self.node.read_index(id) {
+            self.pending_reads.insert(id, respond);
+        } else {
+            let _ = respond.send(None);
+        }
+    }
+
+    /// Carries out what the core asked for, in the order it needs: persist,
+    /// then send, then apply. False once the log can't be persisted or the
+    /// apply task is gone.
+    pub(crate) fn handle_ready(&mut self) -> bool {
+        if !self.node.has_ready() {
+            return true;
+        }
+        let ready = self.node.ready();
+
+        for read in ready.reads {
+            if let Some(respond) = self.pending_reads.remove(&read.id) {
+                let _ = respond.send(Some(read.index));
+            }
+        }
+        let stepped_down = ready.soft_state.is_some_and(|soft| soft.role != ServerState::Leader);
+        if let Some(soft) = ready.soft_state {
+            // a leader keeps its term and role for as long as it leads, so
+            // any change means the core dropped the reads it hadn't
+            // confirmed yet.
+            for (_, respond) in self.pending_reads.drain() {
+                let _ = respond.send(None);
+            }
+            debug!(term = soft.term, role = ?soft.role, leader = ?soft.leader, "raft state changed");
+            self.db.set_term_state(TermState {
+                current_term: soft.term,
+                server_state: soft.role,
+                leader: soft.leader.and_then(|id| self.members.get(&id).copied()),
+            });
+        }
+
+        let appended = ready.entries.len();
+        match ready.entries.first() {
+            // a new leader's entries replace ours from there on.
+            Some(first) if first.index <= self.log.last_index() => self.fail_pending(first.index),
+            _ => {}
+        }
+        let persisted = self.log.append_entries(ready.entries);
+        let persisted = match ready.hard_state {
+            Some(hard) => persisted.and_then(|()| self.log.set_hard_state(hard)),
+            None => persisted,
+        };
+        if let Err(err) = persisted {
+            // nothing may go out that the log doesn't back.
+            error!(cause = %err, "failed to persist the log, stopping");
+            return false;
+        }
+        if appended > 0 {
+            debug!(entries = appended, flushes = self.log.flushes(), "appended entries");
+        }
+
+        for (to, message) in ready.messages {
+            if let Some(peer) = self.peers.get(&to) {
+                self.transport.send(*peer, message);
+            }
+        }
+
+        if let Some(last) = ready.committed.last() {
+            self.committed.send_replace(last.index);
+        }
+        for entry in ready.committed {
+            // a proposal whose index was taken over by another leader's
+            // entry never made it into the log.
+            let waiter = match self.pending.remove(&entry.index) {
+                Some(waiter) if waiter.term == entry.term => Some((waiter.respond, waiter.reservation)),
+                Some(waiter) => {
+                    let lost = Frame::Error(String::from("proposal lost to a new leader, try again"));
+                    let _ = waiter.respond.send(lost);
+                    None
+                }
+                None => None,
+            };
+            if self.apply.send(Committed { entry, waiter }).is_err() {
+                return false;
+            }
+        }
+        if stepped_down {
+            // whatever is still waiting may yet be committed by the next
+            // leader, or replaced by it; this node can't tell which.
+            for (_, waiter) in self.pending.drain() {
+                let unknown = Frame::Error(String::from("leader stepped down, outcome unknown"));
+                let _ = waiter.respond.send(unknown);
+            }
+        }
+        true
+    }
+
+    /// Answers every proposal from index `from` on with "not the leader",
+    /// which hands its share of the limits back.
+    fn fail_pending(&mut self, from: u64) {
+        let lost: Vec<u64> = self.pending.keys().copied().filter(|&index| index >= from).collect();
+        for index in lost {
+            if let Some(waiter) = self.pending.remove(&index) {
+                let _ = waiter.respond.send(Frame::Error(String::from("not the leader")));
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+// node-to-node traffic, kept apart from clients.
+//
+// Every node listens for Raft RPCs on its own peer port, which has no share
+// of the client connection limit, so a flood of clients can't starve
+// heartbeats. Outbound, a `PeerPool` keeps one long-lived connection to
+// each peer, redialling with exponential backoff whenever it drops. Calls
+// to a peer that isn't connected fail straight away instead of queueing.
+//
+// Raft messages are one way: the receiving node hands them to its
+// transport's inbox and answers, if at all, with a message of its own over
+// its own connection. Only PING (and anything we don't understand) gets a
+// reply on the same connection.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, error, info};
+
+use crate::command::{Frame, Hello};
+use crate::connection::{Connection, Result};
+use crate::consensus::{Message, ServerId};
+
+/// How long to wait before redialling a peer. Doubles after every failed
+/// attempt, up to `max`, and starts over once a dial succeeds.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+/// A frame for a peer, and where to send its reply. One-way frames have
+/// nowhere to send one.
+#[derive(Debug)]
+struct Call {
+    frame: Frame,
+    respond: Option<oneshot::Sender<Result<Frame>>>,
+}
+
+impl Call {
+    fn fail(self, msg: &str) {
+        if let Some(respond) = self.respond {
+            let _ = respond.send(Err(msg.into()));
+        }
+    }
+}
+
+/// Where the peer listener delivers inbound Raft messages, tagged with the
+/// sender's node id. Messages are dropped when it is full.
+#[derive(Debug, Clone)]
+pub struct Inbox(mpsc::Sender<(u32, Message)>);
+
+impl Inbox {
+    pub fn new(capacity: usize) -> (Inbox, mpsc::Receiver<(u32, Message)>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (Inbox(sender), receiver)
+    }
+}
+
+#[derive(Debug)]
+struct PeerHandle {
+    calls: mpsc::Sender<Call>,
+    connected: watch::Receiver<bool>,
+    task: JoinHandle<()>,
+}
+
+/// Outbound connections to the rest of the cluster, keyed by `ServerId`.
+/// Each peer's address here is its peer port. Dropping the pool closes
+/// them all.
+#[derive(Debug)]
+pub struct PeerPool {
+    peers: HashMap<ServerId, PeerHandle>,
+}
+
+impl PeerPool {
+    /// Starts dialling every peer in the background. `keepalive` is how
+    /// often an idle connection is pinged, so a dead one is noticed.
+    pub fn new(
+        hello: Hello,
+        peers: impl IntoIterator<Item = ServerId>,
+        backoff: Backoff,
+        keepalive: Duration,
+    ) -> PeerPool {
+        let peers = peers
+            .into_iter()
+            .map(|peer| {
+                let (calls, receiver) = mpsc::channel(64);
+                let (connected_tx, connected) = watch::channel(false);
+                let task = tokio::spawn(maintain(
+                    peer,
+                    hello.clone(),
+                    receiver,
+                    connected_tx,
+                    backoff,
+                    keepalive,
+                ));
+                (peer, PeerHandle { calls, connected, task })
+            })
+            .collect();
+        PeerPool { peers }
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = &ServerId> {
+        self.peers.keys()
+    }
+
+    pub fn is_connected(&self, peer: &ServerId) -> bool {
+        self.peers.get(peer).is_some_and(|p| *p.connected.borrow())
+    }
+
+    /// Sends `frame` to `peer` and waits for its answer.
+    pub async fn call(&self, peer: &ServerId, frame: Frame) -> Result<Frame> {
+        let handle = self
+            .peers
+            .get(peer)
+            .ok_or_else(|| format!("unknown peer {}", peer.id))?;
+        let (respond, response) = oneshot::channel();
+        let call = Call {
+            frame,
+            respond: Some(respond),
+        };
+        handle.calls.send(call).await.map_err(|_| "peer pool is shut down")?;
+        response.await.map_err(|_| "peer connection dropped the call")?
+    }
+
+    /// Closes every connection once what is already queued on it has been
+    /// written. Peers that can't be reached get nothing.
+    pub async fn close(self) {
+        let tasks: Vec<JoinHandle<()>> = self.peers.into_values().map(|handle| handle.task).collect();
+        for task in tasks {
+            let _ = task.await;
+        }
+    }
+
+    /// Queues a one-way frame for `peer`. It is dropped if the peer is
+    /// unknown, unreachable or backed up; Raft resends what matters.
+    pub fn send(&self, peer: &ServerId, frame: Frame) {
+        if let Some(handle) = self.peers.get(peer) {
+            let call = Call { frame, respond: None };
+            if handle.calls.try_send(call).is_err() {
+                debug!(peer = peer.id, "dropping message for peer");
+            }
+        }
+    }
+}
+
+/// Keeps one connection to `peer` up until the pool goes away.
+async fn maintain(
+    peer: ServerId,
+    hello: Hello,
+    mut calls: mpsc::Receiver<Call>,
+    connected: watch::Sender<bool>,
+    backoff: Backoff,
+    keepalive: Duration,
+) {
+    let mut delay = backoff.initial;
+    loop {
+        let Some(dialled) = failing_calls(&mut calls, dial(peer.address, &hello, backoff.max)).await else {
+            return;
+        };
+        match dialled {
+            Ok(mut connection) => {
+                info!(peer = peer.id, "connected to peer");
+                delay = backoff.initial;
+                connected.send_replace(true);
+                let open = exchange(&mut connection, &mut calls, keepalive).await;
+                connected.send_replace(false);
+                if !open {
+                    return;
+                }
+                info!(peer = peer.id, "lost connection to peer");
+            }
+            Err(err) => debug!(peer = peer.id, cause = %err, ?delay, "failed to dial peer"),
+        }
+
+        if failing_calls(&mut calls, time::sleep(delay)).await.is_none() {
+            return;
+        }
+        delay = (delay * 2).min(backoff.max);
+    }
+}
+
+async fn dial(address: SocketAddr, hello: &Hello, timeout: Duration) -> Result<Connection> {
+    let socket = time::timeout(timeout, TcpStream::connect(address))
+        .await
+        .map_err(|_| "timed out dialling peer")??;
+    let mut connection = Connection::new(socket);
+    connection.handshake(hello).await?;
+    Ok(connection)
+}
+
+/// Drives `fut` to completion, failing any calls that arrive meanwhile.
+/// None if the pool went away first.
+async fn failing_calls<T>(calls: &mut mpsc::Receiver<Call>, fut: impl Future<Output = T>) -> Option<T> {
+    tokio::pin!(fut);
+    loop {
+        tokio::select! {
+            out = &mut fut => return Some(out),
+            call = calls.recv() => match call {
+                Some(call) => call.fail("not connected to peer"),
+                None => return None,
+            },
+        }
+    }
+}
+
+/// Runs calls over an open connection, one at a time, until it breaks.
+/// Returns false if the pool went away instead.
+async fn exchange(connection: &mut Connection, calls: &mut mpsc::Receiver<Call>, keepalive: Duration) -> bool {
+    let mut idle = time::interval(keepalive);
+    idle.reset();
+    loop {
+        let (frame, respond) = tokio::select! {
+            call = calls.recv() => match call {
+                Some(call) => (call.frame, call.respond),
+                None => return false,
+            },
+            _ = idle.tick() => (Frame::Ping, None),
+        };
+        let broken = match respond {
+            Some(respond) => {
+                let result = round_trip(connection, &frame).await;
+                let broken = result.is_err();
+                let _ = respond.send(result);
+                broken
+            }
+            None if frame == Frame::Ping => match round_trip(connection, &frame).await {
+                Ok(_) => false,
+                Err(err) => {
+                    debug!(cause = %err, "peer keepalive failed");
+                    true
+                }
+            },
+            None => connection.write(&frame.to_string()).await.is_err(),
+        };
+        if broken {
+            return true;
+        }
+        idle.reset();
+    }
+}
+
+async fn round_trip(connection: &mut Connection, frame: &Frame) -> Result<Frame> {
+    connection.write(&frame.to_string()).await?;
+    match connection.read_frame().await? {
+        Some(frame) => Ok(frame),
+        None => Err("peer closed the connection".into()),
+    }
+}
+
+/// Accepts connections on the peer port. Only `members`, the other nodes
+/// of this cluster, get past the handshake.
+pub async fn serve(listener: TcpListener, hello: Hello, members: HashSet<u32>, inbox: Inbox) -> Result<()> {
+    info!("Accepting peer connections on {}", listener.local_addr()?);
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let hello = hello.clone();
+        let members = members.clone();
+        let inbox = inbox.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(Connection::new(socket), &hello, &members, &inbox).await {
+                error!(cause = ?err, "peer connection error");
+            }
+        });
+    }
+}
+
+/// Whether `remote` may use the peer port: it has to say it belongs to
+/// our cluster, as a node we know.
+fn check_member(hello: &Hello, members: &HashSet<u32>, remote: &Hello) -> std::result::Result<(), String> {
+    if remote.cluster_id.is_none() || remote.cluster_id != hello.cluster_id {
+        return Err(String::from("the peer port is for cluster members"));
+    }
+    match remote.node_id {
+        Some(id) if members.contains(&id) => Ok(()),
+        Some(id) => Err(format!("unknown node id {}", id)),
+        None => Err(String::from("the peer port is for cluster members")),
+    }
+}
+
+async fn handle(mut connection: Connection, hello: &Hello, members: &HashSet<u32>, inbox: &Inbox) -> Result<()> {
+    let remote = connection
+        .accept_handshake_from(hello, |remote| check_member(hello, members, remote))
+        .await?;
+    let Some(from) = remote.node_id else {
+        return Err("peer handshake without a node id".into());
+    };
+    debug!(?remote, "peer handshake complete");
+
+    while let Some(frame) = connection.read_frame().await? {
+        let reply = match frame {
+            Frame::Ping => Frame::Success,
+            Frame::Raft(message) => {
+                if inbox.0.try_send((from, message)).is_err() {
+                    debug!(from, "inbox full, dropping message");
+                }
+                continue;
+            }
+            _ => Frame::Error(String::from("the peer port only serves raft traffic")),
+        };
+        connection.write(&reply.to_string()).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,710 @@
+// the consensus algorithm, with no I/O.
+//
+// `RaftNode` is a plain state machine in the style of etcd/raft: feed it
+// ticks, messages from peers and proposals, then take a `Ready` saying what
+// it wants done: state to persist, messages to send and committed entries
+// to apply. Whoever drives it owns the clock, the disk and the network, so
+// the same code runs in the server, under simulation and in unit tests.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::command::Frame;
+use crate::consensus::{Message, Progress, ProgressState, ServerState};
+use crate::log::LogEntry;
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub id: u32,
+    /// Every other member of the cluster.
+    pub peers: Vec<u32>,
+    /// A follower stands for election after a random number of ticks in
+    /// this range without hearing from a leader.
+    pub election_ticks_min: u64,
+    pub election_ticks_max: u64,
+    /// A leader sends heartbeats this often.
+    pub heartbeat_ticks: u64,
+    /// Most entries in one AppendEntries.
+    pub max_append_entries: usize,
+    pub max_inflight_appends: usize,
+    pub max_inflight_bytes: usize,
+    /// Seeds the election timeout jitter, so runs can be replayed.
+    pub seed: u64,
+}
+
+impl RaftConfig {
+    pub fn new(id: u32, peers: Vec<u32>) -> RaftConfig {
+        RaftConfig {
+            id,
+            peers,
+            election_ticks_min: 10,
+            election_ticks_max: 20,
+            heartbeat_ticks: 2,
+            max_append_entries: 64,
+            max_inflight_appends: 8,
+            max_inflight_bytes: 8 * 1024 * 1024,
+            seed: u64::from(id),
+        }
+    }
+}
+
+/// What has to be on disk before any message that depends on it goes out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<u32>,
+    pub commit: u64,
+}
+
+/// Who leads, as far as this node knows. Nothing to persist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftState {
+    pub term: u64,
+    pub role: ServerState,
+    pub leader: Option<u32>,
+}
+
+/// A read a quorum has confirmed this node led for: it can be served once
+/// the state machine has applied `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadState {
+    pub id: u64,
+    pub index: u64,
+}
+
+/// A read waiting for a quorum to confirm this node still leads.
+#[derive(Debug)]
+struct PendingRead {
+    id: u64,
+    seq: u64,
+    acks: HashSet<u32>,
+}
+
+/// Everything the node wants done since the last `ready`. Handle it in
+/// order: persist `hard_state` and `entries`, then send `messages`, then
+/// apply `committed`. `reads` can be answered at any point.
+#[derive(Debug, Default)]
+pub struct Ready {
+    pub soft_state: Option<SoftState>,
+    pub hard_state: Option<HardState>,
+    /// New log entries. They replace whatever the log holds from the first
+    /// one's index on.
+    pub entries: Vec<LogEntry>,
+    pub messages: Vec<(u32, Message)>,
+    pub committed: Vec<LogEntry>,
+    pub reads: Vec<ReadState>,
+}
+
+impl Ready {
+    pub fn is_empty(&self) -> bool {
+        self.soft_state.is_none()
+            && self.hard_state.is_none()
+            && self.entries.is_empty()
+            && self.messages.is_empty()
+            && self.committed.is_empty()
+            && self.reads.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub struct RaftNode {
+    config: RaftConfig,
+    term: u64,
+    voted_for: Option<u32>,
+    role: ServerState,
+    leader: Option<u32>,
+    log: Vec<LogEntry>,
+    commit: u64,
+    applied: u64,
+    /// First index not handed out in a `Ready` yet.
+    unstable: u64,
+    votes: HashSet<u32>,
+    progress: HashMap<u32, Progress>,
+    election_elapsed: u64,
+    election_timeout: u64,
+    heartbeat_elapsed: u64,
+    rng: u64,
+    messages: Vec<(u32, Message)>,
+    soft_state: SoftState,
+    hard_state: HardState,
+    /// Last `ReadIndex` sequence number sent.
+    read_seq: u64,
+    pending_reads: Vec<PendingRead>,
+    read_states: Vec<ReadState>,
+}
+
+impl RaftNode {
+    pub fn new(config: RaftConfig) -> RaftNode {
+        RaftNode::restore(config, HardState::default(), Vec::new())
+    }
+
+    /// Picks up where a node left off, from what it persisted. Committed
+    /// entries come out of the first `Ready` again, so the state machine
+    /// can be rebuilt.
+    pub fn restore(config: RaftConfig, hard_state: HardState, log: Vec<LogEntry>) -> RaftNode {
+        let last_index = log.last().map_or(0, |e| e.index);
+        let soft_state = SoftState {
+            term: hard_state.term,
+            role: ServerState::Follower,
+            leader: None,
+        };
+        let mut node = RaftNode {
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            role: ServerState::Follower,
+            leader: None,
+            commit: hard_state.commit.min(last_index),
+            applied: 0,
+            unstable: last_index + 1,
+            log,
+            votes: HashSet::new(),
+            progress: HashMap::new(),
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            // xorshift needs a non-zero state.
+            rng: config.seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            messages: Vec::new(),
+            soft_state,
+            hard_state,
+            read_seq: 0,
+            pending_reads: Vec::new(),
+            read_states: Vec::new(),
+            config,
+        };
+        node.reset_election_timer();
+        node
+    }
+
+    pub fn id(&self) -> u32 {
+        self.config.id
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn role(&self) -> ServerState {
+        self.role
+    }
+
+    pub fn leader(&self) -> Option<u32> {
+        self.leader
+    }
+
+    pub fn log(&self) -> &[LogEntry] {
+        &self.log
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.last().map_or(0, |e| e.index)
+    }
+
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            i => self.log.get(i as usize - 1).map_or(0, |e| e.term),
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.config.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    /// Advances the clock by one tick.
+    pub fn tick(&mut self) {
+        if self.role == ServerState::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.config.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.heartbeat();
+            }
+        } else {
+            self.election_elapsed += 1;
+            if self.election_elapsed >= self.election_timeout {
+                self.campaign();
+            }
+        }
+    }
+
+    /// Stands for election now instead of waiting for the timeout. A
+    /// cluster of one wins straight away.
+    pub fn campaign(&mut self) {
+        if self.role == ServerState::Leader {
+            return;
+        }
+        self.term += 1;
+        self.role = ServerState::Candidate;
+        self.leader = None;
+        self.voted_for = Some(self.config.id);
+        self.votes = HashSet::from([self.config.id]);
+        self.reset_election_timer();
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let request = Message::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.term_at(self.last_index()),
+        };
+        for &peer in &self.config.peers {
+            self.messages.push((peer, request.clone()));
+        }
+    }
+
+    /// Appends `commands` to the log if this node leads, and returns the
+    /// index of the first. Entries are consecutive.
+    pub fn propose(&mut self, commands: Vec<Frame>) -> Option<u64> {
+        if self.role != ServerState::Leader {
+            return None;
+        }
+        let first = self.last_index() + 1;
+        self.append(commands);
+        self.broadcast_append();
+        Some(first)
+    }
+
+    pub fn step(&mut self, from: u32, message: Message) {
+        let term = message.term();
+        if term > self.term {
+            let leader = matches!(message, Message::AppendEntries { .. }).then_some(from);
+            self.become_follower(term, leader);
+        } else if term < self.term {
+            // tell stale candidates and leaders about the newer term, so
+            // they step down. Stale answers need no reply.
+            match message {
+                Message::RequestVote { .. } => self.send(
+                    from,
+                    Message::Vote {
+                        term: self.term,
+                        granted: false,
+                    },
+                ),
+                Message::AppendEntries { .. } => self.send(
+                    from,
+                    Message::AppendResponse {
+                        term: self.term,
+                        success: false,
+                        match_index: 0,
+                    },
+                ),
+                Message::ReadIndex { seq, .. } => self.send(
+                    from,
+                    Message::ReadIndexResponse {
+                        term: self.term,
+                        seq,
+                    },
+                ),
+                _ => {}
+            }
+            return;
+        }
+
+        match message {
+            Message::RequestVote {
+                last_log_index,
+                last_log_term,
+                ..
+            } => self.handle_request_vote(from, last_log_index, last_log_term),
+            Message::Vote { granted, .. } => self.handle_vote(from, granted),
+            Message::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+                ..
+            } => self.handle_append(from, prev_log_index, prev_log_term, entries, leader_commit),
+            Message::AppendResponse {
+                success, match_index, ..
+            } => self.handle_append_response(from, success, match_index),
+            Message::TimeoutNow { .. } => {
+                if self.role == ServerState::Follower && self.leader == Some(from) {
+                    self.campaign();
+                }
+            }
+            // only one node leads a term, so answering in the same term is
+            // all it takes to back it.
+            Message::ReadIndex { seq, .. } => self.send(
+                from,
+                Message::ReadIndexResponse {
+                    term: self.term,
+                    seq,
+                },
+            ),
+            Message::ReadIndexResponse { seq, .. } => self.handle_read_index_response(from, seq),
+        }
+    }
+
+    /// Asks a quorum whether this node still leads, so read `id` can be
+    /// served without going through the log. Once it does, a `ReadState`
+    /// with the commit index comes out of a `Ready`. False if this node
+    /// doesn't lead; the read is dropped if it stops leading before then.
+    pub fn read_index(&mut self, id: u64) -> bool {
+        if self.role != ServerState::Leader {
+            return false;
+        }
+        self.read_seq += 1;
+        self.pending_reads.push(PendingRead {
+            id,
+            seq: self.read_seq,
+            acks: HashSet::from([self.config.id]),
+        });
+        self.broadcast_read_index();
+        self.release_reads();
+        true
+    }
+
+    /// Hands leadership to the follower holding the most of the log: sends
+    /// it whatever it is missing, then tells it to stand for election at
+    /// once. Returns the follower picked, or None if this node doesn't lead
+    /// or has nobody to hand over to.
+    pub fn transfer_leadership(&mut self) -> Option<u32> {
+        if self.role != ServerState::Leader {
+            return None;
+        }
+        let (&to, progress) = self
+            .progress
+            .iter()
+            .max_by_key(|&(&id, p)| (p.match_index, std::cmp::Reverse(id)))?;
+        let matched = progress.match_index;
+        if matched < self.last_index() {
+            let entries = self.log[matched as usize..].to_vec();
+            self.send_append(to, matched, entries);
+        }
+        self.send(to, Message::TimeoutNow { term: self.term });
+        Some(to)
+    }
+
+    pub fn has_ready(&self) -> bool {
+        self.current_soft_state() != self.soft_state
+            || self.current_hard_state() != self.hard_state
+            || self.unstable <= self.last_index()
+            || !self.messages.is_empty()
+            || self.applied < self.commit
+            || !self.read_states.is_empty()
+    }
+
+    /// Takes what the node wants done. See `Ready` for the order.
+    pub fn ready(&mut self) -> Ready {
+        let soft_state = self.current_soft_state();
+        let hard_state = self.current_hard_state();
+        let start = (self.unstable - 1) as usize;
+        let ready = Ready {
+            soft_state: (soft_state != self.soft_state).then_some(soft_state),
+            hard_state: (hard_state != self.hard_state).then_some(hard_state),
+            entries: self.log.get(start..).unwrap_or_default().to_vec(),
+            messages: std::mem::take(&mut self.messages),
+            committed: self.log[self.applied as usize..self.commit as usize].to_vec(),
+            reads: std::mem::take(&mut self.read_states),
+        };
+        self.soft_state = soft_state;
+        self.hard_state = hard_state;
+        self.unstable = self.last_index() + 1;
+        self.applied = self.commit;
+        ready
+    }
+
+    fn current_soft_state(&self) -> SoftState {
+        SoftState {
+            term: self.term,
+            role: self.role,
+            leader: self.leader,
+        }
+    }
+
+    fn current_hard_state(&self) -> HardState {
+        HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+            commit: self.commit,
+        }
+    }
+
+    fn send(&mut self, to: u32, message: Message) {
+        self.messages.push((to, message));
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn reset_election_timer(&mut self) {
+        let spread = self.config.election_ticks_max.saturating_sub(self.config.election_ticks_min) + 1;
+        self.election_elapsed = 0;
+        self.election_timeout = self.config.election_ticks_min + self.next_random() % spread;
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<u32>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = ServerState::Follower;
+        self.leader = leader;
+        self.votes.clear();
+        self.progress.clear();
+        self.pending_reads.clear();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = ServerState::Leader;
+        self.leader = Some(self.config.id);
+        self.heartbeat_elapsed = 0;
+        let last_index = self.last_index();
+        self.progress = self
+            .config
+            .peers
+            .iter()
+            .map(|&peer| {
+                let progress = Progress::new(
+                    last_index,
+                    self.config.max_inflight_appends,
+                    self.config.max_inflight_bytes,
+                );
+                (peer, progress)
+            })
+            .collect();
+        // entries from earlier terms only commit once one from this term
+        // does, so start the term with an empty one.
+        self.append(vec![Frame::Ping]);
+        self.broadcast_append();
+    }
+
+    fn append(&mut self, commands: Vec<Frame>) {
+        let first = self.last_index() + 1;
+        let term = self.term;
+        self.log.extend(commands.into_iter().enumerate().map(|(i, command)| LogEntry {
+            term,
+            index: first + i as u64,
+            command,
+        }));
+        self.maybe_commit();
+    }
+
+    /// Commits whatever a quorum holds, if it is from this term.
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<u64> = self.progress.values().map(|p| p.match_index).collect();
+        matched.push(self.last_index());
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index > self.commit && self.term_at(index) == self.term {
+            self.commit = index;
+            self.release_reads();
+        }
+    }
+
+    fn broadcast_read_index(&mut self) {
+        let message = Message::ReadIndex {
+            term: self.term,
+            seq: self.read_seq,
+        };
+        for &peer in &self.config.peers {
+            self.messages.push((peer, message.clone()));
+        }
+    }
+
+    fn handle_read_index_response(&mut self, from: u32, seq: u64) {
+        if self.role != ServerState::Leader {
+            return;
+        }
+        for read in self.pending_reads.iter_mut().filter(|r| r.seq <= seq) {
+            read.acks.insert(from);
+        }
+        self.release_reads();
+    }
+
+    /// Hands out the reads a quorum has confirmed, at the commit index.
+    /// That only covers every write earlier leaders acknowledged once an
+    /// entry from this term has committed, so until then they wait.
+    fn release_reads(&mut self) {
+        if self.term_at(self.commit) != self.term {
+            return;
+        }
+        let quorum = self.quorum();
+        let commit = self.commit;
+        let (confirmed, waiting): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.pending_reads).into_iter().partition(|r| r.acks.len() >= quorum);
+        self.pending_reads = waiting;
+        self.read_states.extend(confirmed.into_iter().map(|r| ReadState { id: r.id, index: commit }));
+    }
+
+    fn broadcast_append(&mut self) {
+        let peers = self.config.peers.clone();
+        for peer in peers {
+            self.send_entries(peer);
+        }
+    }
+
+    /// Sends `peer` what it is missing, as far as the in-flight window
+    /// allows.
+    fn send_entries(&mut self, peer: u32) {
+        let last_index = self.last_index();
+        loop {
+            let progress = &self.progress[&peer];
+            if !progress.can_send() || progress.next_index > last_index {
+                return;
+            }
+            let start = progress.next_index;
+            let end = (start + self.config.max_append_entries as u64 - 1).min(last_index);
+            let entries = self.log[(start - 1) as usize..end as usize].to_vec();
+            let bytes = entries.iter().map(|e| e.command.to_string().len()).sum();
+            self.send_append(peer, start - 1, entries);
+            self.progress.get_mut(&peer).unwrap().sent(end, bytes);
+        }
+    }
+
+    fn send_append(&mut self, peer: u32, prev_log_index: u64, entries: Vec<LogEntry>) {
+        let message = Message::AppendEntries {
+            term: self.term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries,
+            leader_commit: self.commit,
+        };
+        self.send(peer, message);
+    }
+
+    fn heartbeat(&mut self) {
+        // the question or its answers may be lost: ask again.
+        if !self.pending_reads.is_empty() {
+            self.broadcast_read_index();
+        }
+        let peers = self.config.peers.clone();
+        for peer in peers {
+            let progress = self.progress.get_mut(&peer).unwrap();
+            match progress.state {
+                // the probe or its answer may be lost: send it again.
+                ProgressState::Probe => progress.resume(),
+                // an append unanswered for a whole heartbeat: assume it
+                // and those after it were lost, and probe. Waiting for the
+                // window to fill would leave a quiet leader's last few
+                // entries unsent for good.
+                ProgressState::Replicate => {
+                    if progress.stalled() {
+                        progress.reject(None);
+                    }
+                }
+            }
+            let progress = &self.progress[&peer];
+            if progress.can_send() && progress.next_index <= self.last_index() {
+                self.send_entries(peer);
+            } else {
+                // nothing new: an empty append keeps our authority and
+                // carries the commit index.
+                let prev = match progress.state {
+                    ProgressState::Probe => progress.next_index - 1,
+                    ProgressState::Replicate => progress.match_index,
+                };
+                self.send_append(peer, prev, Vec::new());
+            }
+        }
+    }
+
+    fn handle_request_vote(&mut self, from: u32, last_log_index: u64, last_log_term: u64) {
+        let our_last_term = self.term_at(self.last_index());
+        let up_to_date = last_log_term > our_last_term
+            || (last_log_term == our_last_term && last_log_index >= self.last_index());
+        let free = self.voted_for.is_none_or(|v| v == from);
+        let granted = free && up_to_date && self.role == ServerState::Follower;
+        if granted {
+            self.voted_for = Some(from);
+            self.reset_election_timer();
+        }
+        self.send(
+            from,
+            Message::Vote {
+                term: self.term,
+                granted,
+            },
+        );
+    }
+
+    fn handle_vote(&mut self, from: u32, granted: bool) {
+        if self.role != ServerState::Candidate || !granted {
+            return;
+        }
+        self.votes.insert(from);
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+        }
+    }
+
+    fn handle_append(
+        &mut self,
+        from: u32,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    ) {
+        // only a live leader or a granted vote holds off an election.
+        // Resetting on every new term would let a candidate that can never
+        // win keep the others from ever trying.
+        self.become_follower(self.term, Some(from));
+        self.reset_election_timer();
+
+        if prev_log_index > self.last_index() || self.term_at(prev_log_index) != prev_log_term {
+            let hint = self.last_index().min(prev_log_index.saturating_sub(1));
+            self.send(
+                from,
+                Message::AppendResponse {
+                    term: self.term,
+                    success: false,
+                    match_index: hint,
+                },
+            );
+            return;
+        }
+
+        let last_new = prev_log_index + entries.len() as u64;
+        for entry in entries {
+            if entry.index <= self.last_index() {
+                if self.term_at(entry.index) == entry.term {
+                    continue;
+                }
+                // a conflict: everything from here on came from a leader
+                // that lost. None of it can be committed.
+                debug_assert!(entry.index > self.commit, "truncating committed entries");
+                self.log.truncate(entry.index as usize - 1);
+                self.unstable = self.unstable.min(entry.index);
+            }
+            self.log.push(entry);
+        }
+        if leader_commit > self.commit {
+            self.commit = leader_commit.min(last_new).max(self.commit);
+        }
+        self.send(
+            from,
+            Message::AppendResponse {
+                term: self.term,
+                success: true,
+                match_index: last_new,
+            },
+        );
+    }
+
+    fn handle_append_response(&mut self, from: u32, success: bool, match_index: u64) {
+        if self.role != ServerState::Leader {
+            return;
+        }
+        let Some(progress) = self.progress.get_mut(&from) else {
+            return;
+        };
+        if success {
+            if progress.ack(match_index) {
+                self.maybe_commit();
+            }
+        } else {
+            progress.reject(Some(match_index));
+        }
+        self.send_entries(from);
+    }
+}
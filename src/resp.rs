@@ -0,0 +1,324 @@
+// RESP2 front end, so redis-cli and existing redis client libraries can talk
+// to scow. Commands are mapped onto the same state machine operations as the
+// native READ and WRITE frames.
+
+use bytes::{Buf, BytesMut};
+use std::fmt;
+use std::io::Cursor;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+use tracing::{debug, error, info};
+
+use crate::admission::{Admission, Rejection};
+use crate::command::{get_line, get_u8, CmdError, Frame};
+use crate::connection::Result;
+use crate::consensus::ServerId;
+use crate::handler::Db;
+use crate::log::Proposer;
+use crate::server::{Shutdown, ShutdownSignal};
+
+/// The most arguments one command may have, as in redis.
+const MAX_ARGS: usize = 1024 * 1024;
+/// The longest one argument may be, as in redis.
+const MAX_BULK_BYTES: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reply::Simple(s) => write!(f, "+{}\r\n", s),
+            // an error is a single line; a break in the message would end it.
+            Reply::Error(e) => write!(f, "-{}\r\n", e.replace(['\r', '\n'], " ")),
+            Reply::Integer(i) => write!(f, ":{}\r\n", i),
+            Reply::Bulk(Some(s)) => write!(f, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Bulk(None) => write!(f, "$-1\r\n"),
+        }
+    }
+}
+
+pub(crate) async fn serve(
+    listener: TcpListener,
+    proposer: Proposer,
+    local: ServerId,
+    admission: Admission,
+    signal: ShutdownSignal,
+) -> Result<()> {
+    info!("Accepting RESP connections on {}", listener.local_addr()?);
+    loop {
+        let (socket, address) = listener.accept().await?;
+        let ticket = match admission.admit(address.ip()) {
+            Ok(ticket) => ticket,
+            Err(rejection) => {
+                debug!(%address, %rejection, "refusing RESP connection");
+                tokio::spawn(async move { RespConnection::new(socket).refuse(rejection).await });
+                continue;
+            }
+        };
+        let proposer = proposer.clone();
+        let admission = admission.clone();
+        let mut shutdown = signal.subscribe();
+
+        tokio::spawn(async move {
+            let mut connection = RespConnection::new(socket);
+            if let Err(err) = connection.run(&proposer, local, &admission, &mut shutdown).await {
+                error!(cause = ?err, "resp connection error");
+            }
+            drop(ticket);
+        });
+    }
+}
+
+struct RespConnection {
+    stream: BufWriter<TcpStream>,
+    buffer: BytesMut,
+}
+
+impl RespConnection {
+    fn new(socket: TcpStream) -> RespConnection {
+        RespConnection {
+            stream: BufWriter::new(socket),
+            buffer: BytesMut::with_capacity(4 * 1024),
+        }
+    }
+
+    /// Serves until the client hangs up, or the server shuts down between
+    /// requests.
+    async fn run(
+        &mut self,
+        proposer: &Proposer,
+        local: ServerId,
+        admission: &Admission,
+        shutdown: &mut Shutdown,
+    ) -> Result<()> {
+        loop {
+            let args = tokio::select! {
+                res = self.read_command() => res?,
+                _ = shutdown.recv() => break,
+                _ = admission.idle() => {
+                    debug!("closing idle RESP connection");
+                    admission.closed_idle();
+                    break;
+                }
+            };
+            let Some(args) = args else { break };
+            debug!(?args, "resp command");
+            let reply = handle(proposer, local, admission, args).await;
+            self.stream.write_all(reply.to_string().as_bytes()).await?;
+            self.stream.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Answers a connection that was not let in, as redis does past
+    /// maxclients. Its command is read first: closing with it unread would
+    /// reset the connection under the answer.
+    async fn refuse(mut self, rejection: Rejection) {
+        let _ = time::timeout(Duration::from_secs(1), self.read_command()).await;
+        let reply = Reply::Error(format!("ERR {}", rejection));
+        let _ = self.stream.write_all(reply.to_string().as_bytes()).await;
+        let _ = self.stream.flush().await;
+    }
+
+    async fn read_command(&mut self) -> Result<Option<Vec<String>>> {
+        loop {
+            let mut buf = Cursor::new(&self.buffer[..]);
+            match parse_command(&mut buf) {
+                Ok(args) => {
+                    let len = buf.position() as usize;
+                    self.buffer.advance(len);
+                    return Ok(Some(args));
+                }
+                Err(CmdError::Incomplete) => {}
+                Err(other) => {
+                    // like redis: say what was wrong, then hang up.
+                    let reply = Reply::Error(format!("ERR {}", other));
+                    let _ = self.stream.write_all(reply.to_string().as_bytes()).await;
+                    let _ = self.stream.flush().await;
+                    return Err(other.to_string().into());
+                }
+            }
+            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                } else {
+                    return Err("connection reset by peer".into());
+                }
+            }
+        }
+    }
+}
+
+/// Parses one command, either a RESP array of bulk strings (what client
+/// libraries send) or an inline command (what you type into telnet).
+fn parse_command(src: &mut Cursor<&[u8]>) -> std::result::Result<Vec<String>, CmdError> {
+    if src.get_ref().is_empty() {
+        return Err(CmdError::Incomplete);
+    }
+    if src.get_ref()[0] != b'*' {
+        let line = String::from_utf8(get_line(src)?.to_vec())?;
+        return Ok(line.split_whitespace().map(String::from).collect());
+    }
+
+    get_u8(src)?;
+    let count = get_number(src)?;
+    if count > MAX_ARGS {
+        return Err("protocol error, invalid multibulk length".into());
+    }
+    // the count is the client's word; the args are only kept as they arrive.
+    let mut args = Vec::new();
+    for _ in 0..count {
+        if get_u8(src)? != b'$' {
+            return Err("protocol error, expected bulk string".into());
+        }
+        let len = get_number(src)?;
+        if len > MAX_BULK_BYTES {
+            return Err("protocol error, invalid bulk length".into());
+        }
+        let start = src.position() as usize;
+        let end = start.checked_add(len).ok_or("protocol error, invalid bulk length")?;
+        let next = end.checked_add(2).ok_or("protocol error, invalid bulk length")?;
+        if src.get_ref().len() < next {
+            return Err(CmdError::Incomplete);
+        }
+        let arg = String::from_utf8(src.get_ref()[start..end].to_vec())?;
+        src.set_position(next as u64);
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+fn get_number(src: &mut Cursor<&[u8]>) -> std::result::Result<usize, CmdError> {
+    let line = String::from_utf8(get_line(src)?.to_vec())?;
+    line.parse::<usize>()
+        .map_err(|_| format!("protocol error, invalid length `{}`", line).into())
+}
+
+async fn handle(proposer: &Proposer, local: ServerId, admission: &Admission, args: Vec<String>) -> Reply {
+    let db = proposer.db();
+    let mut args = args.into_iter();
+    let cmd = match args.next() {
+        Some(cmd) => cmd.to_lowercase(),
+        None => return Reply::Error(String::from("ERR empty command")),
+    };
+    let args: Vec<String> = args.collect();
+
+    match cmd.as_str() {
+        "ping" => match args.len() {
+            0 => Reply::Simple(String::from("PONG")),
+            1 => Reply::Bulk(args.into_iter().next()),
+            _ => wrong_arity(&cmd),
+        },
+        "info" => Reply::Bulk(Some(info(db, local, admission))),
+        "get" | "set" | "del" | "exists" => {
+            if let Some(redirect) = redirect(db, local) {
+                return redirect;
+            }
+            data_command(proposer, &cmd, args).await
+        }
+        _ => Reply::Error(format!("ERR unknown command '{}'", cmd)),
+    }
+}
+
+async fn data_command(proposer: &Proposer, cmd: &str, args: Vec<String>) -> Reply {
+    match (cmd, args.len()) {
+        ("get", 1) => {
+            let key = args.into_iter().next().unwrap();
+            match proposer.execute(Frame::Read(key)).await {
+                Frame::Value(v) => Reply::Bulk(Some(v)),
+                Frame::Error(e) if e == "Key not found." => Reply::Bulk(None),
+                Frame::Busy(ms) => busy(ms),
+                other => error(other),
+            }
+        }
+        ("set", 2) => {
+            let mut args = args.into_iter();
+            let frame = Frame::Write(args.next().unwrap(), args.next().unwrap());
+            match proposer.execute(frame).await {
+                Frame::Success => Reply::Simple(String::from("OK")),
+                Frame::Busy(ms) => busy(ms),
+                other => error(other),
+            }
+        }
+        ("set", n) if n > 2 => Reply::Error(String::from("ERR syntax error")),
+        ("del", n) if n > 0 => count(proposer, args.into_iter().map(Frame::Delete)).await,
+        ("exists", n) if n > 0 => count(proposer, args.into_iter().map(Frame::Exists)).await,
+        _ => wrong_arity(cmd),
+    }
+}
+
+/// DEL and EXISTS take several keys and answer with how many matched.
+async fn count(proposer: &Proposer, frames: impl Iterator<Item = Frame>) -> Reply {
+    let mut matched = 0;
+    for frame in frames {
+        match proposer.execute(frame).await {
+            Frame::Bool(true) => matched += 1,
+            Frame::Bool(false) => {}
+            Frame::Busy(ms) => return busy(ms),
+            other => return error(other),
+        }
+    }
+    Reply::Integer(matched)
+}
+
+fn error(frame: Frame) -> Reply {
+    match frame {
+        Frame::Error(e) => Reply::Error(format!("ERR {}", e)),
+        other => Reply::Error(format!("ERR {}", other.to_string().trim_end())),
+    }
+}
+
+/// Redis uses BUSY for a server that can't take the command right now.
+fn busy(retry_after_ms: u64) -> Reply {
+    Reply::Error(format!("BUSY server overloaded, retry after {}ms", retry_after_ms))
+}
+
+/// Only the leader serves data commands. Everyone else points the client
+/// at the leader, like a redis cluster node answering for a slot it
+/// doesn't own.
+fn redirect(db: &Db, local: ServerId) -> Option<Reply> {
+    match db.leader() {
+        Some(leader) if leader == local => None,
+        Some(leader) => match db.member(leader.id).and_then(|m| m.resp) {
+            Some(resp) => Some(Reply::Error(format!("MOVED 0 {}", resp))),
+            None => Some(Reply::Error(format!("CLUSTERDOWN leader {} has no resp address", leader.id))),
+        },
+        None => Some(Reply::Error(String::from("CLUSTERDOWN no leader elected"))),
+    }
+}
+
+fn info(db: &Db, local: ServerId, admission: &Admission) -> String {
+    let leader = db.leader();
+    let role = if leader == Some(local) { "master" } else { "slave" };
+    let mut info = String::from("# Server\r\n");
+    info.push_str(&format!("scow_version:{}\r\n", env!("CARGO_PKG_VERSION")));
+    info.push_str(&format!("node_id:{}\r\n", local.id));
+    let connections = admission.stats();
+    info.push_str("\r\n# Clients\r\n");
+    info.push_str(&format!("connected_clients:{}\r\n", connections.open));
+    info.push_str("\r\n# Stats\r\n");
+    info.push_str(&format!("rejected_connections:{}\r\n", connections.rejected));
+    info.push_str(&format!("rejected_connections_per_ip:{}\r\n", connections.rejected_per_ip));
+    info.push_str(&format!("closed_idle_connections:{}\r\n", connections.closed_idle));
+    info.push_str("\r\n# Replication\r\n");
+    info.push_str(&format!("role:{}\r\n", role));
+    if let Some(leader) = leader {
+        info.push_str(&format!("leader_id:{}\r\n", leader.id));
+        if let Some(resp) = db.member(leader.id).and_then(|m| m.resp) {
+            info.push_str(&format!("leader_addr:{}\r\n", resp));
+        }
+    }
+    info
+}
+
+fn wrong_arity(cmd: &str) -> Reply {
+    Reply::Error(format!("ERR wrong number of arguments for '{}' command", cmd))
+}
@@ -1,106 +1,452 @@
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{self, Instant};
 
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
 use std::future::Future;
-use std::net::{SocketAddrV4, Ipv4Addr};
+use std::hash::BuildHasher;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
-use crate::command::Frame;
+use crate::admission::{Admission, Rejection};
+use crate::clock::{Clock, SystemClock};
+use crate::command::{Frame, Hello, WatchArgs, WatchCursor};
+use crate::config::Config;
 use crate::connection::{Connection, Result};
-use crate::consensus::{TermState, ServerId};
+use crate::consensus::{Member, ServerId};
+use crate::disk::Disk;
+use crate::fault::{FaultTransport, Faults};
 use crate::handler::{Db, DbDropGuard};
+use crate::lease::LeaseTracker;
+use crate::log::{Limits, Log, LogLoop, Proposer};
+use crate::peer::{Backoff, Inbox};
+use crate::raft::{RaftConfig, RaftNode};
+use crate::transport::{TcpTransport, Transport};
+use crate::{apply, http, lease, peer, resp};
 
 
+/// Where native clients connect. A `TcpListener`, unless a simulation
+/// hands out connections over its own network.
+pub trait Listener: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    fn accept(&self) -> impl Future<Output = io::Result<(Self::Stream, SocketAddr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+
+    fn accept(&self) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
+    }
+}
+
+/// Sockets a node serves. Only the native client listener is required.
+#[derive(Debug)]
+pub struct Listeners<L = TcpListener> {
+    pub client: L,
+    pub resp: Option<TcpListener>,
+    pub http: Option<TcpListener>,
+    /// Raft traffic from the other nodes.
+    pub peer: Option<TcpListener>,
+}
+
+impl<L> Listeners<L> {
+    pub fn new(client: L) -> Listeners<L> {
+        Listeners {
+            client,
+            resp: None,
+            http: None,
+            peer: None,
+        }
+    }
+
+    pub fn with_resp(mut self, resp: TcpListener) -> Listeners<L> {
+        self.resp = Some(resp);
+        self
+    }
+
+    pub fn with_http(mut self, http: TcpListener) -> Listeners<L> {
+        self.http = Some(http);
+        self
+    }
+
+    pub fn with_peer(mut self, peer: TcpListener) -> Listeners<L> {
+        self.peer = Some(peer);
+        self
+    }
+}
+
 pub async fn run(tcp_listener: TcpListener, shutdown: impl Future) {
+    run_with_config(Listeners::new(tcp_listener), Config::default(), shutdown).await
+}
+
+/// The consensus core's settings, in heartbeats: the log loop ticks it
+/// once per heartbeat interval.
+fn raft_config(config: &Config) -> RaftConfig {
+    let heartbeat = config.heartbeat_interval_ms.max(1);
+    let election_ticks_min = (config.election_timeout_min_ms / heartbeat).max(2);
+    // std seeds every RandomState randomly, which is all the jitter needs.
+    let seed = RandomState::new().hash_one(config.node_id);
+    RaftConfig {
+        election_ticks_min,
+        election_ticks_max: (config.election_timeout_max_ms / heartbeat).max(election_ticks_min),
+        heartbeat_ticks: 1,
+        max_append_entries: config.max_batch_size,
+        max_inflight_appends: config.max_inflight_appends,
+        max_inflight_bytes: config.max_follower_inflight_bytes,
+        seed,
+        ..RaftConfig::new(config.node_id, config.peers.iter().map(|p| p.id).collect())
+    }
+}
+
+pub async fn run_with_config(listeners: Listeners, config: Config, shutdown: impl Future) {
+    run_with_clock(listeners, config, SystemClock, shutdown).await
+}
+
+/// Like `run_with_config`, with every election, heartbeat and lease
+/// timeout measured by `clock`.
+pub async fn run_with_clock(listeners: Listeners, config: Config, clock: impl Clock, shutdown: impl Future) {
+    let peers = config
+        .peers
+        .iter()
+        .map(|p| ServerId {
+            id: p.id,
+            address: p.address,
+        })
+        .collect();
+    let transport = TcpTransport::new(
+        ServerId {
+            id: config.node_id,
+            address: config.peer_addr,
+        },
+        Hello::node(config.node_id, &config.cluster_id),
+        peers,
+        Backoff::default(),
+        Duration::from_millis(config.heartbeat_interval_ms),
+    );
+    let inbox = transport.inbox();
+    serve(listeners, config, Arc::new(clock), transport, Some(inbox), None, shutdown).await
+}
+
+/// Like `run_with_clock`, with consensus traffic going over `transport`
+/// instead of the peer port, which isn't served. With a `ChannelTransport`
+/// a whole cluster runs inside one process. The transport must know the
+/// nodes by their `peer_addr`s.
+pub async fn run_with_transport(
+    listeners: Listeners<impl Listener>,
+    config: Config,
+    clock: impl Clock,
+    transport: impl Transport,
+    shutdown: impl Future,
+) {
+    serve(listeners, config, Arc::new(clock), transport, None, None, shutdown).await
+}
 
+/// Like `run_with_transport`, with the log persisted on `disk` instead of
+/// in `data_dir`. A simulation hands each node a disk that outlives it, so
+/// a restarted node picks up where it left off.
+pub async fn run_with_disk(
+    listeners: Listeners<impl Listener>,
+    config: Config,
+    clock: impl Clock,
+    transport: impl Transport,
+    disk: impl Disk,
+    shutdown: impl Future,
+) {
+    serve(listeners, config, Arc::new(clock), transport, None, Some(Box::new(disk)), shutdown).await
+}
+
+/// Runs a node until `shutdown`, then shuts it down gracefully: open
+/// connections finish the request they are on, a leader hands over to its
+/// most caught up follower, and what the log has left is persisted and
+/// sent, all within `shutdown_timeout_ms`. `inbox`, where there is one, is
+/// fed by the peer listener.
+async fn serve(
+    listeners: Listeners<impl Listener>,
+    config: Config,
+    clock: Arc<dyn Clock>,
+    transport: impl Transport,
+    inbox: Option<Inbox>,
+    disk: Option<Box<dyn Disk>>,
+    shutdown: impl Future,
+) {
+    if config.fault_injection {
+        let faults = Faults::new(RandomState::new().hash_one(config.node_id));
+        let transport = FaultTransport::new(transport, faults.clone());
+        run_node(listeners, config, clock, transport, inbox, disk, Some(faults), shutdown).await
+    } else {
+        run_node(listeners, config, clock, transport, inbox, disk, None, shutdown).await
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_node(
+    listeners: Listeners<impl Listener>,
+    config: Config,
+    clock: Arc<dyn Clock>,
+    transport: impl Transport,
+    inbox: Option<Inbox>,
+    disk: Option<Box<dyn Disk>>,
+    faults: Option<Faults>,
+    shutdown: impl Future,
+) {
+    let local = match listeners.client.local_addr() {
+        Ok(address) => ServerId {
+            id: config.node_id,
+            address,
+        },
+        Err(err) => {
+            error!(cause = %err, "failed to read listener address");
+            return;
+        }
+    };
+
+    let (signal, mut connections_done) = ShutdownSignal::new();
+    let db_holder = DbDropGuard::new();
+    let (proposer, pipeline) = Proposer::new(
+        db_holder.db(),
+        config.max_batch_size,
+        Limits::new(config.max_inflight_proposals, config.max_uncommitted_bytes),
+    );
+    let hello = Hello::node(config.node_id, &config.cluster_id);
+    let peers: HashMap<u32, ServerId> = config
+        .peers
+        .iter()
+        .map(|p| {
+            let peer = ServerId {
+                id: p.id,
+                address: p.address,
+            };
+            (p.id, peer)
+        })
+        .collect();
     let mut server = Server {
-        tcp_listener,
-        db_holder: DbDropGuard::new(),
-        limit_connections: Arc::new(Semaphore::new(100)),
-        term_state: TermState::new(),
-        last_heartbeat: SystemTime::UNIX_EPOCH,
-        heartbeat_interval: 5000
+        listener: listeners.client,
+        hello: hello.clone(),
+        db_holder,
+        proposer,
+        leases: LeaseTracker::new(clock.clone()),
+        faults,
+        admission: Admission::new(&config),
+        shutdown: signal.clone(),
+    };
+
+    // clients are redirected to the leader's port for their protocol.
+    let resp_addr = listeners.resp.as_ref().and_then(|l| l.local_addr().ok());
+    let http_addr = listeners.http.as_ref().and_then(|l| l.local_addr().ok());
+    let db = server.db_holder.db();
+    db.add_member(Member { id: local.id, client: local.address, resp: resp_addr, http: http_addr });
+    let mut members = HashMap::from([(local.id, local)]);
+    for peer in &config.peers {
+        let address = peer.client_addr;
+        members.insert(peer.id, ServerId { id: peer.id, address });
+        db.add_member(Member { id: peer.id, client: address, resp: peer.resp_addr, http: peer.http_addr });
+    }
+
+    // a node with a disk or a data_dir picks up where it left off.
+    let opened = match (disk, &config.data_dir) {
+        (Some(disk), _) => Some(Log::with_disk(disk)),
+        (None, Some(dir)) => Some(Log::open(dir)),
+        (None, None) => None,
+    };
+    let (log, mut node) = match opened {
+        Some(Ok(log)) => {
+            let node = RaftNode::restore(raft_config(&config), log.hard_state(), log.entries().to_vec());
+            (log, node)
+        }
+        Some(Err(err)) => {
+            error!(cause = %err, "failed to open the log");
+            return;
+        }
+        None => (Log::new(), RaftNode::new(raft_config(&config))),
+    };
+    if config.peers.is_empty() {
+        // a cluster of one has nobody to wait for.
+        node.campaign();
+    }
+    let admission = server.admission.clone();
+    let proposer = server.proposer.clone();
+    let (apply_tx, apply_rx) = mpsc::unbounded_channel();
+    let mut log = LogLoop {
+        node,
+        transport,
+        peers,
+        members,
+        proposals: pipeline.proposals,
+        reads: pipeline.reads,
+        committed: pipeline.committed,
+        apply: apply_tx,
+        db: db.clone(),
+        log,
+        max_batch: config.max_batch_size,
+        clock,
+        tick: Duration::from_millis(config.heartbeat_interval_ms),
+        pending: HashMap::new(),
+        pending_reads: HashMap::new(),
+        next_read: 0,
+    };
+    // settle the single node election before anyone asks who leads.
+    log.handle_ready();
+    let (stop_log, log_stopped) = oneshot::channel();
+    // boxed, so it can be dropped once it has had its chance to stop.
+    let mut log = Box::pin(log.run(log_stopped));
+    // the apply task ends by itself once the log loop is dropped.
+    let applier = tokio::spawn(apply::run(apply_rx, db, pipeline.applied));
+    let expiry = lease::expire_leases(proposer.clone(), server.leases.clone(), local);
+    let resp = async {
+        match listeners.resp {
+            Some(listener) => {
+                resp::serve(listener, proposer.clone(), local, admission.clone(), signal.clone()).await
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let peer = async {
+        match (listeners.peer, inbox) {
+            (Some(listener), Some(inbox)) => {
+                let members = config.peers.iter().map(|p| p.id).collect();
+                peer::serve(listener, hello, members, inbox).await
+            }
+            _ => std::future::pending().await,
+        }
+    };
+    let http = async {
+        match listeners.http {
+            Some(listener) => {
+                http::serve(listener, proposer.clone(), local, admission.clone(), signal.clone()).await
+            }
+            None => std::future::pending().await,
+        }
     };
 
-    tokio::select! {
+    let log_ended = tokio::select! {
      res = server.run() => {
          debug!("got to server.run?");
          if let Err(err) = res {
              error!(cause = %err, "failed to accept");
          }
+         false
+     },
+     res = resp => {
+         if let Err(err) = res {
+             error!(cause = %err, "failed to accept resp connection");
+         }
+         false
      },
+     res = http => {
+         if let Err(err) = res {
+             error!(cause = %err, "failed to accept http connection");
+         }
+         false
+     },
+     res = peer => {
+         if let Err(err) = res {
+             error!(cause = %err, "failed to accept peer connection");
+         }
+         false
+     },
+     _ = expiry => false,
+     _ = &mut log => true,
      _ = shutdown => {
          info!("shutdown");
+         false
      },
-    }
+    };
 
+    // nothing new is accepted from here on. Open connections finish the
+    // request they are on and hang up; the log keeps running meanwhile,
+    // so their writes can still commit.
+    let deadline = Instant::now() + Duration::from_millis(config.shutdown_timeout_ms);
+    signal.notify();
+    drop(signal);
+    drop(server);
+    if !log_ended {
+        tokio::select! {
+            _ = &mut log => {}
+            finished = time::timeout_at(deadline, connections_done.recv()) => {
+                if finished.is_err() {
+                    warn!("gave up waiting for connections to finish");
+                }
+                let _ = stop_log.send(());
+                if time::timeout_at(deadline, &mut log).await.is_err() {
+                    warn!("gave up waiting for the log to stop");
+                }
+            }
+        }
+    }
+    // with the log loop gone, the apply task finishes what it was handed.
+    drop(log);
+    if time::timeout_at(deadline, applier).await.is_err() {
+        warn!("gave up waiting for committed entries to be applied");
+    }
 }
 
 #[derive(Debug)]
-struct Server {
-    tcp_listener: TcpListener,
+struct Server<L> {
+    listener: L,
+    hello: Hello,
     db_holder: DbDropGuard,
-    limit_connections: Arc<Semaphore>,
-    term_state: TermState,
-    heartbeat_interval: u64,
-    last_heartbeat: SystemTime,
+    proposer: Proposer,
+    leases: LeaseTracker,
+    faults: Option<Faults>,
+    admission: Admission,
+    shutdown: ShutdownSignal,
 }
 
-impl Server {
+impl<L: Listener> Server<L> {
     async fn run(&mut self) -> Result<()> {
         info!("Accepting inbound connections");
 
-        let servers = vec![
-            ServerId {
-                id: 0,
-                address: std::net::SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 9990)),
-            },
-            ServerId {
-                id: 1,
-                address: std::net::SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127,0,0,1), 9991)),
-            },
-        ];
-
         loop {
-            let permit = self
-                .limit_connections
-                .clone()
-                .acquire_owned()
-                .await
-                .unwrap();
-
-            let socket = self.accept().await?;
+            let (socket, address) = self.accept().await?;
+            let ticket = match self.admission.admit(address.ip()) {
+                Ok(ticket) => ticket,
+                Err(rejection) => {
+                    debug!(%address, %rejection, "refusing connection");
+                    tokio::spawn(refuse(socket, rejection));
+                    continue;
+                }
+            };
 
             let mut handler = Handler {
                 db: self.db_holder.db(),
+                proposer: self.proposer.clone(),
+                leases: self.leases.clone(),
+                faults: self.faults.clone(),
+                admission: self.admission.clone(),
                 connection: Connection::new(socket),
-                shutdown: Shutdown::new(),
+                hello: self.hello.clone(),
+                shutdown: self.shutdown.subscribe(),
             };
 
             tokio::spawn(async move {
                 if let Err(err) = handler.run().await {
                     error!(cause = ?err, "connection error");
-                    drop(permit);
                 }
-                
-            });
-
-            tokio::spawn(async move {
-                
+                drop(ticket);
             });
         }
     }
 
-    async fn accept(&mut self) -> Result<TcpStream> {
+    async fn accept(&mut self) -> Result<(L::Stream, SocketAddr)> {
         debug!("accept in listener");
         let mut backoff = 1;
         loop {
-            match self.tcp_listener.accept().await {
-                Ok((socket, _)) => return Ok(socket),
+            match self.listener.accept().await {
+                Ok(accepted) => return Ok(accepted),
                 Err(e) => {
                     if backoff > 64 {
                         return Err(e.into());
@@ -112,74 +458,88 @@ impl Server {
             backoff *= 2;
         }
     }
+}
 
-    // async fn heartbeat_loop(&mut self) -> () {
-    //     let heartbeat = tokio::spawn(async move  {
-    //         if let Err(err) = self.heartbeat().await {
-    //             error!(cause = ?err, "heartbeat error");
-    //         }
-    //     });
-    // }
+/// Tells a client it was not let in. Its HELLO is read first: closing with
+/// it unread would reset the connection under the answer.
+async fn refuse(socket: impl AsyncRead + AsyncWrite + Unpin, rejection: Rejection) {
+    let mut connection = Connection::new(socket);
+    let _ = time::timeout(Duration::from_secs(1), connection.read_frame()).await;
+    let _ = connection.write(&Frame::Error(rejection.to_string()).to_string()).await;
+}
 
-    pub async fn heartbeat(&mut self) -> Result<String> {
-        println!("heartbeat loop start");
-        let mut interval = tokio::time::interval(Duration::from_millis(self.heartbeat_interval));
+/// Listens for the server shutting down. Every connection task holds one,
+/// and dropping it tells the server the connection is done.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    is_shutdown: bool,
+    notify: broadcast::Receiver<()>,
+    _done: mpsc::Sender<()>,
+}
 
-        loop {
-            interval.tick().await;
-            self.heartbeat_action().await;
-        }
+impl Shutdown {
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.is_shutdown
     }
 
-    async fn heartbeat_action(&mut self) {
-        // do the things here
-        println!("thump-thump");
-        let now = SystemTime::now();
-
-        // the algorithm:
-
-        // IF this server is not the leader
-        //   AND this server has not gotten a ping from the leader (within random timeout)
-
-        if self.term_state.server_state == crate::consensus::ServerState::Follower {
-            if self.last_heartbeat.elapsed().unwrap() > Duration::from_millis(250) {
-
-            // THEN 
-            //   - change state to candidate  
-            //   - increase term counter
-            //   - vote for yourself
-            //   - request votes from known servers
-
-                println!("request vote");
-            }
+    /// Waits for the signal, or returns straight away if it already came.
+    pub(crate) async fn recv(&mut self) {
+        if self.is_shutdown {
+            return;
         }
+        // a closed channel means the server is gone: shut down all the same.
+        let _ = self.notify.recv().await;
+        self.is_shutdown = true;
     }
-
 }
 
-pub(crate) struct Shutdown {
-    shutdown: bool,
+/// Hands every connection task a `Shutdown`, and later tells them all it
+/// is time.
+#[derive(Debug, Clone)]
+pub(crate) struct ShutdownSignal {
+    notify: broadcast::Sender<()>,
+    done: mpsc::Sender<()>,
 }
 
-impl Shutdown {
-    pub(crate) fn new() -> Shutdown {
-        Shutdown { shutdown: false }
+impl ShutdownSignal {
+    /// The receiver ends once every `Shutdown` handed out, and every
+    /// signal, has been dropped.
+    fn new() -> (ShutdownSignal, mpsc::Receiver<()>) {
+        let (notify, _) = broadcast::channel(1);
+        let (done, all_done) = mpsc::channel(1);
+        (ShutdownSignal { notify, done }, all_done)
     }
-    pub(crate) fn is_shutdown(&self) -> bool {
-        self.shutdown
+
+    pub(crate) fn subscribe(&self) -> Shutdown {
+        Shutdown {
+            is_shutdown: false,
+            notify: self.notify.subscribe(),
+            _done: self.done.clone(),
+        }
+    }
+
+    fn notify(&self) {
+        let _ = self.notify.send(());
     }
 }
 
-struct Handler {
+struct Handler<S> {
     db: Db,
-    connection: Connection,
+    proposer: Proposer,
+    leases: LeaseTracker,
+    faults: Option<Faults>,
+    admission: Admission,
+    connection: Connection<S>,
+    hello: Hello,
     shutdown: Shutdown,
 }
 
-impl Handler {
+impl<S: AsyncRead + AsyncWrite + Unpin> Handler<S> {
     async fn run(&mut self) -> crate::connection::Result<()> {
         debug!("in Handler#run, should have something on the wire");
 
+        let remote = self.connection.accept_handshake(&self.hello).await?;
+        debug!(?remote, "handshake complete");
 
         while !self.shutdown.is_shutdown() {
             // TODO should make this kind of stuff part of the frame or connection types
@@ -190,7 +550,13 @@ impl Handler {
             // all commands and responses being in the same frame type is a little weird?
             // should frame be union of a single command OR a single response?
             let maybe_frame = tokio::select! {
-                res = self.connection.read_frame() => res?
+                res = self.connection.read_frame() => res?,
+                _ = self.shutdown.recv() => return Ok(()),
+                _ = self.admission.idle() => {
+                    debug!("closing idle connection");
+                    self.admission.closed_idle();
+                    return Ok(());
+                }
             };
 
             let frame = match maybe_frame {
@@ -202,34 +568,113 @@ impl Handler {
             };
             debug!(?frame);
             let result = match frame {
-                Frame::Read(k) => {
-                    let read = self.db.get(&k);
-                    match read {
-                        Some(v) => Frame::Value(v),
-                        None => Frame::Error(String::from("Key not found.")),
+                Frame::Read(_)
+                | Frame::Write(_, _)
+                | Frame::Delete(_)
+                | Frame::Exists(_)
+                | Frame::CompareAndSwap(_)
+                | Frame::Scan(_)
+                | Frame::Prefix(_)
+                | Frame::Get(_)
+                | Frame::Compact(_)
+                | Frame::LeaseRevoke(_)
+                | Frame::LeasePut(_, _, _)
+                | Frame::Txn(_)
+                | Frame::WriteBatch(_) => self.proposer.execute(frame).await,
+                Frame::Success | Frame::Ping => Frame::Success,
+                Frame::Hello(_) => Frame::Error(String::from("unexpected HELLO")),
+                Frame::Raft(_) => Frame::Error(String::from("raft traffic belongs on the peer port")),
+                Frame::Fault(command) => match &self.faults {
+                    Some(faults) => {
+                        info!(?command, "injecting faults");
+                        faults.apply(&command);
+                        Frame::Success
                     }
-                }
-                Frame::Write(k, v) => {
-                    let _write = self.db.set(k, v);
-                    Frame::Success
-                }
-                Frame::Success => Frame::Success,
-                Frame::RequestVote(_) => {
-                    todo!()
+                    None => Frame::Error(String::from("fault injection is disabled")),
                 },
-                Frame::Vote(server) => {
-                    todo!()
-                },
-                Frame::Value(_) => {
-                    // servers dont' need to care about this type of frame, but we should handle it eventually
-                    todo!()
+                Frame::LeaseGrant(_) | Frame::LeaseKeepAlive(_) => {
+                    let result = self.proposer.execute(frame).await;
+                    if let Frame::Lease(lease) = result {
+                        self.leases.keep_alive(lease);
+                    }
+                    result
                 }
-                Frame::Error(_) => todo!(),
+                Frame::Watch(args) => {
+                    // the connection belongs to the watch from here on.
+                    return self.watch(args).await;
+                }
+                Frame::Bool(_)
+                | Frame::CasResult(_)
+                | Frame::Page(_)
+                | Frame::Entry(_)
+                | Frame::Event(_)
+                | Frame::Lease(_)
+                | Frame::TxnResult(_)
+                | Frame::Value(_)
+                | Frame::Error(_)
+                | Frame::Busy(_) => Frame::Error(String::from("unexpected response frame")),
             };
             let response = result.to_string();
-            println!("writing {} to the wire.", response);
             self.connection.write(&response).await?;
         }
         Ok(())
     }
+
+    /// Streams events matching `args` until the client hangs up. Answers
+    /// OK once the watch is in place, then one EVENT frame per change.
+    async fn watch(&mut self, mut args: WatchArgs) -> crate::connection::Result<()> {
+        let (start, mut backlog, mut events) = match self.db.watch(&args) {
+            Ok(watch) => watch,
+            Err(e) => {
+                self.connection.write(&Frame::Error(e).to_string()).await?;
+                return Ok(());
+            }
+        };
+        // answered with the revision the watch starts at, so the client can
+        // resume from there even if nothing has happened yet.
+        args.start_revision = start;
+        self.connection.write(&Frame::Watch(args.clone()).to_string()).await?;
+
+        let mut cursor = WatchCursor::starting_at(start);
+        loop {
+            for event in backlog.drain(..) {
+                if cursor.advance(&event) {
+                    self.connection.write(&Frame::Event(event).to_string()).await?;
+                }
+            }
+
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) if args.matches(&event.kv.key) => backlog.push(event),
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        // fell behind the live channel: catch up from history,
+                        // the cursor skips whatever was already sent.
+                        debug!(missed, "watcher lagged, replaying from history");
+                        args.start_revision = cursor.revision;
+                        match self.db.watch(&args) {
+                            Ok((_, replay, receiver)) => {
+                                cursor.resume();
+                                backlog = replay;
+                                events = receiver;
+                            }
+                            Err(e) => {
+                                self.connection.write(&Frame::Error(e).to_string()).await?;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = self.shutdown.recv() => return Ok(()),
+                frame = self.connection.read_frame() => {
+                    // clients end a watch by hanging up; anything else is a mistake.
+                    if let Some(frame) = frame? {
+                        debug!(?frame, "unexpected frame during watch");
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
 }
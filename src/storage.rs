@@ -0,0 +1,122 @@
+// the log and hard state on a `Disk`.
+//
+// The log is one append-only file with a line per entry: its term, its
+// index and its command as it goes over the wire. Replacing entries from
+// some index on truncates the file there first. The hard state is a small
+// file of its own, replaced whole, so a crash leaves the old one or the
+// new. Both are durable before `append` and `set_hard_state` return, which
+// is before anything that depends on them is sent or acknowledged.
+//
+// A crash can leave the last entry half written. Nobody was told about it,
+// so opening the log drops it.
+
+use std::io::{self, Write};
+
+use crate::command::Frame;
+use crate::disk::Disk;
+use crate::log::LogEntry;
+use crate::raft::HardState;
+
+const LOG: &str = "log";
+const HARD_STATE: &str = "hard_state";
+
+#[derive(Debug)]
+pub(crate) struct Storage {
+    disk: Box<dyn Disk>,
+    /// Where each entry starts in the log file, by index - 1.
+    offsets: Vec<u64>,
+    len: u64,
+}
+
+impl Storage {
+    /// Opens what is on `disk`, and returns it along with the hard state
+    /// and entries persisted there.
+    pub(crate) fn open(mut disk: Box<dyn Disk>) -> io::Result<(Storage, HardState, Vec<LogEntry>)> {
+        let hard_state = match disk.read(HARD_STATE)? {
+            Some(contents) => parse_hard_state(&contents).ok_or_else(|| invalid(HARD_STATE, 1))?,
+            None => HardState::default(),
+        };
+
+        let contents = disk.read(LOG)?.unwrap_or_default();
+        let mut entries = Vec::new();
+        let mut offsets = Vec::new();
+        let mut len = 0;
+        let mut lines = contents.split_inclusive(|&b| b == b'\n').peekable();
+        while let Some(line) = lines.next() {
+            match parse_entry(line) {
+                Some(entry) if line.ends_with(b"\n") && entry.index == entries.len() as u64 + 1 => {
+                    offsets.push(len);
+                    len += line.len() as u64;
+                    entries.push(entry);
+                }
+                // only the last line can be torn; anything before it was
+                // acknowledged.
+                _ if lines.peek().is_none() => break,
+                _ => return Err(invalid(LOG, entries.len() + 1)),
+            }
+        }
+        if len < contents.len() as u64 {
+            disk.truncate(LOG, len)?;
+        }
+
+        let storage = Storage { disk, offsets, len };
+        Ok((storage, hard_state, entries))
+    }
+
+    /// Writes `entries` and syncs them. They replace anything on disk from
+    /// the first one's index on.
+    pub(crate) fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let Some(first) = entries.first() else {
+            return Ok(());
+        };
+        let keep = (first.index - 1) as usize;
+        if keep < self.offsets.len() {
+            self.len = self.offsets[keep];
+            self.offsets.truncate(keep);
+            self.disk.truncate(LOG, self.len)?;
+        }
+        let mut buf = Vec::new();
+        for entry in entries {
+            self.offsets.push(self.len + buf.len() as u64);
+            write!(buf, "{} {} {}", entry.term, entry.index, entry.command)?;
+        }
+        self.disk.append(LOG, &buf)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    pub(crate) fn set_hard_state(&mut self, hard_state: HardState) -> io::Result<()> {
+        let voted_for = hard_state.voted_for.map_or(String::from("-"), |id| id.to_string());
+        let line = format!("{} {} {}\n", hard_state.term, voted_for, hard_state.commit);
+        self.disk.replace(HARD_STATE, line.as_bytes())
+    }
+}
+
+fn parse_hard_state(contents: &[u8]) -> Option<HardState> {
+    let mut fields = std::str::from_utf8(contents).ok()?.split_whitespace();
+    let term = fields.next()?.parse().ok()?;
+    let voted_for = match fields.next()? {
+        "-" => None,
+        id => Some(id.parse().ok()?),
+    };
+    let commit = fields.next()?.parse().ok()?;
+    Some(HardState { term, voted_for, commit })
+}
+
+fn parse_entry(line: &[u8]) -> Option<LogEntry> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (term, rest) = line.split_once(' ')?;
+    let (index, command) = rest.split_once(' ')?;
+    Some(LogEntry {
+        term: term.parse().ok()?,
+        index: index.parse().ok()?,
+        command: Frame::decode(command.as_bytes()).ok()?,
+    })
+}
+
+fn invalid(name: &str, line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} is corrupt at line {}", name, line),
+    )
+}
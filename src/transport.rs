@@ -0,0 +1,173 @@
+// how consensus reaches other nodes.
+//
+// Raft only needs to fire a message at a node and hear what others fire at
+// it. Delivery is best effort, messages can be dropped or reordered, and
+// the protocol copes by retrying. `TcpTransport` does this over the peer
+// port; `ChannelTransport` does it with in-process channels, so a whole
+// cluster can run inside one test.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::command::{Frame, Hello};
+use crate::consensus::{Message, ServerId};
+use crate::peer::{Backoff, Inbox, PeerPool};
+
+pub trait Transport: Send + 'static {
+    /// The node this transport speaks for.
+    fn local(&self) -> ServerId;
+
+    /// Queues `message` for `to`. Never blocks and never fails: messages to
+    /// unknown or unreachable nodes are dropped.
+    fn send(&self, to: ServerId, message: Message);
+
+    /// The next message for this node and who sent it. None once the
+    /// transport has shut down.
+    fn recv(&mut self) -> impl Future<Output = Option<(ServerId, Message)>> + Send;
+
+    /// Shuts down once what was sent so far is on its way, as far as it can
+    /// be.
+    fn close(self) -> impl Future<Output = ()> + Send
+    where
+        Self: Sized,
+    {
+        async {}
+    }
+}
+
+/// Inbound messages queued per node before new ones get dropped.
+const INBOX_CAPACITY: usize = 1024;
+
+/// Messages over the peer port. Outbound goes through a `PeerPool`;
+/// inbound arrives through the `Inbox` given to `peer::serve`.
+#[derive(Debug)]
+pub struct TcpTransport {
+    local: ServerId,
+    pool: PeerPool,
+    peers: HashMap<u32, ServerId>,
+    inbox: Inbox,
+    inbound: mpsc::Receiver<(u32, Message)>,
+}
+
+impl TcpTransport {
+    /// `local` and `peers` carry peer port addresses.
+    pub fn new(
+        local: ServerId,
+        hello: Hello,
+        peers: Vec<ServerId>,
+        backoff: Backoff,
+        keepalive: Duration,
+    ) -> TcpTransport {
+        let (inbox, inbound) = Inbox::new(INBOX_CAPACITY);
+        TcpTransport {
+            local,
+            pool: PeerPool::new(hello, peers.iter().copied(), backoff, keepalive),
+            peers: peers.into_iter().map(|p| (p.id, p)).collect(),
+            inbox,
+            inbound,
+        }
+    }
+
+    /// Hand this to `peer::serve` for the listener feeding this transport.
+    pub fn inbox(&self) -> Inbox {
+        self.inbox.clone()
+    }
+
+    pub fn pool(&self) -> &PeerPool {
+        &self.pool
+    }
+}
+
+impl Transport for TcpTransport {
+    fn local(&self) -> ServerId {
+        self.local
+    }
+
+    fn send(&self, to: ServerId, message: Message) {
+        self.pool.send(&to, Frame::Raft(message));
+    }
+
+    async fn close(self) {
+        self.pool.close().await
+    }
+
+    async fn recv(&mut self) -> Option<(ServerId, Message)> {
+        loop {
+            let (from, message) = self.inbound.recv().await?;
+            match self.peers.get(&from) {
+                Some(peer) => return Some((*peer, message)),
+                None => debug!(from, "dropping message from a node that isn't a peer"),
+            }
+        }
+    }
+}
+
+type Mailboxes = HashMap<ServerId, mpsc::UnboundedSender<(ServerId, Message)>>;
+
+/// An in-process network. Every transport joined to it can reach every
+/// other one that is still alive.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelNetwork {
+    nodes: Arc<Mutex<Mailboxes>>,
+}
+
+impl ChannelNetwork {
+    pub fn new() -> ChannelNetwork {
+        ChannelNetwork::default()
+    }
+
+    /// Adds a node. Joining again with the same id replaces the old one,
+    /// like a restarted process.
+    pub fn join(&self, local: ServerId) -> ChannelTransport {
+        let (mailbox, inbound) = mpsc::unbounded_channel();
+        self.nodes.lock().unwrap().insert(local, mailbox.clone());
+        ChannelTransport {
+            local,
+            network: self.clone(),
+            mailbox,
+            inbound,
+        }
+    }
+}
+
+/// One node's end of a `ChannelNetwork`. Dropping it takes the node off
+/// the network.
+#[derive(Debug)]
+pub struct ChannelTransport {
+    local: ServerId,
+    network: ChannelNetwork,
+    mailbox: mpsc::UnboundedSender<(ServerId, Message)>,
+    inbound: mpsc::UnboundedReceiver<(ServerId, Message)>,
+}
+
+impl Transport for ChannelTransport {
+    fn local(&self) -> ServerId {
+        self.local
+    }
+
+    fn send(&self, to: ServerId, message: Message) {
+        let nodes = self.network.nodes.lock().unwrap();
+        if let Some(mailbox) = nodes.get(&to) {
+            let _ = mailbox.send((self.local, message));
+        }
+    }
+
+    async fn recv(&mut self) -> Option<(ServerId, Message)> {
+        self.inbound.recv().await
+    }
+}
+
+impl Drop for ChannelTransport {
+    fn drop(&mut self) {
+        let mut nodes = self.network.nodes.lock().unwrap();
+        // a rejoined node owns the slot now; leave it alone.
+        if nodes.get(&self.local).is_some_and(|m| m.same_channel(&self.mailbox)) {
+            nodes.remove(&self.local);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+
+use scow::client::Client;
+use scow::command::{Frame, Hello};
+use scow::config::Config;
+use scow::connection::Connection;
+use scow::server::{self, Listeners};
+
+struct Addrs {
+    native: SocketAddr,
+    resp: SocketAddr,
+    http: SocketAddr,
+}
+
+#[tokio::test]
+async fn connections_give_their_place_back_when_they_close() {
+    let addrs = start_server(Config {
+        max_connections: 2,
+        ..Config::default()
+    })
+    .await;
+
+    // far more clients, one after another, than there are places.
+    for i in 0..10 {
+        let mut client = connect(addrs.native).await;
+        assert_eq!(client.write(&format!("key{}", i), "x").await.unwrap(), Frame::Success);
+    }
+}
+
+#[tokio::test]
+async fn connections_past_the_limit_are_refused_and_counted() {
+    let addrs = start_server(Config {
+        max_connections: 2,
+        ..Config::default()
+    })
+    .await;
+    let _first = connect(addrs.native).await;
+    let second = connect(addrs.native).await;
+
+    assert_eq!(refusal(addrs.native).await, "handshake rejected: too many connections");
+    let mut stream = TcpStream::connect(addrs.resp).await.unwrap();
+    stream.write_all(b"PING\r\n").await.unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await.unwrap();
+    assert_eq!(reply, "-ERR too many connections\r\n");
+
+    drop(second);
+    let (status, refused) = status(addrs.http).await;
+    let rejected = format!(r#""rejected":{}"#, 2 + refused);
+    assert!(status.contains(&rejected), "{}", status);
+}
+
+#[tokio::test]
+async fn one_address_cannot_take_every_place() {
+    let addrs = start_server(Config {
+        max_connections_per_ip: 1,
+        ..Config::default()
+    })
+    .await;
+    let first = connect(addrs.native).await;
+
+    assert_eq!(
+        refusal(addrs.native).await,
+        "handshake rejected: too many connections from this address"
+    );
+    drop(first);
+    let (status, _) = status(addrs.http).await;
+    assert!(status.contains(r#""rejected_per_ip":1"#), "{}", status);
+}
+
+#[tokio::test]
+async fn idle_connections_are_closed() {
+    let addrs = start_server(Config {
+        idle_timeout_ms: 100,
+        ..Config::default()
+    })
+    .await;
+    let mut idle = Connection::new(TcpStream::connect(addrs.native).await.unwrap());
+    idle.handshake(&Hello::client()).await.unwrap();
+
+    let closed = tokio::time::timeout(Duration::from_secs(2), idle.read_frame()).await;
+    assert_eq!(closed.unwrap().unwrap(), None);
+    let (status, _) = status(addrs.http).await;
+    assert!(status.contains(r#""closed_idle":1"#), "{}", status);
+}
+
+/// A client, once a place frees up.
+async fn connect(addr: SocketAddr) -> Client {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    loop {
+        match Client::connect(addr).await {
+            Ok(client) => return client,
+            Err(err) if Instant::now() > deadline => panic!("never let in: {}", err),
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    }
+}
+
+/// Why a client was not let in.
+async fn refusal(addr: SocketAddr) -> String {
+    match Client::connect(addr).await {
+        Ok(_) => panic!("let in"),
+        Err(err) => err.to_string(),
+    }
+}
+
+/// The status endpoint, once a place frees up for it, and how many times
+/// it was refused first.
+async fn status(addr: SocketAddr) -> (String, u64) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+    let mut refused = 0;
+    loop {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /v1/status HTTP/1.1\r\nHost: scow\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        if response.starts_with("HTTP/1.1 200 OK") || Instant::now() > deadline {
+            return (response, refused);
+        }
+        refused += 1;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+async fn start_server(config: Config) -> Addrs {
+    let native = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let resp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addrs = Addrs {
+        native: native.local_addr().unwrap(),
+        resp: resp.local_addr().unwrap(),
+        http: http.local_addr().unwrap(),
+    };
+
+    let listeners = Listeners::new(native).with_resp(resp).with_http(http);
+    tokio::spawn(async move { server::run_with_config(listeners, config, tokio::signal::ctrl_c()).await });
+    addrs
+}
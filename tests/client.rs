@@ -0,0 +1,120 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+
+use scow::client::{Client, ClientError, RetryPolicy};
+use scow::command::{Frame, Hello};
+use scow::connection::Connection;
+
+/// How a fake server answers a connection's first request.
+#[derive(Clone, Copy)]
+enum Answer {
+    HangUp,
+    Silence,
+    Value,
+}
+
+/// A server that shakes hands, then answers the first request on the nth
+/// connection with `answers[n]`, or a value past the end. Counts the
+/// requests it gets.
+async fn fake_server(answers: Vec<Answer>) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counted = requests.clone();
+    tokio::spawn(async move {
+        for n in 0.. {
+            let (socket, _) = listener.accept().await.unwrap();
+            let answer = answers.get(n).copied().unwrap_or(Answer::Value);
+            let requests = counted.clone();
+            tokio::spawn(async move {
+                let mut connection = Connection::new(socket);
+                connection.accept_handshake(&Hello::node(0, "scow")).await.unwrap();
+                while let Ok(Some(_)) = connection.read_frame().await {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    match answer {
+                        Answer::HangUp => return,
+                        Answer::Silence => std::future::pending::<()>().await,
+                        Answer::Value => {
+                            let value = Frame::Value("v".to_string()).to_string();
+                            connection.write(&value).await.unwrap();
+                        }
+                    }
+                }
+            });
+        }
+    });
+    (addr, requests)
+}
+
+#[tokio::test]
+async fn a_server_hanging_up_is_an_error_not_a_panic() {
+    let (addr, _) = fake_server(vec![Answer::HangUp]).await;
+    let mut client = Client::connect(addr).await.unwrap().with_retry(RetryPolicy::never());
+
+    assert_eq!(client.read("key").await, Err(ClientError::ConnectionClosed));
+    // the next request dials again.
+    assert_eq!(client.read("key").await, Ok(Frame::Value("v".to_string())));
+}
+
+#[tokio::test]
+async fn connecting_gives_up_at_the_deadline() {
+    // the listener never accepts, so the handshake is never answered.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let started = Instant::now();
+    assert_eq!(Client::connect(addr).await.err(), Some(ClientError::Timeout));
+    assert!(started.elapsed() < Duration::from_secs(6));
+}
+
+#[tokio::test]
+async fn requests_give_up_at_the_deadline() {
+    let (addr, _) = fake_server(vec![Answer::Silence]).await;
+    let mut client = Client::connect(addr)
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_millis(100))
+        .with_retry(RetryPolicy::never());
+
+    let started = Instant::now();
+    assert_eq!(client.read("key").await, Err(ClientError::Timeout));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn reads_are_retried() {
+    let retry = RetryPolicy {
+        max_attempts: 3,
+        backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+    };
+    let (addr, requests) = fake_server(vec![Answer::HangUp, Answer::Silence]).await;
+    let mut client = Client::connect(addr)
+        .await
+        .unwrap()
+        .with_timeout(Duration::from_secs(1))
+        .with_retry(retry);
+
+    // hung up on, then ignored until the deadline: the third try is let be.
+    assert_eq!(client.read("key").await, Err(ClientError::Timeout));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+    let (addr, requests) = fake_server(vec![Answer::HangUp]).await;
+    let mut client = Client::connect(addr).await.unwrap().with_retry(retry);
+    assert_eq!(client.read("key").await, Ok(Frame::Value("v".to_string())));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn writes_are_not_retried() {
+    let (addr, requests) = fake_server(vec![Answer::HangUp]).await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    // the write may have happened; doing it again could undo someone else's.
+    assert_eq!(client.write("key", "value").await, Err(ClientError::ConnectionClosed));
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
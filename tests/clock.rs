@@ -0,0 +1,148 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+use scow::clock::{Clock, Interval, ManualClock};
+use scow::config::{Config, Peer};
+use scow::server::{self, Listeners};
+
+/// Lets spawned tasks run without real time passing.
+async fn settle() {
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+}
+
+#[tokio::test]
+async fn manual_timers_wait_for_the_clock() {
+    let clock = ManualClock::new();
+    let (fired, mut rx) = oneshot::channel();
+    let timer = clock.sleep_until(clock.now() + Duration::from_secs(10));
+    tokio::spawn(async move {
+        timer.await;
+        let _ = fired.send(());
+    });
+
+    clock.advance(Duration::from_secs(9));
+    settle().await;
+    assert!(rx.try_recv().is_err());
+
+    clock.advance(Duration::from_secs(1));
+    rx.await.unwrap();
+}
+
+#[tokio::test]
+async fn intervals_tick_once_per_period() {
+    let clock = ManualClock::new();
+    let start = clock.now();
+    let mut interval = Interval::new(Arc::new(clock.clone()), Duration::from_millis(50));
+
+    for i in 1..=3 {
+        clock.advance(Duration::from_millis(50));
+        interval.tick().await;
+        assert_eq!(clock.now() - start, Duration::from_millis(50 * i));
+    }
+    // a long stall is one late tick, not a burst.
+    clock.advance(Duration::from_secs(1));
+    interval.tick().await;
+    let (tx, mut rx) = oneshot::channel();
+    tokio::spawn(async move {
+        interval.tick().await;
+        let _ = tx.send(());
+    });
+    settle().await;
+    assert!(rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn elections_happen_when_the_clock_says() {
+    let clock = ManualClock::new();
+    let status = start_cluster(3, &clock).await;
+    let heartbeat = Duration::from_millis(Config::default().heartbeat_interval_ms);
+
+    settle().await;
+    for addr in &status {
+        assert!(get_status(*addr).await.contains(r#""role":"follower""#));
+    }
+
+    // no election can start before the shortest timeout.
+    let min_ticks = Config::default().election_timeout_min_ms / Config::default().heartbeat_interval_ms;
+    for _ in 1..min_ticks {
+        clock.advance(heartbeat);
+        settle().await;
+    }
+    for addr in &status {
+        assert!(get_status(*addr).await.contains(r#""term":0"#));
+    }
+
+    for _ in 0..100 {
+        clock.advance(heartbeat);
+        // votes still travel over real sockets.
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        let mut leaders = 0;
+        for addr in &status {
+            if get_status(*addr).await.contains(r#""role":"leader""#) {
+                leaders += 1;
+            }
+        }
+        if leaders == 1 {
+            return;
+        }
+    }
+    panic!("no leader elected");
+}
+
+async fn get_status(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /v1/status HTTP/1.1\r\nHost: scow\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Starts `size` nodes on one manual clock, and returns their HTTP
+/// addresses.
+async fn start_cluster(size: u32, clock: &ManualClock) -> Vec<SocketAddr> {
+    let mut nodes = Vec::new();
+    for _ in 0..size {
+        let client = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        nodes.push((client, peer, http));
+    }
+    let addresses: Vec<(SocketAddr, SocketAddr)> = nodes
+        .iter()
+        .map(|(client, peer, _)| (client.local_addr().unwrap(), peer.local_addr().unwrap()))
+        .collect();
+
+    let mut status = Vec::new();
+    for (i, (client, peer, http)) in nodes.into_iter().enumerate() {
+        status.push(http.local_addr().unwrap());
+        let config = Config {
+            node_id: i as u32 + 1,
+            client_addr: addresses[i].0,
+            peer_addr: addresses[i].1,
+            peers: (0..size as usize)
+                .filter(|&j| j != i)
+                .map(|j| Peer {
+                    id: j as u32 + 1,
+                    address: addresses[j].1,
+                    client_addr: addresses[j].0,
+                    resp_addr: None,
+                    http_addr: None,
+                })
+                .collect(),
+            ..Config::default()
+        };
+        let listeners = Listeners::new(client).with_peer(peer).with_http(http);
+        let clock = clock.clone();
+        tokio::spawn(server::run_with_clock(listeners, config, clock, std::future::pending::<()>()));
+    }
+    status
+}
@@ -0,0 +1,327 @@
+// whole clusters of in-process nodes: elections, replication, crashes and
+// partitions, seen from clients and the status endpoint.
+
+mod common;
+
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use scow::client::{ClientError, RetryPolicy};
+use scow::command::Frame;
+use scow::config::Config;
+
+use common::cluster::TestCluster;
+use common::linearizability::{History, RecordingClient};
+
+#[tokio::test]
+async fn writes_to_the_leader_reach_every_node() {
+    let cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_leader().await;
+
+    let mut client = cluster.client(leader).await;
+    assert_eq!(client.write("key", "value").await.unwrap(), Frame::Success);
+    // every node has applied the write...
+    cluster.wait_for_convergence().await;
+
+    // ...but only the leader can tell nothing newer has been acknowledged.
+    for id in cluster.ids() {
+        let mut client = cluster.client(id).await;
+        let expected = match id == leader {
+            true => Ok(Frame::Value("value".to_string())),
+            false => Err(ClientError::NotLeader),
+        };
+        assert_eq!(client.read("key").await, expected);
+    }
+}
+
+#[tokio::test]
+async fn followers_refuse_writes() {
+    let cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_leader().await;
+    let follower = cluster.ids().into_iter().find(|&id| id != leader).unwrap();
+
+    let mut client = cluster.client(follower).await;
+    assert_eq!(client.write("key", "value").await, Err(ClientError::NotLeader));
+}
+
+#[tokio::test]
+async fn followers_send_redis_clients_to_the_leaders_resp_port() {
+    let cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_convergence().await;
+    let follower = cluster.ids().into_iter().find(|&id| id != leader).unwrap();
+
+    let mut stream = TcpStream::connect(cluster.resp_addr(follower)).await.unwrap();
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n").await.unwrap();
+    let mut buf = vec![0; 256];
+    let n = stream.read(&mut buf).await.unwrap();
+    let expected = format!("-MOVED 0 {}\r\n", cluster.resp_addr(leader));
+    assert_eq!(String::from_utf8_lossy(&buf[..n]), expected);
+}
+
+#[tokio::test]
+async fn followers_redirect_http_writes_to_the_leaders_http_port() {
+    let cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_convergence().await;
+    let follower = cluster.ids().into_iter().find(|&id| id != leader).unwrap();
+
+    let mut stream = TcpStream::connect(cluster.http_addr(follower)).await.unwrap();
+    stream
+        .write_all(b"PUT /v1/kv/key HTTP/1.1\r\ncontent-length: 5\r\nconnection: close\r\n\r\nvalue")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 307"), "{}", response);
+    let location = format!("location: http://{}/v1/kv/key\r\n", cluster.http_addr(leader));
+    assert!(response.to_lowercase().contains(&location), "{}", response);
+
+    // reads aren't redirected, but a follower can't serve them either.
+    let mut stream = TcpStream::connect(cluster.http_addr(follower)).await.unwrap();
+    stream.write_all(b"GET /v1/kv/key HTTP/1.1\r\nconnection: close\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+}
+
+#[tokio::test]
+async fn a_redis_get_that_fails_is_an_error_not_a_missing_key() {
+    let cluster = TestCluster::start(3).await;
+    let old = cluster.wait_for_convergence().await;
+
+    cluster.isolate(old);
+    let mut stream = TcpStream::connect(cluster.resp_addr(old)).await.unwrap();
+    // the old leader still thinks it leads, so the read waits for a quorum...
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n").await.unwrap();
+    cluster.wait_for_new_leader(old).await;
+    // ...until it hears of the new term and gives up on it.
+    cluster.heal();
+    let mut buf = vec![0; 256];
+    let n = stream.read(&mut buf).await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&buf[..n]), "-ERR not the leader\r\n");
+}
+
+#[tokio::test]
+async fn a_killed_leader_is_replaced_and_a_restarted_node_catches_up() {
+    let mut cluster = TestCluster::start(3).await;
+    let old = cluster.wait_for_leader().await;
+    let mut client = cluster.client(old).await;
+    client.write("before", "1").await.unwrap();
+    cluster.wait_for_convergence().await;
+
+    cluster.kill(old).await;
+    let new = cluster.wait_for_new_leader(old).await;
+    let mut client = cluster.client(new).await;
+    assert_eq!(client.write("after", "2").await.unwrap(), Frame::Success);
+
+    cluster.restart(old);
+    let leader = cluster.wait_for_convergence().await;
+    let mut client = cluster.client(leader).await;
+    assert_eq!(client.read("before").await.unwrap(), Frame::Value("1".to_string()));
+    assert_eq!(client.read("after").await.unwrap(), Frame::Value("2".to_string()));
+}
+
+#[tokio::test]
+async fn a_cluster_restarted_from_scratch_keeps_what_it_acknowledged() {
+    let mut cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_leader().await;
+    let mut client = cluster.client(leader).await;
+    assert_eq!(client.write("a", "1").await.unwrap(), Frame::Success);
+    assert_eq!(client.write("b", "2").await.unwrap(), Frame::Success);
+
+    // every node crashes at once, so nothing can be caught up from a peer.
+    for id in cluster.ids() {
+        cluster.kill(id).await;
+    }
+    for id in cluster.ids() {
+        cluster.restart(id);
+    }
+    let leader = cluster.wait_for_convergence().await;
+    let mut client = cluster.client(leader).await;
+    assert_eq!(client.read("a").await.unwrap(), Frame::Value("1".to_string()));
+    assert_eq!(client.read("b").await.unwrap(), Frame::Value("2".to_string()));
+}
+
+#[tokio::test]
+async fn a_stopped_leader_hands_over_without_an_election_timeout() {
+    // timeouts long enough that only a handover explains a quick leader.
+    let config = Config {
+        election_timeout_min_ms: 1000,
+        election_timeout_max_ms: 2000,
+        heartbeat_interval_ms: 50,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(3, config);
+    let old = cluster.wait_for_leader().await;
+    let mut client = cluster.client(old).await;
+    assert_eq!(client.write("key", "value").await.unwrap(), Frame::Success);
+
+    let stopped = Instant::now();
+    cluster.stop(old).await;
+    let new = cluster.wait_for_new_leader(old).await;
+    assert!(stopped.elapsed() < Duration::from_millis(800), "took {:?}", stopped.elapsed());
+
+    let mut client = cluster.client(new).await;
+    assert_eq!(client.read("key").await.unwrap(), Frame::Value("value".to_string()));
+}
+
+#[tokio::test]
+async fn an_isolated_leader_is_replaced_and_steps_down_on_healing() {
+    let cluster = TestCluster::start(5).await;
+    let old = cluster.wait_for_leader().await;
+    let term = cluster.status(old).await.unwrap().term;
+
+    cluster.isolate(old);
+    let new = cluster.wait_for_new_leader(old).await;
+    assert!(cluster.status(new).await.unwrap().term > term);
+    let mut client = cluster.client(new).await;
+    assert_eq!(client.write("key", "majority").await.unwrap(), Frame::Success);
+
+    cluster.heal();
+    assert_eq!(cluster.wait_for_convergence().await, new);
+    let status = cluster.status(old).await.unwrap();
+    assert!(!status.is_leader());
+    let mut client = cluster.client(old).await;
+    assert_eq!(client.read("key").await, Err(ClientError::NotLeader));
+    let mut client = cluster.client(new).await;
+    assert_eq!(client.read("key").await.unwrap(), Frame::Value("majority".to_string()));
+}
+
+#[tokio::test]
+async fn a_partitioned_old_leader_serves_no_stale_reads() {
+    let cluster = TestCluster::start(3).await;
+    let old = cluster.wait_for_leader().await;
+    let history = History::new();
+    let mut on_old = recording(&cluster, old, &history).await;
+    assert_eq!(on_old.write("key", "old").await.unwrap(), Frame::Success);
+
+    cluster.isolate(old);
+    let new = cluster.wait_for_new_leader(old).await;
+    let mut on_new = recording(&cluster, new, &history).await;
+    assert_eq!(on_new.write("key", "new").await.unwrap(), Frame::Success);
+    // the old leader hasn't heard it lost, but can't get a quorum to say
+    // it still leads.
+    let read = on_old.read("key").await;
+    assert!(read.is_err(), "{:?}", read);
+
+    cluster.heal();
+    cluster.wait_for_convergence().await;
+    assert_eq!(on_old.read("key").await, Err(ClientError::NotLeader));
+    assert_eq!(on_new.read("key").await.unwrap(), Frame::Value("new".to_string()));
+    if let Err(violation) = history.check() {
+        panic!("{}", violation);
+    }
+}
+
+#[tokio::test]
+async fn a_leader_that_steps_down_fails_its_pending_writes() {
+    let config = Config {
+        election_timeout_min_ms: 100,
+        election_timeout_max_ms: 200,
+        heartbeat_interval_ms: 20,
+        max_inflight_proposals: 2,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(5, config);
+    let old = cluster.wait_for_convergence().await;
+
+    cluster.isolate(old);
+    let mut pending = Vec::new();
+    for key in ["a", "b"] {
+        let mut client = cluster.client(old).await.with_retry(RetryPolicy::never());
+        pending.push(tokio::spawn(async move { client.write(key, "lost").await }));
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // the writes that can't commit hold both slots.
+    let mut client = cluster.client(old).await.with_retry(RetryPolicy::never());
+    let busy = client.write("other", "value").await;
+    assert!(matches!(busy, Err(ClientError::Busy(_))), "{:?}", busy);
+
+    let new = cluster.wait_for_new_leader(old).await;
+    cluster.heal();
+    // the old leader hears of the new term either before the new leader's
+    // entries replace its own, and can't tell what became of the writes, or
+    // along with them. Either way they are answered, not left waiting.
+    for write in pending {
+        let write = tokio::time::timeout(Duration::from_secs(2), write).await.unwrap().unwrap();
+        assert!(
+            matches!(write, Err(ClientError::OutcomeUnknown | ClientError::NotLeader)),
+            "{:?}",
+            write
+        );
+    }
+    // the new leader's entries have replaced the lost write.
+    cluster.wait_for_convergence().await;
+
+    // win the old leader its leadership back: only it can reach a majority.
+    cluster.isolate(new);
+    let others: Vec<u32> = cluster.ids().into_iter().filter(|&id| id != old && id != new).collect();
+    cluster.partition(&others.iter().map(std::slice::from_ref).collect::<Vec<_>>());
+    assert_eq!(cluster.wait_for_new_leader(new).await, old);
+    cluster.heal();
+    assert_eq!(cluster.wait_for_convergence().await, old);
+    // the failed write gave its slot back.
+    assert_eq!(client.write("other", "value").await.unwrap(), Frame::Success);
+}
+
+#[tokio::test]
+async fn a_deposed_leaders_write_can_still_commit() {
+    let config = Config {
+        election_timeout_min_ms: 100,
+        election_timeout_max_ms: 200,
+        heartbeat_interval_ms: 20,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(5, config);
+    let old = cluster.wait_for_convergence().await;
+    let others: Vec<u32> = cluster.ids().into_iter().filter(|&id| id != old).collect();
+    let (holders, starved) = others.split_at(2);
+
+    // two followers take the write but their acks never come back; the
+    // other two hear nothing from the leader.
+    for &id in holders {
+        cluster.faults().cut(id, old);
+    }
+    for &id in starved {
+        cluster.faults().cut(old, id);
+    }
+
+    let mut client = cluster.client(old).await.with_retry(RetryPolicy::never());
+    // the first starved follower to stand deposes the leader before
+    // anything commits, but only a node holding the write can win.
+    let write = tokio::time::timeout(Duration::from_secs(2), client.write("key", "value")).await;
+    assert_eq!(write.unwrap(), Err(ClientError::OutcomeUnknown));
+
+    cluster.heal();
+    let leader = cluster.wait_for_convergence().await;
+    let mut client = cluster.client(leader).await;
+    assert_eq!(client.read("key").await.unwrap(), Frame::Value("value".to_string()));
+}
+
+#[tokio::test]
+async fn a_minority_cannot_elect_a_leader() {
+    let cluster = TestCluster::start(5).await;
+    let leader = cluster.wait_for_leader().await;
+    let mut others = cluster.ids().into_iter().filter(|&id| id != leader);
+    let minority = [leader, others.next().unwrap()];
+    let majority: Vec<u32> = others.collect();
+
+    cluster.partition(&[&minority, &majority]);
+    let new = cluster.wait_for_new_leader(leader).await;
+    assert!(majority.contains(&new));
+    // the old leader can no longer commit.
+    let mut client = cluster.client(leader).await;
+    let write = tokio::time::timeout(std::time::Duration::from_millis(500), client.write("key", "lost"));
+    assert!(!matches!(write.await, Ok(Ok(Frame::Success))));
+
+    cluster.heal();
+    cluster.wait_for_convergence().await;
+}
+
+/// A client of node `id` that gives up quickly and records into `history`.
+async fn recording(cluster: &TestCluster, id: u32, history: &History) -> RecordingClient {
+    let client = cluster.client(id).await.with_timeout(Duration::from_millis(500));
+    RecordingClient::new(id, client.with_retry(RetryPolicy::never()), history.clone())
+}
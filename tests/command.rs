@@ -0,0 +1,108 @@
+use proptest::prelude::*;
+
+use scow::command::{CasArgs, Compare, CompareTarget, Frame, GetArgs, Txn, TxnOp};
+use scow::consensus::Message;
+use scow::log::LogEntry;
+
+/// Any string, and short ones made mostly of the characters the framing
+/// has to get around.
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![any::<String>(), "[a \\\\\r\n:-]{0,12}"]
+}
+
+fn txn_op() -> impl Strategy<Value = TxnOp> {
+    prop_oneof![
+        (text(), text()).prop_map(|(k, v)| TxnOp::Put(k, v)),
+        text().prop_map(TxnOp::Delete),
+        text().prop_map(TxnOp::Get),
+    ]
+}
+
+fn compare() -> impl Strategy<Value = Compare> {
+    let target = prop_oneof![
+        text().prop_map(CompareTarget::Value),
+        any::<u64>().prop_map(CompareTarget::Version),
+        any::<bool>().prop_map(CompareTarget::Exists),
+    ];
+    (text(), target).prop_map(|(key, target)| Compare { key, target })
+}
+
+/// Every frame that is proposed to the log.
+fn write_frame() -> impl Strategy<Value = Frame> {
+    prop_oneof![
+        (text(), text()).prop_map(|(k, v)| Frame::Write(k, v)),
+        text().prop_map(Frame::Delete),
+        (text(), proptest::option::of(text()), text())
+            .prop_map(|(key, expected, new)| Frame::CompareAndSwap(CasArgs { key, expected, new })),
+        any::<u64>().prop_map(Frame::Compact),
+        any::<u64>().prop_map(Frame::LeaseGrant),
+        any::<u64>().prop_map(Frame::LeaseRevoke),
+        (text(), any::<u64>(), text()).prop_map(|(k, lease, v)| Frame::LeasePut(k, lease, v)),
+        proptest::collection::vec((text(), text()), 0..4).prop_map(Frame::WriteBatch),
+        (
+            proptest::collection::vec(compare(), 0..3),
+            proptest::collection::vec(txn_op(), 0..3),
+            proptest::collection::vec(txn_op(), 0..3),
+        )
+            .prop_map(|(compares, success, failure)| Frame::Txn(Txn {
+                compares,
+                success,
+                failure,
+            })),
+    ]
+}
+
+fn decode(frame: &Frame) -> Frame {
+    Frame::decode(frame.to_string().as_bytes()).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn write_frames_round_trip(frame in write_frame()) {
+        prop_assert_eq!(decode(&frame), frame);
+    }
+
+    #[test]
+    fn write_frames_round_trip_inside_appends(frames in proptest::collection::vec(write_frame(), 1..4)) {
+        let entries: Vec<LogEntry> = frames
+            .into_iter()
+            .enumerate()
+            .map(|(i, command)| LogEntry { term: 2, index: i as u64 + 1, command })
+            .collect();
+        let append = Frame::Raft(Message::AppendEntries {
+            term: 2,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries,
+            leader_commit: 0,
+        });
+        prop_assert_eq!(decode(&append), append);
+    }
+
+    #[test]
+    fn keys_and_values_round_trip(key in text(), value in text()) {
+        for frame in [
+            Frame::Read(key.clone()),
+            Frame::Value(value.clone()),
+            Frame::Error(value.clone()),
+            Frame::Exists(key.clone()),
+            Frame::Get(GetArgs { key: key.clone(), revision: Some(3) }),
+            Frame::Get(GetArgs { key: key.clone(), revision: None }),
+        ] {
+            prop_assert_eq!(decode(&frame), frame);
+        }
+    }
+}
+
+#[test]
+fn frames_stay_on_one_line() {
+    let frame = Frame::Write("a key".to_string(), "two\r\nlines \\ ".to_string());
+    let line = frame.to_string();
+    assert_eq!(line.find("\r\n"), Some(line.len() - 2));
+    assert_eq!(decode(&frame), frame);
+
+    let frame = Frame::Error("bad key `two\r\nlines`".to_string());
+    let line = frame.to_string();
+    assert_eq!(line.find("\r\n"), Some(line.len() - 2));
+    assert_eq!(decode(&frame), frame);
+}
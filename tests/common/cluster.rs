@@ -0,0 +1,362 @@
+// a cluster of real nodes inside the test process.
+//
+// Each node runs on its own thread and runtime, so killing one drops every
+// task and connection it had, like a crashed process; stopping one shuts it
+// down gracefully instead. Nodes serve clients, RESP and HTTP on ephemeral
+// ports and talk Raft over a `ChannelNetwork`, where the test can inject
+// faults. Each node persists its log in a data_dir of its own, so a
+// restarted node keeps its ports and picks up from what it had on disk.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, TcpListener as StdListener};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use scow::client::Client;
+use scow::clock::SystemClock;
+use scow::config::{Config, Peer};
+use scow::consensus::ServerId;
+use scow::fault::{FaultTransport, Faults};
+use scow::server::{self, Listeners};
+use scow::transport::ChannelNetwork;
+
+use super::data_dir::DataDir;
+
+/// How long the wait_for_* helpers wait before failing the test.
+const PATIENCE: Duration = Duration::from_secs(10);
+
+/// A node's view of the cluster, from its HTTP status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub term: u64,
+    pub role: String,
+    pub leader: Option<u32>,
+    pub commit_index: u64,
+    pub applied_index: u64,
+}
+
+impl Status {
+    pub fn is_leader(&self) -> bool {
+        self.role == "leader"
+    }
+
+    fn parse(json: &str) -> Status {
+        let role = json.split("\"role\":\"").nth(1).and_then(|r| r.split('"').next());
+        Status {
+            term: number(json, "\"term\":").unwrap(),
+            role: role.unwrap().to_string(),
+            leader: number(json, "\"leader\":{\"id\":").map(|id| id as u32),
+            commit_index: number(json, "\"commit_index\":").unwrap(),
+            applied_index: number(json, "\"applied_index\":").unwrap(),
+        }
+    }
+}
+
+fn number(json: &str, after: &str) -> Option<u64> {
+    let rest = &json[json.find(after)? + after.len()..];
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..end].parse().ok()
+}
+
+struct Node {
+    client: SocketAddr,
+    http: SocketAddr,
+    resp: SocketAddr,
+    data: DataDir,
+    running: Option<Running>,
+}
+
+struct Running {
+    /// Shuts the node down gracefully.
+    stop: oneshot::Sender<()>,
+    /// Stops it dead.
+    crash: oneshot::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+pub struct TestCluster {
+    nodes: BTreeMap<u32, Node>,
+    network: ChannelNetwork,
+    faults: Faults,
+    /// Settings shared by every node; ids, addresses and peers are filled
+    /// in per node.
+    config: Config,
+}
+
+impl TestCluster {
+    /// Starts `size` nodes, numbered from 1, and waits for a leader.
+    pub async fn start(size: u32) -> TestCluster {
+        let config = Config {
+            election_timeout_min_ms: 100,
+            election_timeout_max_ms: 200,
+            heartbeat_interval_ms: 20,
+            ..Config::default()
+        };
+        let cluster = TestCluster::start_with_config(size, config);
+        cluster.wait_for_leader().await;
+        cluster
+    }
+
+    /// Starts `size` nodes with `config`'s settings, without waiting for
+    /// anything.
+    pub fn start_with_config(size: u32, config: Config) -> TestCluster {
+        let mut cluster = TestCluster {
+            nodes: BTreeMap::new(),
+            network: ChannelNetwork::new(),
+            faults: Faults::default(),
+            config,
+        };
+        let mut listeners = BTreeMap::new();
+        for id in 1..=size {
+            let client = StdListener::bind("127.0.0.1:0").unwrap();
+            let http = StdListener::bind("127.0.0.1:0").unwrap();
+            let resp = StdListener::bind("127.0.0.1:0").unwrap();
+            let node = Node {
+                client: client.local_addr().unwrap(),
+                http: http.local_addr().unwrap(),
+                resp: resp.local_addr().unwrap(),
+                data: DataDir::create(),
+                running: None,
+            };
+            cluster.nodes.insert(id, node);
+            listeners.insert(id, (client, http, resp));
+        }
+        for (id, (client, http, resp)) in listeners {
+            cluster.launch(id, client, http, resp);
+        }
+        cluster
+    }
+
+    pub fn ids(&self) -> Vec<u32> {
+        self.nodes.keys().copied().collect()
+    }
+
+    /// Where node `id` serves the native protocol.
+    pub fn client_addr(&self, id: u32) -> SocketAddr {
+        self.nodes[&id].client
+    }
+
+    pub fn http_addr(&self, id: u32) -> SocketAddr {
+        self.nodes[&id].http
+    }
+
+    pub fn resp_addr(&self, id: u32) -> SocketAddr {
+        self.nodes[&id].resp
+    }
+
+    pub async fn client(&self, id: u32) -> Client {
+        Client::connect(self.client_addr(id)).await.unwrap()
+    }
+
+    pub fn is_running(&self, id: u32) -> bool {
+        self.nodes[&id].running.is_some()
+    }
+
+    /// Node `id`'s status, or None if it is down.
+    pub async fn status(&self, id: u32) -> Option<Status> {
+        if !self.is_running(id) {
+            return None;
+        }
+        let mut stream = TcpStream::connect(self.http_addr(id)).await.ok()?;
+        stream
+            .write_all(b"GET /v1/status HTTP/1.1\r\nHost: scow\r\nConnection: close\r\n\r\n")
+            .await
+            .ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.ok()?;
+        let body = response.split("\r\n\r\n").nth(1)?;
+        Some(Status::parse(body))
+    }
+
+    async fn statuses(&self) -> BTreeMap<u32, Status> {
+        let mut statuses = BTreeMap::new();
+        for id in self.ids() {
+            if let Some(status) = self.status(id).await {
+                statuses.insert(id, status);
+            }
+        }
+        statuses
+    }
+
+    /// The node a majority of the cluster follows, if there is one.
+    pub async fn leader(&self) -> Option<u32> {
+        let statuses = self.statuses().await;
+        let (&leader, status) = statuses.iter().filter(|(_, s)| s.is_leader()).max_by_key(|(_, s)| s.term)?;
+        let followers = statuses
+            .values()
+            .filter(|s| s.term == status.term && s.leader == Some(leader))
+            .count();
+        (followers > self.nodes.len() / 2).then_some(leader)
+    }
+
+    /// Waits until a majority follows one leader, and returns it.
+    pub async fn wait_for_leader(&self) -> u32 {
+        self.wait_for_leader_other_than(None).await
+    }
+
+    /// Waits until a majority follows a leader other than `old`. Right
+    /// after `old` is cut off, the others still follow it until they time
+    /// out.
+    pub async fn wait_for_new_leader(&self, old: u32) -> u32 {
+        self.wait_for_leader_other_than(Some(old)).await
+    }
+
+    async fn wait_for_leader_other_than(&self, old: Option<u32>) -> u32 {
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            match self.leader().await {
+                Some(leader) if Some(leader) != old => return leader,
+                _ => {}
+            }
+            if Instant::now() > deadline {
+                panic!("no leader elected: {:?}", self.statuses().await);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Waits until every running node follows the same leader and has
+    /// applied everything it committed. Heal partitions first: a cut off
+    /// node never catches up.
+    pub async fn wait_for_convergence(&self) -> u32 {
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            let statuses = self.statuses().await;
+            if let Some(leader) = self.leader().await {
+                let commit = statuses.get(&leader).map(|s| s.commit_index);
+                let running = self.ids().into_iter().filter(|&id| self.is_running(id)).count();
+                let converged = statuses.len() == running
+                    && statuses
+                        .values()
+                        .all(|s| s.leader == Some(leader) && Some(s.applied_index) == commit);
+                if converged {
+                    return leader;
+                }
+            }
+            if Instant::now() > deadline {
+                panic!("cluster did not converge: {:?}", statuses);
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Stops node `id` the hard way: its tasks and connections just go
+    /// away.
+    pub async fn kill(&mut self, id: u32) {
+        let running = self.nodes.get_mut(&id).unwrap().running.take();
+        if let Some(running) = running {
+            let _ = running.crash.send(());
+            tokio::task::spawn_blocking(move || running.thread.join()).await.unwrap().unwrap();
+        }
+    }
+
+    /// Shuts node `id` down as an operator would, and waits until it is
+    /// done.
+    pub async fn stop(&mut self, id: u32) {
+        let running = self.nodes.get_mut(&id).unwrap().running.take();
+        if let Some(running) = running {
+            let _ = running.stop.send(());
+            tokio::task::spawn_blocking(move || running.thread.join()).await.unwrap().unwrap();
+        }
+    }
+
+    /// Starts a killed or stopped node again on the same ports.
+    pub fn restart(&mut self, id: u32) {
+        assert!(!self.is_running(id), "node {} is already running", id);
+        let node = &self.nodes[&id];
+        let client = StdListener::bind(node.client).unwrap();
+        let http = StdListener::bind(node.http).unwrap();
+        let resp = StdListener::bind(node.resp).unwrap();
+        self.launch(id, client, http, resp);
+    }
+
+    /// Faults applied to every node's consensus traffic, as it sends it.
+    pub fn faults(&self) -> &Faults {
+        &self.faults
+    }
+
+    /// Cuts every link between nodes in different groups. Nodes left out of
+    /// every group keep their links.
+    pub fn partition(&self, groups: &[&[u32]]) {
+        for (i, group) in groups.iter().enumerate() {
+            for other in &groups[i + 1..] {
+                self.faults.partition(group, other);
+            }
+        }
+    }
+
+    /// Cuts node `id` off from everyone else.
+    pub fn isolate(&self, id: u32) {
+        let rest: Vec<u32> = self.ids().into_iter().filter(|&other| other != id).collect();
+        self.partition(&[&[id], &rest]);
+    }
+
+    /// Restores every link and clears every fault.
+    pub fn heal(&self) {
+        self.faults.heal();
+    }
+
+    fn launch(&mut self, id: u32, client: StdListener, http: StdListener, resp: StdListener) {
+        // nodes only meet on the channel network, which knows them by
+        // their client address.
+        let address = |id: u32| self.nodes[&id].client;
+        let config = Config {
+            node_id: id,
+            client_addr: address(id),
+            peer_addr: address(id),
+            http_addr: Some(self.nodes[&id].http),
+            data_dir: Some(self.nodes[&id].data.path().to_path_buf()),
+            peers: self
+                .ids()
+                .into_iter()
+                .filter(|&p| p != id)
+                .map(|p| Peer {
+                    id: p,
+                    address: address(p),
+                    client_addr: address(p),
+                    resp_addr: Some(self.nodes[&p].resp),
+                    http_addr: Some(self.nodes[&p].http),
+                })
+                .collect(),
+            ..self.config.clone()
+        };
+        let transport = FaultTransport::new(
+            self.network.join(ServerId { id, address: address(id) }),
+            self.faults.clone(),
+        );
+        let (stop, stopped) = oneshot::channel::<()>();
+        let (crash, crashed) = oneshot::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async move {
+                client.set_nonblocking(true).unwrap();
+                http.set_nonblocking(true).unwrap();
+                resp.set_nonblocking(true).unwrap();
+                let listeners = Listeners::new(TcpListener::from_std(client).unwrap())
+                    .with_http(TcpListener::from_std(http).unwrap())
+                    .with_resp(TcpListener::from_std(resp).unwrap());
+                tokio::select! {
+                    _ = server::run_with_transport(listeners, config, SystemClock, transport, stopped) => {}
+                    _ = crashed => {}
+                }
+            });
+            // dropping the runtime takes every task the node spawned with it.
+        });
+        self.nodes.get_mut(&id).unwrap().running = Some(Running { stop, crash, thread });
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        for node in self.nodes.values_mut() {
+            if let Some(running) = node.running.take() {
+                let _ = running.crash.send(());
+            }
+        }
+    }
+}
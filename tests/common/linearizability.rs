@@ -0,0 +1,333 @@
+// a linearizability checker for key-value histories, in the style of
+// Knossos and Porcupine.
+//
+// Clients record when each operation was invoked and when, and how, it
+// completed. `History::check` then looks for an order of the operations that
+// one sequential key-value store could have produced, with each operation
+// taking effect at some instant between its invocation and its completion.
+// Keys are independent, so every key is checked on its own, with Wing &
+// Gong's search and Lowe's cache of states already visited.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use scow::client::{Client, ClientError};
+use scow::command::Frame;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Read,
+    Write(String),
+    /// Sets the key to `new` if it holds `expected` (None: is absent).
+    Cas { expected: Option<String>, new: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// What a read saw; None for a missing key.
+    Value(Option<String>),
+    Written,
+    /// Whether a CAS swapped.
+    Cas(bool),
+    /// No answer (a timeout, a dropped connection, a leader change): the
+    /// operation may or may not have taken effect, at any time after it was
+    /// invoked.
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub client: u32,
+    pub key: String,
+    pub op: Op,
+    pub outcome: Outcome,
+    /// Position of the invocation and completion among every event in the
+    /// history. Unknown outcomes never complete.
+    pub invoked: u64,
+    pub completed: Option<u64>,
+    /// Since the history started, for reading a failure.
+    pub invoked_at: Duration,
+    pub completed_at: Option<Duration>,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match &self.op {
+            Op::Read => "read".to_string(),
+            Op::Write(value) => format!("write {:?}", value),
+            Op::Cas { expected, new } => format!("cas {:?} -> {:?}", expected, new),
+        };
+        let outcome = match &self.outcome {
+            Outcome::Value(value) => format!("{:?}", value),
+            Outcome::Written => "ok".to_string(),
+            Outcome::Cas(swapped) => format!("swapped: {}", swapped),
+            Outcome::Unknown => "unknown".to_string(),
+        };
+        let completed = self.completed_at.map_or("...".to_string(), |at| format!("{:?}", at));
+        write!(
+            f,
+            "client {} [{:?} .. {}] {} {} => {}",
+            self.client, self.invoked_at, completed, self.key, op, outcome
+        )
+    }
+}
+
+/// A key whose operations no sequential store could have produced.
+#[derive(Debug)]
+pub struct Violation {
+    pub key: String,
+    /// A smallest sub-history that is still not linearizable: dropping any
+    /// one of these operations, short of a write something here observed,
+    /// makes it linearizable.
+    pub ops: Vec<Operation>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "history of key {:?} is not linearizable, minimal failing sub-history:", self.key)?;
+        for op in &self.ops {
+            writeln!(f, "  {}", op)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Recorded {
+    start: Instant,
+    events: u64,
+    ops: Vec<Option<Operation>>,
+}
+
+/// Operations recorded by any number of concurrent clients.
+#[derive(Debug, Clone)]
+pub struct History {
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    pub fn new() -> History {
+        let recorded = Recorded {
+            start: Instant::now(),
+            events: 0,
+            ops: Vec::new(),
+        };
+        History {
+            recorded: Arc::new(Mutex::new(recorded)),
+        }
+    }
+
+    /// Records that `client` started `op` on `key`. Hand the returned id to
+    /// `complete` or `fail` once it is answered.
+    pub fn invoke(&self, client: u32, key: &str, op: Op) -> usize {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.events += 1;
+        let operation = Operation {
+            client,
+            key: key.to_string(),
+            op,
+            outcome: Outcome::Unknown,
+            invoked: recorded.events,
+            completed: None,
+            invoked_at: recorded.start.elapsed(),
+            completed_at: None,
+        };
+        recorded.ops.push(Some(operation));
+        recorded.ops.len() - 1
+    }
+
+    pub fn complete(&self, id: usize, outcome: Outcome) {
+        let mut recorded = self.recorded.lock().unwrap();
+        recorded.events += 1;
+        let (events, elapsed) = (recorded.events, recorded.start.elapsed());
+        if let Some(op) = recorded.ops[id].as_mut() {
+            if outcome != Outcome::Unknown {
+                op.completed = Some(events);
+                op.completed_at = Some(elapsed);
+            }
+            op.outcome = outcome;
+        }
+    }
+
+    /// Forgets an operation known to have had no effect, like a write the
+    /// server refused.
+    pub fn fail(&self, id: usize) {
+        self.recorded.lock().unwrap().ops[id] = None;
+    }
+
+    pub fn operations(&self) -> Vec<Operation> {
+        self.recorded.lock().unwrap().ops.iter().flatten().cloned().collect()
+    }
+
+    pub fn check(&self) -> std::result::Result<(), Violation> {
+        check(&self.operations())
+    }
+}
+
+/// Checks every key's operations, reporting the first key that isn't
+/// linearizable.
+pub fn check(ops: &[Operation]) -> std::result::Result<(), Violation> {
+    let mut keys: BTreeMap<&str, Vec<Operation>> = BTreeMap::new();
+    for op in ops {
+        keys.entry(&op.key).or_default().push(op.clone());
+    }
+    for (key, ops) in keys {
+        if !linearizable(&ops) {
+            return Err(Violation {
+                key: key.to_string(),
+                ops: minimize(ops),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Drops operations one at a time for as long as what is left still fails.
+/// A write stays while anything left observes its value, so the result
+/// reads as an explanation rather than, say, a lone read of a value that
+/// was never written.
+fn minimize(mut ops: Vec<Operation>) -> Vec<Operation> {
+    let mut i = 0;
+    while i < ops.len() {
+        let observed = match &ops[i].op {
+            Op::Write(value) | Op::Cas { new: value, .. } => ops.iter().any(|o| observes(o, value)),
+            Op::Read => false,
+        };
+        let mut smaller = ops.clone();
+        smaller.remove(i);
+        if observed || linearizable(&smaller) {
+            i += 1;
+        } else {
+            ops = smaller;
+        }
+    }
+    ops
+}
+
+fn observes(op: &Operation, value: &str) -> bool {
+    match (&op.op, &op.outcome) {
+        (Op::Read, Outcome::Value(Some(seen))) => seen == value,
+        (Op::Cas { expected: Some(expected), .. }, _) => expected == value,
+        _ => false,
+    }
+}
+
+/// The sequential model: one register per key. The state after `op`, or
+/// None if it couldn't have answered `outcome` from `state`.
+fn step(state: &Option<String>, op: &Op, outcome: &Outcome) -> Option<Option<String>> {
+    match (op, outcome) {
+        (Op::Read, Outcome::Value(seen)) => (seen == state).then(|| state.clone()),
+        (Op::Read, _) => Some(state.clone()),
+        (Op::Write(value), _) => Some(Some(value.clone())),
+        (Op::Cas { expected, new }, outcome) => {
+            let swaps = expected == state;
+            match outcome {
+                Outcome::Cas(swapped) if *swapped != swaps => None,
+                _ if swaps => Some(Some(new.clone())),
+                _ => Some(state.clone()),
+            }
+        }
+    }
+}
+
+fn linearizable(ops: &[Operation]) -> bool {
+    let mut done = vec![0u64; ops.len().div_ceil(64)];
+    let mut seen = HashSet::new();
+    search(ops, &mut done, ops.len(), &None, &mut seen)
+}
+
+/// Linearizes one more operation: any not yet done that was invoked before
+/// every remaining operation's completion could be next.
+fn search(
+    ops: &[Operation],
+    done: &mut Vec<u64>,
+    left: usize,
+    state: &Option<String>,
+    seen: &mut HashSet<(Vec<u64>, Option<String>)>,
+) -> bool {
+    if left == 0 {
+        return true;
+    }
+    let is_done = |done: &[u64], i: usize| done[i / 64] & (1 << (i % 64)) != 0;
+    let horizon = (0..ops.len())
+        .filter(|&i| !is_done(done, i))
+        .map(|i| ops[i].completed.unwrap_or(u64::MAX))
+        .min()
+        .unwrap_or(u64::MAX);
+    for i in 0..ops.len() {
+        if is_done(done, i) || ops[i].invoked > horizon {
+            continue;
+        }
+        let Some(next) = step(state, &ops[i].op, &ops[i].outcome) else {
+            continue;
+        };
+        done[i / 64] |= 1 << (i % 64);
+        if seen.insert((done.clone(), next.clone())) && search(ops, done, left - 1, &next, seen) {
+            return true;
+        }
+        done[i / 64] &= !(1 << (i % 64));
+    }
+    false
+}
+
+/// A `Client` that records every read, write and CAS in a `History`.
+pub struct RecordingClient {
+    id: u32,
+    client: Client,
+    history: History,
+}
+
+impl RecordingClient {
+    pub fn new(id: u32, client: Client, history: History) -> RecordingClient {
+        RecordingClient { id, client, history }
+    }
+
+    pub async fn read(&mut self, key: &str) -> Result<Frame, ClientError> {
+        let op = self.history.invoke(self.id, key, Op::Read);
+        let result = self.client.read(key).await;
+        match &result {
+            Ok(Frame::Value(value)) => self.history.complete(op, Outcome::Value(Some(value.clone()))),
+            Err(ClientError::NotFound) => self.history.complete(op, Outcome::Value(None)),
+            // a failed read changed nothing.
+            _ => self.history.fail(op),
+        }
+        result
+    }
+
+    pub async fn write(&mut self, key: &str, value: &str) -> Result<Frame, ClientError> {
+        let op = self.history.invoke(self.id, key, Op::Write(value.to_string()));
+        let result = self.client.write(key, value).await;
+        match &result {
+            Ok(Frame::Success) => self.history.complete(op, Outcome::Written),
+            // turned away before it reached the log.
+            Err(ClientError::NotLeader | ClientError::Busy(_)) => self.history.fail(op),
+            _ => self.history.complete(op, Outcome::Unknown),
+        }
+        result
+    }
+
+    pub async fn compare_and_swap(&mut self, key: &str, expected: Option<&str>, new: &str) -> Result<Frame, ClientError> {
+        let cas = Op::Cas {
+            expected: expected.map(String::from),
+            new: new.to_string(),
+        };
+        let op = self.history.invoke(self.id, key, cas);
+        let result = self.client.compare_and_swap(key, expected, new).await;
+        match &result {
+            Ok(Frame::CasResult(cas)) => self.history.complete(op, Outcome::Cas(cas.succeeded)),
+            Err(ClientError::NotLeader | ClientError::Busy(_)) => self.history.fail(op),
+            _ => self.history.complete(op, Outcome::Unknown),
+        }
+        result
+    }
+}
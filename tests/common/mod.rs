@@ -0,0 +1,7 @@
+// support code shared by the integration tests. Each test crate only uses
+// part of it.
+#![allow(dead_code)]
+
+pub mod cluster;
+pub mod data_dir;
+pub mod linearizability;
@@ -0,0 +1,135 @@
+use std::io::Write;
+
+use scow::config::{Config, Peer};
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+    vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn parses_a_toml_file() {
+    let config = Config::from_toml(
+        r#"
+        node_id = 2
+        client_addr = "127.0.0.1:9902"
+        peer_addr = "127.0.0.1:9802"
+        data_dir = "/var/lib/scow"
+        peers = [
+            { id = 1, address = "127.0.0.1:9801", client_addr = "127.0.0.1:9901" },
+            { id = 3, address = "127.0.0.1:9803", client_addr = "127.0.0.1:9903", resp_addr = "127.0.0.1:6303" },
+        ]
+        "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.node_id, 2);
+    assert_eq!(config.client_addr, "127.0.0.1:9902".parse().unwrap());
+    assert_eq!(config.data_dir, Some("/var/lib/scow".into()));
+    assert_eq!(
+        config.peers[1],
+        Peer {
+            id: 3,
+            address: "127.0.0.1:9803".parse().unwrap(),
+            client_addr: "127.0.0.1:9903".parse().unwrap(),
+            resp_addr: Some("127.0.0.1:6303".parse().unwrap()),
+            http_addr: None,
+        }
+    );
+    // anything not in the file keeps its default.
+    assert_eq!(config.max_batch_size, Config::default().max_batch_size);
+    config.validate().unwrap();
+}
+
+#[test]
+fn unknown_settings_are_errors() {
+    let err = Config::from_toml("node = 1").unwrap_err();
+    assert!(err.to_string().contains("unknown field"), "{}", err);
+
+    let err = Config::load(env(&[]), args(&["--nodeid", "1"])).unwrap_err();
+    assert!(err.to_string().contains("unknown setting `nodeid`"), "{}", err);
+}
+
+#[test]
+fn flags_override_env_override_file() {
+    let mut file = tempfile();
+    writeln!(file.1, "node_id = 1\ncluster_id = \"file\"\nheartbeat_interval_ms = 20").unwrap();
+
+    let config = Config::load(
+        env(&[
+            ("SCOW_CONFIG", file.0.to_str().unwrap()),
+            ("SCOW_NODE_ID", "2"),
+            ("SCOW_CLUSTER_ID", "env"),
+            ("HOME", "/root"),
+        ]),
+        args(&[
+            "--node-id",
+            "3",
+            "--peers=1=127.0.0.1:9801/client=127.0.0.1:9901,2=127.0.0.1:9802/client=127.0.0.1:9902/resp=127.0.0.1:6302/http=127.0.0.1:8002",
+        ]),
+    )
+    .unwrap();
+
+    assert_eq!(config.node_id, 3);
+    assert_eq!(config.cluster_id, "env");
+    assert_eq!(config.heartbeat_interval_ms, 20);
+    assert_eq!(config.peers.len(), 2);
+    assert_eq!(config.peers[1].client_addr, "127.0.0.1:9902".parse().unwrap());
+    assert_eq!(config.peers[1].resp_addr, Some("127.0.0.1:6302".parse().unwrap()));
+    assert_eq!(config.peers[1].http_addr, Some("127.0.0.1:8002".parse().unwrap()));
+    assert_eq!(config.peers[0].resp_addr, None);
+    std::fs::remove_file(file.0).unwrap();
+}
+
+#[test]
+fn peers_need_a_client_address() {
+    let err = Config::from_toml(r#"peers = [{ id = 1, address = "127.0.0.1:9801" }]"#).unwrap_err();
+    assert!(err.to_string().contains("missing field `client_addr`"), "{}", err);
+
+    let err = Config::load(env(&[("SCOW_PEERS", "1=127.0.0.1:9801")]), args(&[])).unwrap_err();
+    assert!(err.to_string().contains("peer `1=127.0.0.1:9801` has no client address"), "{}", err);
+
+    let err = Config::load(env(&[]), args(&["--peers", "1=127.0.0.1:9801/admin=127.0.0.1:1"])).unwrap_err();
+    assert!(err.to_string().starts_with("invalid peer"), "{}", err);
+}
+
+#[test]
+fn validation_reports_every_problem() {
+    let err = Config::load(
+        env(&[]),
+        args(&[
+            "--node-id",
+            "1",
+            "--peers",
+            "1=127.0.0.1:9801/client=127.0.0.1:9901",
+            "--heartbeat-interval-ms",
+            "500",
+            "--max-batch-size",
+            "0",
+        ]),
+    )
+    .unwrap_err()
+    .to_string();
+
+    assert!(err.contains("peer 1 has this node's own id"), "{}", err);
+    assert!(err.contains("heartbeat_interval_ms 500"), "{}", err);
+    assert!(err.contains("max_batch_size must be at least 1"), "{}", err);
+
+    let err = Config::load(env(&[]), args(&["--client-addr", "nowhere"])).unwrap_err();
+    assert!(err.to_string().starts_with("invalid client_addr `nowhere`"), "{}", err);
+}
+
+fn tempfile() -> (std::path::PathBuf, std::fs::File) {
+    let path = std::env::temp_dir().join(format!("scow-config-{}.toml", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    (path, file)
+}
+
+#[test]
+fn example_config_is_valid() {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("scow.example.toml");
+    Config::from_file(&path).unwrap().validate().unwrap();
+}
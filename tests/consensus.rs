@@ -0,0 +1,170 @@
+use std::io::Write;
+
+use scow::command::Frame;
+use scow::consensus::{Progress, ProgressState};
+use scow::log::{Log, LogEntry};
+use scow::raft::HardState;
+
+mod common;
+use common::data_dir::DataDir;
+
+#[test]
+fn log_appends_a_batch_with_one_flush() {
+    let mut log = Log::new();
+    let entries = log.append(
+        1,
+        vec![
+            Frame::Write("a".to_string(), "1".to_string()),
+            Frame::Write("b".to_string(), "2".to_string()),
+            Frame::Delete("a".to_string()),
+        ],
+    )
+    .unwrap();
+
+    let indexes: Vec<u64> = entries.iter().map(|e| e.index).collect();
+    assert_eq!(indexes, vec![1, 2, 3]);
+    assert_eq!(log.flushes(), 1);
+
+    log.append(2, vec![Frame::Delete("b".to_string())]).unwrap();
+    assert_eq!((log.last_index(), log.last_term()), (4, 2));
+    assert_eq!(log.flushes(), 2);
+    assert_eq!(log.entries_from(3, 10).len(), 2);
+}
+
+#[test]
+fn a_persisted_log_reopens_as_it_was_left() {
+    let dir = DataDir::create();
+    let mut log = Log::open(dir.path()).unwrap();
+    log.append(1, vec![Frame::Write("a".to_string(), "one\r\nline".to_string())]).unwrap();
+    log.append(1, vec![Frame::Write("b".to_string(), "2".to_string()), Frame::Delete("a".to_string())])
+        .unwrap();
+    // a new leader's entries replace the last two.
+    let replacement = LogEntry {
+        term: 2,
+        index: 2,
+        command: Frame::Delete("b".to_string()),
+    };
+    log.append_entries(vec![replacement.clone()]).unwrap();
+    let hard_state = HardState {
+        term: 2,
+        voted_for: Some(3),
+        commit: 1,
+    };
+    log.set_hard_state(hard_state).unwrap();
+    drop(log);
+
+    // a crash in the middle of the next write leaves half an entry.
+    let mut file = std::fs::OpenOptions::new().append(true).open(dir.path().join("log")).unwrap();
+    file.write_all(b"2 3 WRITE 1:c").unwrap();
+
+    let mut log = Log::open(dir.path()).unwrap();
+    assert_eq!(log.hard_state(), hard_state);
+    assert_eq!(log.commit_index(), 1);
+    assert_eq!(log.last_index(), 2);
+    assert_eq!(log.entries()[0].command, Frame::Write("a".to_string(), "one\r\nline".to_string()));
+    assert_eq!(log.entries()[1], replacement);
+
+    log.append(2, vec![Frame::Delete("c".to_string())]).unwrap();
+    drop(log);
+    assert_eq!(Log::open(dir.path()).unwrap().last_index(), 3);
+}
+
+#[test]
+fn probe_sends_one_at_a_time() {
+    let mut progress = Progress::new(10, 4, 1 << 20);
+    assert_eq!(progress.state, ProgressState::Probe);
+    assert_eq!(progress.next_index, 11);
+
+    assert!(progress.can_send());
+    progress.sent(10, 100);
+    assert!(!progress.can_send());
+}
+
+#[test]
+fn replicate_pipelines_up_to_the_window() {
+    let mut progress = Progress::new(10, 3, 1 << 20);
+    progress.sent(10, 100);
+    assert!(progress.ack(10));
+    assert_eq!(progress.state, ProgressState::Replicate);
+
+    for last in [12, 14, 16] {
+        assert!(progress.can_send());
+        progress.sent(last, 100);
+    }
+    assert!(!progress.can_send());
+    assert_eq!(progress.next_index, 17);
+
+    // acking the second append frees both it and the first.
+    progress.ack(14);
+    assert_eq!(progress.inflight(), 1);
+    assert_eq!(progress.match_index, 14);
+    assert!(progress.can_send());
+
+    // stale acks don't move anything backwards.
+    assert!(!progress.ack(12));
+    assert_eq!(progress.match_index, 14);
+}
+
+#[test]
+fn rejection_falls_back_to_probe() {
+    let mut progress = Progress::new(10, 3, 1 << 20);
+    progress.sent(10, 100);
+    progress.ack(10);
+    progress.sent(12, 100);
+    progress.sent(14, 100);
+
+    progress.reject(None);
+    assert_eq!(progress.state, ProgressState::Probe);
+    assert_eq!(progress.inflight(), 0);
+    assert_eq!(progress.next_index, 11);
+}
+
+#[test]
+fn an_append_unanswered_for_a_heartbeat_has_stalled() {
+    let mut progress = Progress::new(10, 8, 1 << 20);
+    progress.sent(10, 100);
+    progress.ack(10);
+
+    progress.sent(11, 100);
+    assert!(!progress.stalled());
+    // a heartbeat later, still unanswered.
+    assert!(progress.stalled());
+
+    progress.ack(11);
+    progress.sent(12, 100);
+    assert!(!progress.stalled());
+    progress.ack(12);
+    progress.sent(13, 100);
+    assert!(!progress.stalled());
+    progress.ack(13);
+    assert!(!progress.stalled());
+}
+
+#[test]
+fn probe_walks_back_using_hint() {
+    let mut progress = Progress::new(20, 3, 1 << 20);
+    progress.sent(20, 100);
+    progress.reject(None);
+    assert_eq!(progress.next_index, 20);
+
+    progress.sent(20, 100);
+    progress.reject(Some(7));
+    assert_eq!(progress.next_index, 8);
+}
+
+#[test]
+fn replicate_stops_at_the_byte_budget() {
+    let mut progress = Progress::new(10, 8, 1000);
+    progress.sent(10, 100);
+    progress.ack(10);
+
+    progress.sent(11, 600);
+    assert!(progress.can_send());
+    progress.sent(12, 600);
+    assert_eq!(progress.inflight_bytes(), 1200);
+    assert!(!progress.can_send());
+
+    progress.ack(11);
+    assert_eq!(progress.inflight_bytes(), 600);
+    assert!(progress.can_send());
+}
@@ -0,0 +1,226 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use scow::client::ClientError;
+use scow::command::{FaultCommand, Frame};
+use scow::config::Config;
+use scow::consensus::{Message, ServerId};
+use scow::fault::{FaultTransport, Faults, LinkFaults};
+use scow::transport::{ChannelNetwork, ChannelTransport, Transport};
+
+mod common;
+use common::cluster::TestCluster;
+
+fn server(id: u32) -> ServerId {
+    ServerId {
+        id,
+        address: SocketAddr::from(([127, 0, 0, 1], 9000 + id as u16)),
+    }
+}
+
+fn vote(term: u64) -> Message {
+    Message::Vote { term, granted: true }
+}
+
+/// Nodes 1 and 2 on one network, both misbehaving as `faults` say.
+fn pair(faults: &Faults) -> (FaultTransport<ChannelTransport>, FaultTransport<ChannelTransport>) {
+    let network = ChannelNetwork::new();
+    let a = FaultTransport::new(network.join(server(1)), faults.clone());
+    let b = FaultTransport::new(network.join(server(2)), faults.clone());
+    (a, b)
+}
+
+/// What `to` receives within `wait`. `from` sends its delayed messages
+/// while we listen.
+async fn received(
+    from: &mut FaultTransport<ChannelTransport>,
+    to: &mut FaultTransport<ChannelTransport>,
+    wait: Duration,
+) -> Vec<Message> {
+    let mut messages = Vec::new();
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+    loop {
+        tokio::select! {
+            Some((_, message)) = to.recv() => messages.push(message),
+            _ = from.recv() => panic!("nothing was sent the other way"),
+            _ = &mut deadline => return messages,
+        }
+    }
+}
+
+#[tokio::test]
+async fn cuts_can_go_one_way() {
+    let faults = Faults::default();
+    let (mut a, mut b) = pair(&faults);
+    faults.cut(1, 2);
+
+    a.send(server(2), vote(1));
+    assert!(received(&mut a, &mut b, Duration::from_millis(50)).await.is_empty());
+    b.send(server(1), vote(2));
+    assert_eq!(received(&mut b, &mut a, Duration::from_millis(50)).await, vec![vote(2)]);
+
+    faults.heal();
+    a.send(server(2), vote(3));
+    assert_eq!(received(&mut a, &mut b, Duration::from_millis(50)).await, vec![vote(3)]);
+}
+
+#[tokio::test]
+async fn a_cut_holds_at_the_receiving_end_too() {
+    // the sender knows of no faults; the receiver's cut is enough.
+    let network = ChannelNetwork::new();
+    let faults = Faults::default();
+    let mut a = FaultTransport::new(network.join(server(1)), Faults::default());
+    let mut b = FaultTransport::new(network.join(server(2)), faults.clone());
+    faults.cut(1, 2);
+
+    a.send(server(2), vote(1));
+    assert!(received(&mut a, &mut b, Duration::from_millis(50)).await.is_empty());
+}
+
+#[tokio::test]
+async fn messages_can_be_dropped_duplicated_and_delayed() {
+    let faults = Faults::default();
+    let (mut a, mut b) = pair(&faults);
+
+    faults.set(None, None, LinkFaults { drop: 1.0, ..LinkFaults::default() });
+    a.send(server(2), vote(1));
+    assert!(received(&mut a, &mut b, Duration::from_millis(50)).await.is_empty());
+
+    faults.set(None, None, LinkFaults { duplicate: 1.0, ..LinkFaults::default() });
+    a.send(server(2), vote(2));
+    assert_eq!(received(&mut a, &mut b, Duration::from_millis(50)).await, vec![vote(2), vote(2)]);
+
+    let delay = LinkFaults {
+        delay: Duration::from_millis(200),
+        ..LinkFaults::default()
+    };
+    faults.set(None, None, delay);
+    a.send(server(2), vote(3));
+    assert!(received(&mut a, &mut b, Duration::from_millis(100)).await.is_empty());
+    assert_eq!(received(&mut a, &mut b, Duration::from_millis(200)).await, vec![vote(3)]);
+}
+
+#[tokio::test]
+async fn reordered_messages_arrive_after_the_next_one() {
+    let faults = Faults::default();
+    let (mut a, mut b) = pair(&faults);
+    faults.set(Some(1), Some(2), LinkFaults { reorder: 1.0, ..LinkFaults::default() });
+
+    a.send(server(2), vote(1));
+    a.send(server(2), vote(2));
+    assert_eq!(received(&mut a, &mut b, Duration::from_millis(50)).await, vec![vote(2), vote(1)]);
+}
+
+#[tokio::test]
+async fn the_most_specific_rule_wins() {
+    let faults = Faults::default();
+    let (mut a, mut b) = pair(&faults);
+    faults.set(None, None, LinkFaults { drop: 1.0, ..LinkFaults::default() });
+    faults.set(Some(1), None, LinkFaults::default());
+
+    a.send(server(2), vote(1));
+    assert_eq!(received(&mut a, &mut b, Duration::from_millis(50)).await, vec![vote(1)]);
+    b.send(server(1), vote(2));
+    assert!(received(&mut b, &mut a, Duration::from_millis(50)).await.is_empty());
+}
+
+#[test]
+fn fault_commands_survive_the_wire() {
+    let link = FaultCommand::Link {
+        from: Some(1),
+        to: None,
+        faults: LinkFaults {
+            drop: 0.25,
+            duplicate: 0.1,
+            reorder: 0.0,
+            delay: Duration::from_millis(30),
+            jitter: Duration::from_millis(5),
+        },
+    };
+    for command in [FaultCommand::Cut { from: 3, to: 1 }, link, FaultCommand::Heal] {
+        let wire = Frame::Fault(command.clone()).to_string();
+        assert_eq!(Frame::decode(wire.as_bytes()).unwrap(), Frame::Fault(command));
+    }
+    assert!(Frame::decode(b"FAULT LINK * * 2 0 0 0 0\r\n").is_err());
+}
+
+fn test_config() -> Config {
+    Config {
+        election_timeout_min_ms: 100,
+        election_timeout_max_ms: 200,
+        heartbeat_interval_ms: 20,
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn nodes_refuse_fault_commands_unless_enabled() {
+    let cluster = TestCluster::start(1).await;
+    let mut client = cluster.client(1).await;
+    assert_eq!(
+        client.fault(FaultCommand::Heal).await,
+        Err(ClientError::Rejected("fault injection is disabled".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn a_leader_that_cannot_send_is_replaced() {
+    let config = Config {
+        fault_injection: true,
+        ..test_config()
+    };
+    let cluster = TestCluster::start_with_config(3, config);
+    let old = cluster.wait_for_leader().await;
+
+    // the old leader still hears everyone, but nobody hears it.
+    let mut admin = cluster.client(old).await;
+    for id in cluster.ids().into_iter().filter(|&id| id != old) {
+        let cut = FaultCommand::Cut { from: old, to: id };
+        assert_eq!(admin.fault(cut).await.unwrap(), Frame::Success);
+    }
+    let new = cluster.wait_for_new_leader(old).await;
+    let mut client = cluster.client(new).await;
+    assert_eq!(client.write("key", "value").await.unwrap(), Frame::Success);
+
+    assert_eq!(admin.fault(FaultCommand::Heal).await.unwrap(), Frame::Success);
+    let leader = cluster.wait_for_convergence().await;
+    let mut client = cluster.client(leader).await;
+    assert_eq!(client.read("key").await.unwrap(), Frame::Value("value".to_string()));
+}
+
+#[tokio::test]
+async fn writes_get_through_a_lossy_network() {
+    let cluster = TestCluster::start(3).await;
+    let lossy = LinkFaults {
+        drop: 0.1,
+        duplicate: 0.1,
+        reorder: 0.1,
+        delay: Duration::from_millis(2),
+        jitter: Duration::from_millis(5),
+    };
+    cluster.faults().set(None, None, lossy);
+
+    for i in 0..20 {
+        let key = format!("key{}", i);
+        // a lost heartbeat too many can move the leader; follow it.
+        loop {
+            let leader = cluster.wait_for_leader().await;
+            let mut client = cluster.client(leader).await;
+            match client.write(&key, "value").await {
+                Ok(Frame::Success) => break,
+                // the same value again is harmless if the first one landed.
+                Err(ClientError::NotLeader | ClientError::OutcomeUnknown) => {}
+                other => panic!("unexpected answer {:?}", other),
+            }
+        }
+    }
+
+    cluster.heal();
+    let leader = cluster.wait_for_convergence().await;
+    let mut client = cluster.client(leader).await;
+    for i in 0..20 {
+        let value = client.read(&format!("key{}", i)).await.unwrap();
+        assert_eq!(value, Frame::Value("value".to_string()));
+    }
+}
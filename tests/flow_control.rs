@@ -0,0 +1,72 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use scow::client::{Client, ClientError};
+use scow::command::Frame;
+use scow::config::Config;
+use scow::server::{self, Listeners};
+
+#[tokio::test]
+async fn writes_over_the_limit_are_busy() {
+    let (addr, _) = start_server(Config {
+        max_inflight_proposals: 0,
+        ..Config::default()
+    })
+    .await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let busy = Err(ClientError::Busy(Duration::from_millis(50)));
+    assert_eq!(client.write("a", "1").await, busy);
+    assert_eq!(client.delete("a").await, busy);
+
+    // reads don't go through the log, so they're never turned away.
+    assert_eq!(client.read("a").await, Err(ClientError::NotFound));
+}
+
+#[tokio::test]
+async fn http_busy_is_503_with_retry_after() {
+    let (_, http) = start_server(Config {
+        max_inflight_proposals: 0,
+        ..Config::default()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(http).await.unwrap();
+    let request = "PUT /v1/kv/a HTTP/1.1\r\nConnection: close\r\nContent-Length: 1\r\n\r\n1";
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(response.contains("Retry-After: 1\r\n"));
+}
+
+#[tokio::test]
+async fn budget_is_returned_once_applied() {
+    let (addr, _) = start_server(Config {
+        max_inflight_proposals: 1,
+        max_uncommitted_bytes: 1,
+        ..Config::default()
+    })
+    .await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    // one at a time fits, however big, because each is applied before the
+    // next is proposed.
+    for i in 0..10 {
+        let value = "x".repeat(100 * i);
+        assert_eq!(client.write("k", &value).await.unwrap(), Frame::Success);
+    }
+}
+
+async fn start_server(config: Config) -> (SocketAddr, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addrs = (listener.local_addr().unwrap(), http.local_addr().unwrap());
+
+    let listeners = Listeners::new(listener).with_http(http);
+    tokio::spawn(async move { server::run_with_config(listeners, config, tokio::signal::ctrl_c()).await });
+    addrs
+}
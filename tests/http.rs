@@ -0,0 +1,132 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use scow::config::Config;
+use scow::server::{self, Listeners};
+
+#[tokio::test]
+async fn put_get_delete() {
+    let addr = start_server().await;
+
+    let put = request(addr, "PUT", "/v1/kv/greeting", "hello world").await;
+    assert!(put.starts_with("HTTP/1.1 200 OK"));
+    assert!(put.ends_with(r#"{"key":"greeting","value":"hello world"}"#));
+
+    let get = request(addr, "GET", "/v1/kv/greeting", "").await;
+    assert!(get.ends_with(r#"{"key":"greeting","value":"hello world"}"#));
+
+    let delete = request(addr, "DELETE", "/v1/kv/greeting", "").await;
+    assert!(delete.ends_with(r#"{"deleted":true}"#));
+
+    let missing = request(addr, "GET", "/v1/kv/greeting", "").await;
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[tokio::test]
+async fn percent_encoded_keys() {
+    let addr = start_server().await;
+
+    request(addr, "PUT", "/v1/kv/tenant%2Fa", "1").await;
+    let get = request(addr, "GET", "/v1/kv/tenant%2Fa", "").await;
+    assert!(get.ends_with(r#"{"key":"tenant/a","value":"1"}"#));
+}
+
+#[tokio::test]
+async fn status() {
+    let addr = start_server().await;
+
+    request(addr, "PUT", "/v1/kv/a", "1").await;
+    let status = request(addr, "GET", "/v1/status", "").await;
+    assert!(status.starts_with("HTTP/1.1 200 OK"));
+    assert!(status.contains(r#""role":"leader""#));
+    // the leader's empty entry for its term comes first in the log.
+    assert!(status.contains(r#""commit_index":2"#));
+    assert!(status.contains(r#""applied_index":2"#));
+    assert!(status.contains(r#""revision":1"#));
+    assert!(status.contains(r#""members":[{"id":0,"#));
+}
+
+#[tokio::test]
+async fn unknown_route() {
+    let addr = start_server().await;
+
+    let response = request(addr, "GET", "/v2/whatever", "").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    let response = request(addr, "POST", "/v1/kv/key", "").await;
+    assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed"));
+}
+
+#[tokio::test]
+async fn oversized_requests() {
+    let addr = start_server().await;
+
+    // a head one byte over the limit, still waiting for its blank line.
+    let mut head = String::from("GET /v1/kv/key HTTP/1.1\r\nX-Filler: ");
+    head.push_str(&"a".repeat(8 * 1024 + 1 - head.len()));
+    let response = send(addr, &head).await;
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"), "{}", response);
+
+    let response = send(addr, "PUT /v1/kv/key HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 413 Content Too Large"), "{}", response);
+}
+
+#[tokio::test]
+async fn chunked_bodies_are_refused() {
+    let addr = start_server().await;
+
+    // the chunk and the request behind it must not be taken as requests.
+    let response = send(
+        addr,
+        "PUT /v1/kv/key HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nGET /v1/status HTTP/1.1\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 501 Not Implemented"), "{}", response);
+    assert_eq!(response.matches("HTTP/1.1").count(), 1, "{}", response);
+
+    let missing = request(addr, "GET", "/v1/kv/key", "").await;
+    assert!(missing.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[tokio::test]
+async fn query_strings_are_not_part_of_the_key() {
+    let addr = start_server().await;
+
+    request(addr, "PUT", "/v1/kv/key?ttl=5", "1").await;
+    let get = request(addr, "GET", "/v1/kv/key", "").await;
+    assert!(get.ends_with(r#"{"key":"key","value":"1"}"#), "{}", get);
+    let get = request(addr, "GET", "/v1/kv/key?consistent", "").await;
+    assert!(get.ends_with(r#"{"key":"key","value":"1"}"#), "{}", get);
+}
+
+async fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: scow\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    );
+    send(addr, &request).await
+}
+
+async fn send(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = http.local_addr().unwrap();
+
+    let listeners = Listeners::new(listener).with_http(http);
+    tokio::spawn(async move {
+        server::run_with_config(listeners, Config::default(), tokio::signal::ctrl_c()).await
+    });
+    addr
+}
@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+use scow::client::{Client, ClientError};
+use scow::clock::ManualClock;
+use scow::command::Frame;
+use scow::config::Config;
+use scow::server::{self, Listeners};
+
+mod common;
+use common::cluster::TestCluster;
+
+#[tokio::test]
+async fn revoke_deletes_attached_keys() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let lease = grant(&mut client, 60).await;
+    client.write_with_lease("svc/a", "10.0.0.1", lease).await.unwrap();
+    client.write_with_lease("svc/b", "10.0.0.2", lease).await.unwrap();
+    client.write("svc/static", "10.0.0.3").await.unwrap();
+
+    assert_eq!(client.lease_revoke(lease).await.unwrap(), Frame::Bool(true));
+    assert_eq!(client.exists("svc/a").await.unwrap(), Frame::Bool(false));
+    assert_eq!(client.exists("svc/b").await.unwrap(), Frame::Bool(false));
+    assert_eq!(client.exists("svc/static").await.unwrap(), Frame::Bool(true));
+    assert_eq!(client.lease_keep_alive(lease).await, Err(ClientError::NotFound));
+}
+
+#[tokio::test]
+async fn expired_lease_is_revoked_by_leader() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let lease = grant(&mut client, 1).await;
+    client.write_with_lease("svc/a", "10.0.0.1", lease).await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(1600)).await;
+    assert_eq!(client.exists("svc/a").await.unwrap(), Frame::Bool(false));
+}
+
+#[tokio::test]
+async fn expiry_follows_the_servers_clock() {
+    let clock = ManualClock::new();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_clock = clock.clone();
+    tokio::spawn(async move {
+        let listeners = Listeners::new(listener);
+        server::run_with_clock(listeners, Config::default(), server_clock, std::future::pending::<()>()).await
+    });
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let lease = grant(&mut client, 1).await;
+    client.write_with_lease("svc/a", "10.0.0.1", lease).await.unwrap();
+
+    let advance = |steps| {
+        for _ in 0..steps {
+            clock.advance(Duration::from_millis(250));
+        }
+    };
+    advance(3);
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(client.exists("svc/a").await.unwrap(), Frame::Bool(true));
+
+    advance(3);
+    // the revoke is an ordinary write; the next read waits for it.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(client.exists("svc/a").await.unwrap(), Frame::Bool(false));
+}
+
+#[tokio::test]
+async fn keep_alive_extends_lease() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let lease = grant(&mut client, 1).await;
+    client.write_with_lease("svc/a", "10.0.0.1", lease).await.unwrap();
+
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        client.lease_keep_alive(lease).await.unwrap();
+    }
+    assert_eq!(client.exists("svc/a").await.unwrap(), Frame::Bool(true));
+}
+
+#[tokio::test]
+async fn only_the_leader_keeps_leases_alive() {
+    let cluster = TestCluster::start(3).await;
+    let leader = cluster.wait_for_leader().await;
+    let follower = cluster.ids().into_iter().find(|&id| id != leader).unwrap();
+    let mut on_leader = cluster.client(leader).await;
+    let mut on_follower = cluster.client(follower).await;
+
+    let kept = grant(&mut on_leader, 1).await;
+    on_leader.write_with_lease("svc/kept", "10.0.0.1", kept).await.unwrap();
+    let lapsed = grant(&mut on_leader, 1).await;
+    on_leader.write_with_lease("svc/lapsed", "10.0.0.2", lapsed).await.unwrap();
+
+    for _ in 0..4 {
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        on_leader.lease_keep_alive(kept).await.unwrap();
+        // the deadline lives with the leader: a follower can't push it
+        // out, so it mustn't say it did.
+        assert_eq!(on_follower.lease_keep_alive(lapsed).await, Err(ClientError::NotLeader));
+    }
+    assert_eq!(on_leader.exists("svc/kept").await.unwrap(), Frame::Bool(true));
+    assert_eq!(on_leader.exists("svc/lapsed").await.unwrap(), Frame::Bool(false));
+}
+
+#[tokio::test]
+async fn overwrite_detaches_key() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let lease = grant(&mut client, 60).await;
+    client.write_with_lease("svc/a", "10.0.0.1", lease).await.unwrap();
+    client.write("svc/a", "pinned").await.unwrap();
+    client.lease_revoke(lease).await.unwrap();
+
+    assert_eq!(client.exists("svc/a").await.unwrap(), Frame::Bool(true));
+}
+
+#[tokio::test]
+async fn unknown_lease() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let result = client.write_with_lease("svc/a", "x", 42).await;
+    assert_eq!(result, Err(ClientError::NotFound));
+}
+
+async fn grant(client: &mut Client, ttl: u64) -> u64 {
+    match client.lease_grant(ttl).await.unwrap() {
+        Frame::Lease(lease) => lease.id,
+        other => panic!("expected a lease, got {:?}", other),
+    }
+}
+
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move { server::run(listener, tokio::signal::ctrl_c()).await });
+    addr
+}
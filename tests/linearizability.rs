@@ -0,0 +1,103 @@
+// the linearizability checker has to catch what it claims to, and only
+// that.
+
+mod common;
+
+use common::linearizability::{check, History, Op, Outcome};
+
+fn write(value: &str) -> Op {
+    Op::Write(value.to_string())
+}
+
+fn value(value: &str) -> Outcome {
+    Outcome::Value(Some(value.to_string()))
+}
+
+#[test]
+fn sequential_history_is_linearizable() {
+    let history = History::new();
+    let op = history.invoke(1, "k", write("a"));
+    history.complete(op, Outcome::Written);
+    let op = history.invoke(2, "k", Op::Read);
+    history.complete(op, value("a"));
+    let cas = Op::Cas {
+        expected: Some("a".to_string()),
+        new: "b".to_string(),
+    };
+    let op = history.invoke(1, "k", cas);
+    history.complete(op, Outcome::Cas(true));
+    history.check().unwrap();
+}
+
+#[test]
+fn overlapping_operations_may_take_effect_in_either_order() {
+    let history = History::new();
+    let a = history.invoke(1, "k", write("a"));
+    let b = history.invoke(2, "k", write("b"));
+    let read = history.invoke(3, "k", Op::Read);
+    history.complete(a, Outcome::Written);
+    history.complete(b, Outcome::Written);
+    // b went first, then a: still fine.
+    history.complete(read, value("b"));
+    let op = history.invoke(3, "k", Op::Read);
+    history.complete(op, value("a"));
+    history.check().unwrap();
+}
+
+#[test]
+fn stale_read_is_caught_and_minimized() {
+    let history = History::new();
+    let op = history.invoke(1, "other", write("x"));
+    history.complete(op, Outcome::Written);
+    let op = history.invoke(1, "k", write("a"));
+    history.complete(op, Outcome::Written);
+    let op = history.invoke(2, "k", Op::Read);
+    history.complete(op, value("a"));
+    let op = history.invoke(1, "k", write("b"));
+    history.complete(op, Outcome::Written);
+    // b was acknowledged before this read began.
+    let op = history.invoke(2, "k", Op::Read);
+    history.complete(op, value("a"));
+
+    let violation = history.check().unwrap_err();
+    assert_eq!(violation.key, "k");
+    let ops: Vec<(Op, Outcome)> = violation.ops.iter().map(|o| (o.op.clone(), o.outcome.clone())).collect();
+    assert_eq!(ops, vec![(write("a"), Outcome::Written), (write("b"), Outcome::Written), (Op::Read, value("a"))]);
+    assert!(violation.to_string().contains("not linearizable"), "{}", violation);
+}
+
+#[test]
+fn unknown_writes_may_land_late_or_never() {
+    let history = History::new();
+    let lost = history.invoke(1, "k", write("a"));
+    history.complete(lost, Outcome::Unknown);
+    let op = history.invoke(2, "k", Op::Read);
+    history.complete(op, Outcome::Value(None));
+    let op = history.invoke(2, "k", Op::Read);
+    history.complete(op, value("a"));
+    history.check().unwrap();
+
+    // but an acknowledged write can't be undone by one that timed out
+    // before it was even sent.
+    let op = history.invoke(3, "k", write("b"));
+    history.complete(op, Outcome::Written);
+    let op = history.invoke(2, "k", Op::Read);
+    history.complete(op, value("a"));
+    let ops = history.operations();
+    let err = check(&ops).unwrap_err();
+    assert!(err.ops.iter().any(|o| o.op == write("b")), "{}", err);
+}
+
+#[test]
+fn cas_that_swapped_from_the_wrong_value_is_caught() {
+    let history = History::new();
+    let op = history.invoke(1, "k", write("a"));
+    history.complete(op, Outcome::Written);
+    let cas = Op::Cas {
+        expected: None,
+        new: "b".to_string(),
+    };
+    let op = history.invoke(2, "k", cas);
+    history.complete(op, Outcome::Cas(true));
+    history.check().unwrap_err();
+}
@@ -0,0 +1,122 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+
+use scow::command::{Frame, Hello};
+use scow::config::{Config, Peer};
+use scow::connection::Connection;
+use scow::consensus::ServerId;
+use scow::peer::{Backoff, PeerPool};
+use scow::server::{self, Listeners};
+
+const BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(10),
+    max: Duration::from_millis(100),
+};
+
+#[tokio::test]
+async fn pool_connects_and_calls() {
+    let peer = start_node().await;
+    let id = ServerId { id: 1, address: peer };
+    let pool = PeerPool::new(Hello::node(2, "scow"), [id], BACKOFF, Duration::from_secs(1));
+
+    wait_connected(&pool, &id).await;
+    assert_eq!(pool.call(&id, Frame::Ping).await.unwrap(), Frame::Success);
+
+    // client commands don't belong on the peer port.
+    let reply = pool.call(&id, Frame::Read("a".to_string())).await.unwrap();
+    assert!(matches!(reply, Frame::Error(_)));
+}
+
+#[tokio::test]
+async fn peer_port_refuses_clients() {
+    let peer = start_node().await;
+    let mut connection = Connection::new(TcpStream::connect(peer).await.unwrap());
+
+    let err = connection.handshake(&Hello::client()).await.unwrap_err();
+    assert!(err.to_string().contains("the peer port is for cluster members"), "{}", err);
+}
+
+#[tokio::test]
+async fn peer_port_refuses_strangers() {
+    let peer = start_node().await;
+    let strangers = [
+        (Hello::node(9, "scow"), "unknown node id 9"),
+        (Hello::node(2, "some-other-cluster"), "cluster id mismatch"),
+        (
+            Hello {
+                cluster_id: None,
+                ..Hello::node(2, "scow")
+            },
+            "the peer port is for cluster members",
+        ),
+    ];
+    for (hello, refusal) in strangers {
+        let mut connection = Connection::new(TcpStream::connect(peer).await.unwrap());
+        let err = connection.handshake(&hello).await.unwrap_err();
+        assert!(err.to_string().contains(refusal), "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn pool_redials_with_backoff() {
+    // find a free port, then leave it closed for a while.
+    let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let id = ServerId { id: 1, address };
+    let pool = PeerPool::new(Hello::node(2, "scow"), [id], BACKOFF, Duration::from_secs(1));
+
+    let err = pool.call(&id, Frame::Ping).await.unwrap_err();
+    assert!(err.to_string().contains("not connected"), "{}", err);
+    assert!(!pool.is_connected(&id));
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    start_node_on(TcpListener::bind(address).await.unwrap()).await;
+
+    wait_connected(&pool, &id).await;
+    assert_eq!(pool.call(&id, Frame::Ping).await.unwrap(), Frame::Success);
+}
+
+#[tokio::test]
+async fn unknown_peers_are_errors() {
+    let pool = PeerPool::new(Hello::node(2, "scow"), [], BACKOFF, Duration::from_secs(1));
+    let id = ServerId {
+        id: 9,
+        address: "127.0.0.1:1".parse().unwrap(),
+    };
+    assert!(pool.call(&id, Frame::Ping).await.is_err());
+}
+
+async fn wait_connected(pool: &PeerPool, id: &ServerId) {
+    for _ in 0..200 {
+        if pool.is_connected(id) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("never connected to peer {}", id.id);
+}
+
+async fn start_node() -> SocketAddr {
+    start_node_on(TcpListener::bind("127.0.0.1:0").await.unwrap()).await
+}
+
+/// Starts node 1, which knows of a node 2, with `peer` as its peer port,
+/// and returns its address.
+async fn start_node_on(peer: TcpListener) -> SocketAddr {
+    let client = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = peer.local_addr().unwrap();
+    let listeners = Listeners::new(client).with_peer(peer);
+    let config = Config {
+        node_id: 1,
+        peers: vec![Peer {
+            id: 2,
+            address: "127.0.0.1:1".parse().unwrap(),
+            client_addr: "127.0.0.1:1".parse().unwrap(),
+            resp_addr: None,
+            http_addr: None,
+        }],
+        ..Config::default()
+    };
+    tokio::spawn(async move { server::run_with_config(listeners, config, tokio::signal::ctrl_c()).await });
+    addr
+}
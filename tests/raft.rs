@@ -0,0 +1,391 @@
+use std::collections::{BTreeMap, HashSet};
+
+use scow::command::Frame;
+use scow::consensus::{Message, ServerState};
+use scow::log::LogEntry;
+use scow::raft::{HardState, RaftConfig, RaftNode, ReadState};
+
+/// A cluster of cores wired together by hand: messages only move when a
+/// test delivers them, and cut links drop them.
+struct Cluster {
+    nodes: BTreeMap<u32, RaftNode>,
+    queue: Vec<(u32, u32, Message)>,
+    cut: HashSet<(u32, u32)>,
+    /// Reads each node has confirmed, in order.
+    reads: Vec<(u32, ReadState)>,
+}
+
+impl Cluster {
+    fn new(size: u32) -> Cluster {
+        let nodes = (1..=size)
+            .map(|id| {
+                let peers = (1..=size).filter(|&p| p != id).collect();
+                (id, RaftNode::new(RaftConfig::new(id, peers)))
+            })
+            .collect();
+        Cluster {
+            nodes,
+            queue: Vec::new(),
+            cut: HashSet::new(),
+            reads: Vec::new(),
+        }
+    }
+
+    fn node(&mut self, id: u32) -> &mut RaftNode {
+        self.nodes.get_mut(&id).unwrap()
+    }
+
+    /// Takes every node's ready, queueing the messages it wants sent.
+    fn collect(&mut self) {
+        for (&id, node) in self.nodes.iter_mut() {
+            let ready = node.ready();
+            for (to, message) in ready.messages {
+                self.queue.push((id, to, message));
+            }
+            self.reads.extend(ready.reads.into_iter().map(|read| (id, read)));
+        }
+    }
+
+    /// Delivers messages until nobody has anything more to say.
+    fn settle(&mut self) {
+        self.collect();
+        while !self.queue.is_empty() {
+            for (from, to, message) in std::mem::take(&mut self.queue) {
+                if !self.cut.contains(&(from, to)) && !self.cut.contains(&(to, from)) {
+                    self.node(to).step(from, message);
+                }
+            }
+            self.collect();
+        }
+    }
+
+    fn elect(&mut self, id: u32) {
+        self.node(id).campaign();
+        self.settle();
+        assert_eq!(self.node(id).role(), ServerState::Leader);
+    }
+
+    fn isolate(&mut self, id: u32) {
+        for &other in self.nodes.keys() {
+            self.cut.insert((id, other));
+        }
+    }
+
+    fn heal(&mut self) {
+        self.cut.clear();
+    }
+
+    fn tick_all(&mut self, ticks: usize) {
+        for _ in 0..ticks {
+            for node in self.nodes.values_mut() {
+                node.tick();
+            }
+            self.settle();
+        }
+    }
+
+    fn leaders(&self) -> Vec<u32> {
+        self.nodes
+            .iter()
+            .filter(|(_, n)| n.role() == ServerState::Leader)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+}
+
+fn write(key: &str) -> Frame {
+    Frame::Write(key.to_string(), "v".to_string())
+}
+
+fn commands(node: &RaftNode) -> Vec<Frame> {
+    node.log().iter().map(|e| e.command.clone()).collect()
+}
+
+#[test]
+fn a_single_node_elects_itself_and_commits_alone() {
+    let mut node = RaftNode::new(RaftConfig::new(1, vec![]));
+    node.campaign();
+    assert_eq!(node.role(), ServerState::Leader);
+    assert_eq!(node.propose(vec![write("a")]), Some(2));
+
+    let ready = node.ready();
+    assert_eq!(ready.hard_state.unwrap().commit, 2);
+    assert_eq!(ready.entries.len(), 2);
+    assert_eq!(ready.committed.len(), 2);
+    assert!(ready.messages.is_empty());
+    assert!(!node.has_ready());
+}
+
+#[test]
+fn elections_time_out_and_pick_one_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.tick_all(25);
+
+    let leaders = cluster.leaders();
+    assert_eq!(leaders.len(), 1);
+    let leader = leaders[0];
+    for node in cluster.nodes.values() {
+        assert_eq!(node.leader(), Some(leader));
+        assert_eq!(node.term(), cluster.nodes[&leader].term());
+    }
+}
+
+#[test]
+fn proposals_replicate_and_commit_everywhere() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    assert_eq!(cluster.node(2).propose(vec![write("a")]), None);
+
+    cluster.node(1).propose(vec![write("a"), write("b")]);
+    cluster.settle();
+    // followers learn the commit index with the next append.
+    cluster.tick_all(2);
+
+    let expected = vec![Frame::Ping, write("a"), write("b")];
+    for node in cluster.nodes.values() {
+        assert_eq!(commands(node), expected);
+        assert_eq!(node.commit_index(), 3);
+    }
+}
+
+#[test]
+fn a_minority_cannot_commit() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    cluster.isolate(1);
+
+    cluster.node(1).propose(vec![write("a")]);
+    cluster.settle();
+    assert_eq!(cluster.node(1).last_index(), 2);
+    assert_eq!(cluster.node(1).commit_index(), 1);
+}
+
+#[test]
+fn reads_wait_for_a_quorum_to_confirm_the_leader() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    cluster.node(1).propose(vec![write("a")]);
+    cluster.settle();
+    assert!(!cluster.node(2).read_index(7));
+
+    cluster.cut.insert((1, 2));
+    cluster.cut.insert((1, 3));
+    assert!(cluster.node(1).read_index(7));
+    cluster.settle();
+    assert!(cluster.reads.is_empty());
+
+    // one follower hearing the next heartbeat is enough.
+    cluster.cut.remove(&(1, 2));
+    cluster.tick_all(2);
+    assert_eq!(cluster.reads, vec![(1, ReadState { id: 7, index: 2 })]);
+}
+
+#[test]
+fn a_deposed_leader_never_confirms_a_read() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    cluster.isolate(1);
+    cluster.elect(2);
+    cluster.node(2).propose(vec![write("a")]);
+    cluster.settle();
+
+    // node 1 still thinks it leads, but nobody backs it.
+    assert!(cluster.node(1).read_index(7));
+    cluster.settle();
+    cluster.heal();
+    cluster.tick_all(2);
+    assert_eq!(cluster.node(1).role(), ServerState::Follower);
+    assert!(cluster.reads.is_empty());
+}
+
+#[test]
+fn votes_go_only_to_candidates_with_up_to_date_logs() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    cluster.node(1).propose(vec![write("a")]);
+    cluster.settle();
+
+    // node 3 misses everything since, then campaigns with a stale log.
+    cluster.isolate(3);
+    cluster.node(1).propose(vec![write("b")]);
+    cluster.settle();
+    cluster.heal();
+    cluster.node(3).campaign();
+    cluster.settle();
+
+    assert_ne!(cluster.node(3).role(), ServerState::Leader);
+    assert!(cluster.node(3).log().len() < cluster.node(2).log().len());
+}
+
+#[test]
+fn a_new_leader_overwrites_uncommitted_entries() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    cluster.settle();
+
+    // the old leader appends alone while the others move on without it.
+    cluster.isolate(1);
+    cluster.node(1).propose(vec![write("lost")]);
+    cluster.settle();
+    cluster.elect(2);
+    cluster.node(2).propose(vec![write("kept")]);
+    cluster.settle();
+
+    cluster.heal();
+    cluster.tick_all(3);
+    assert_eq!(cluster.leaders(), vec![2]);
+    assert_eq!(cluster.node(1).role(), ServerState::Follower);
+    for node in cluster.nodes.values() {
+        assert_eq!(commands(node), vec![Frame::Ping, Frame::Ping, write("kept")]);
+    }
+}
+
+#[test]
+fn lost_appends_are_sent_again() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+
+    // node 3 alone gets the entry, which is enough to commit it.
+    cluster.cut.insert((1, 2));
+    cluster.node(1).propose(vec![write("a")]);
+    cluster.settle();
+    cluster.heal();
+    // a quiet leader: nothing more is proposed to fill the window. One
+    // heartbeat notices the append, the next gives up on it.
+    cluster.tick_all(5);
+
+    assert_eq!(commands(cluster.node(2)), vec![Frame::Ping, write("a")]);
+    assert_eq!(cluster.node(2).commit_index(), 2);
+}
+
+#[test]
+fn a_stale_leader_steps_down_on_a_higher_term() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    cluster.isolate(1);
+    cluster.elect(2);
+    cluster.heal();
+
+    // node 1 still thinks it leads until it hears otherwise.
+    assert_eq!(cluster.node(1).role(), ServerState::Leader);
+    cluster.tick_all(2);
+    assert_eq!(cluster.node(1).role(), ServerState::Follower);
+    assert_eq!(cluster.node(1).leader(), Some(2));
+}
+
+#[test]
+fn leadership_goes_to_the_most_caught_up_follower() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    let term = cluster.node(1).term();
+
+    // node 3 misses the last entry, so node 2 is the one to take over.
+    cluster.cut.insert((1, 3));
+    cluster.node(1).propose(vec![write("a")]);
+    cluster.settle();
+    cluster.heal();
+
+    assert_eq!(cluster.node(1).transfer_leadership(), Some(2));
+    cluster.settle();
+    assert_eq!(cluster.leaders(), vec![2]);
+    assert_eq!(cluster.node(2).term(), term + 1);
+    assert_eq!(cluster.node(1).leader(), Some(2));
+    assert_eq!(commands(cluster.node(3)), vec![Frame::Ping, write("a"), Frame::Ping]);
+}
+
+#[test]
+fn only_a_leader_hands_leadership_over() {
+    let mut cluster = Cluster::new(3);
+    cluster.elect(1);
+    assert_eq!(cluster.node(2).transfer_leadership(), None);
+
+    // nor does a follower take orders from anyone but its leader.
+    let term = cluster.node(1).term();
+    cluster.node(2).step(3, Message::TimeoutNow { term });
+    assert_eq!(cluster.node(2).role(), ServerState::Follower);
+}
+
+#[test]
+fn a_vote_is_persisted_before_it_is_sent() {
+    let mut node = RaftNode::new(RaftConfig::new(2, vec![1, 3]));
+    node.step(
+        1,
+        Message::RequestVote {
+            term: 1,
+            last_log_index: 0,
+            last_log_term: 0,
+        },
+    );
+
+    let ready = node.ready();
+    assert_eq!(
+        ready.hard_state,
+        Some(HardState {
+            term: 1,
+            voted_for: Some(1),
+            commit: 0
+        })
+    );
+    assert!(matches!(ready.messages[..], [(1, Message::Vote { granted: true, .. })]));
+
+    // one vote per term.
+    node.step(
+        3,
+        Message::RequestVote {
+            term: 1,
+            last_log_index: 0,
+            last_log_term: 0,
+        },
+    );
+    assert!(matches!(node.ready().messages[..], [(3, Message::Vote { granted: false, .. })]));
+}
+
+#[test]
+fn a_restored_node_replays_committed_entries() {
+    let mut node = RaftNode::new(RaftConfig::new(1, vec![]));
+    node.campaign();
+    node.propose(vec![write("a")]);
+    let ready = node.ready();
+
+    let mut restored = RaftNode::restore(RaftConfig::new(1, vec![]), ready.hard_state.unwrap(), ready.entries);
+    assert_eq!(restored.role(), ServerState::Follower);
+    assert_eq!(restored.term(), 1);
+    let replay: Vec<Frame> = restored.ready().committed.into_iter().map(|e| e.command).collect();
+    assert_eq!(replay, vec![Frame::Ping, write("a")]);
+}
+
+#[test]
+fn appends_survive_the_wire() {
+    let entries = vec![
+        LogEntry {
+            term: 2,
+            index: 5,
+            command: Frame::Ping,
+        },
+        LogEntry {
+            term: 3,
+            index: 6,
+            command: Frame::Write("a".to_string(), "b 3:x y".to_string()),
+        },
+    ];
+    for message in [
+        Message::AppendEntries {
+            term: 3,
+            prev_log_index: 4,
+            prev_log_term: 2,
+            entries,
+            leader_commit: 4,
+        },
+        Message::AppendResponse {
+            term: 3,
+            success: false,
+            match_index: 2,
+        },
+        Message::TimeoutNow { term: 3 },
+        Message::ReadIndex { term: 3, seq: 9 },
+        Message::ReadIndexResponse { term: 3, seq: 9 },
+    ] {
+        let wire = Frame::Raft(message.clone()).to_string();
+        assert_eq!(Frame::decode(wire.as_bytes()).unwrap(), Frame::Raft(message));
+    }
+}
@@ -0,0 +1,114 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use scow::client::Client;
+use scow::command::Frame;
+use scow::config::Config;
+use scow::server::{self, Listeners};
+
+#[tokio::test]
+async fn ping() {
+    let (_, resp) = start_server().await;
+    let mut stream = TcpStream::connect(resp).await.unwrap();
+
+    assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+    assert_eq!(send(&mut stream, &["PING", "hi"]).await, "$2\r\nhi\r\n");
+}
+
+#[tokio::test]
+async fn set_get_del_exists() {
+    let (_, resp) = start_server().await;
+    let mut stream = TcpStream::connect(resp).await.unwrap();
+
+    assert_eq!(send(&mut stream, &["SET", "key", "some value"]).await, "+OK\r\n");
+    assert_eq!(send(&mut stream, &["GET", "key"]).await, "$10\r\nsome value\r\n");
+    assert_eq!(send(&mut stream, &["EXISTS", "key", "nope"]).await, ":1\r\n");
+    assert_eq!(send(&mut stream, &["DEL", "key", "nope"]).await, ":1\r\n");
+    assert_eq!(send(&mut stream, &["GET", "key"]).await, "$-1\r\n");
+}
+
+#[tokio::test]
+async fn shares_state_with_native_protocol() {
+    let (native, resp) = start_server().await;
+    let mut stream = TcpStream::connect(resp).await.unwrap();
+    let mut client = Client::connect(native).await.unwrap();
+
+    client.write("shared", "from native").await.unwrap();
+    assert_eq!(send(&mut stream, &["GET", "shared"]).await, "$11\r\nfrom native\r\n");
+
+    send(&mut stream, &["SET", "shared", "from resp"]).await;
+    let read = client.read("shared").await.unwrap();
+    assert_eq!(read, Frame::Value("from resp".to_string()));
+}
+
+#[tokio::test]
+async fn errors() {
+    let (_, resp) = start_server().await;
+    let mut stream = TcpStream::connect(resp).await.unwrap();
+
+    assert_eq!(
+        send(&mut stream, &["GET"]).await,
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        send(&mut stream, &["FLUSHALL"]).await,
+        "-ERR unknown command 'flushall'\r\n"
+    );
+    // a line break in the message can't smuggle in a reply of its own.
+    assert_eq!(
+        send(&mut stream, &["NO\r\n+OK"]).await,
+        "-ERR unknown command 'no  +ok'\r\n"
+    );
+    assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+}
+
+#[tokio::test]
+async fn oversized_lengths_are_protocol_errors() {
+    let (_, resp) = start_server().await;
+
+    for cmd in ["*100000000000\r\n", "*1\r\n$18446744073709551615\r\n"] {
+        let mut stream = TcpStream::connect(resp).await.unwrap();
+        stream.write_all(cmd.as_bytes()).await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert!(reply.starts_with("-ERR protocol error, invalid"), "{:?}", reply);
+    }
+
+    // and the server is still there.
+    let mut stream = TcpStream::connect(resp).await.unwrap();
+    assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+}
+
+#[tokio::test]
+async fn info_reports_role() {
+    let (_, resp) = start_server().await;
+    let mut stream = TcpStream::connect(resp).await.unwrap();
+
+    let info = send(&mut stream, &["INFO"]).await;
+    assert!(info.contains("role:master"));
+}
+
+async fn send(stream: &mut TcpStream, args: &[&str]) -> String {
+    let mut cmd = format!("*{}\r\n", args.len());
+    for arg in args {
+        cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    stream.write_all(cmd.as_bytes()).await.unwrap();
+
+    let mut buf = vec![0; 4096];
+    let n = stream.read(&mut buf).await.unwrap();
+    String::from_utf8(buf[..n].to_vec()).unwrap()
+}
+
+async fn start_server() -> (SocketAddr, SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let resp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addrs = (listener.local_addr().unwrap(), resp.local_addr().unwrap());
+
+    let listeners = Listeners::new(listener).with_resp(resp);
+    tokio::spawn(async move {
+        server::run_with_config(listeners, Config::default(), tokio::signal::ctrl_c()).await
+    });
+    addrs
+}
@@ -1,8 +1,17 @@
 use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
 
-use scow::command::Frame;
-use scow::{client::Client, server};
+use scow::command::{CasResult, Frame, Hello, KeyValue, Page, PROTOCOL_VERSION};
+use scow::connection::Connection;
+use scow::client::{Client, ClientError, WriteBatch};
+use scow::config::Config;
+use scow::server::{self, Listeners};
+
+mod common;
+use common::data_dir::DataDir;
+use common::linearizability::{History, RecordingClient};
 
 #[tokio::test]
 async fn set_then_get() {
@@ -39,8 +48,434 @@ async fn unknown_key() {
     let addr = start_server().await;
     let mut client = Client::connect(addr).await.unwrap();
 
-    let set_result = client.read("unknown").await.unwrap();
-    assert_eq!(set_result, Frame::Error("Key not found.".to_string()));
+    let set_result = client.read("unknown").await;
+    assert_eq!(set_result, Err(ClientError::NotFound));
+}
+
+#[tokio::test]
+async fn delete_and_exists() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    client.write("doomed", "value").await.unwrap();
+    assert_eq!(client.exists("doomed").await.unwrap(), Frame::Bool(true));
+    assert_eq!(client.delete("doomed").await.unwrap(), Frame::Bool(true));
+    assert_eq!(client.exists("doomed").await.unwrap(), Frame::Bool(false));
+    assert_eq!(client.delete("doomed").await.unwrap(), Frame::Bool(false));
+}
+
+#[tokio::test]
+async fn compare_and_swap() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let created = client.compare_and_swap("lock", None, "owner a").await.unwrap();
+    assert_eq!(
+        created,
+        Frame::CasResult(CasResult {
+            succeeded: true,
+            current: Some("owner a".to_string()),
+        })
+    );
+
+    let stolen = client.compare_and_swap("lock", None, "owner b").await.unwrap();
+    assert_eq!(
+        stolen,
+        Frame::CasResult(CasResult {
+            succeeded: false,
+            current: Some("owner a".to_string()),
+        })
+    );
+
+    let handed_over = client
+        .compare_and_swap("lock", Some("owner a"), "owner b")
+        .await
+        .unwrap();
+    assert_eq!(
+        handed_over,
+        Frame::CasResult(CasResult {
+            succeeded: true,
+            current: Some("owner b".to_string()),
+        })
+    );
+}
+
+#[tokio::test]
+async fn compare_and_swap_missing_key() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let result = client.compare_and_swap("nothing", Some("x"), "y").await.unwrap();
+    assert_eq!(
+        result,
+        Frame::CasResult(CasResult {
+            succeeded: false,
+            current: None,
+        })
+    );
+}
+
+#[tokio::test]
+async fn scan_in_pages() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    for key in ["d", "a", "c", "e", "b"] {
+        client.write(key, &format!("value {}", key)).await.unwrap();
+    }
+
+    let first = client.scan("b", Some("e"), 2).await.unwrap();
+    assert_eq!(
+        first,
+        Frame::Page(Page {
+            entries: vec![
+                ("b".to_string(), "value b".to_string()),
+                ("c".to_string(), "value c".to_string()),
+            ],
+            next: Some("d".to_string()),
+        })
+    );
+
+    let second = client.scan("d", Some("e"), 2).await.unwrap();
+    assert_eq!(
+        second,
+        Frame::Page(Page {
+            entries: vec![("d".to_string(), "value d".to_string())],
+            next: None,
+        })
+    );
+}
+
+#[tokio::test]
+async fn prefix_in_pages() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    for key in ["tenant1/a", "tenant1/b", "tenant1/c", "tenant2/a", "tenant0/z"] {
+        client.write(key, "x").await.unwrap();
+    }
+
+    let mut keys = vec![];
+    let mut from: Option<String> = None;
+    loop {
+        let page = match client.prefix("tenant1/", from.as_deref(), 2).await.unwrap() {
+            Frame::Page(page) => page,
+            other => panic!("expected a page, got {:?}", other),
+        };
+        keys.extend(page.entries.into_iter().map(|(k, _)| k));
+        match page.next {
+            Some(next) => from = Some(next),
+            None => break,
+        }
+    }
+    assert_eq!(keys, vec!["tenant1/a", "tenant1/b", "tenant1/c"]);
+}
+
+#[tokio::test]
+async fn revisions() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    client.write("config", "v1").await.unwrap();
+    client.write("other", "x").await.unwrap();
+    client.write("config", "v2").await.unwrap();
+
+    let current = client.get("config").await.unwrap();
+    assert_eq!(
+        current,
+        Frame::Entry(KeyValue {
+            key: "config".to_string(),
+            value: "v2".to_string(),
+            create_revision: 1,
+            mod_revision: 3,
+            version: 2,
+        })
+    );
+
+    let old = client.get_at("config", 2).await.unwrap();
+    assert_eq!(
+        old,
+        Frame::Entry(KeyValue {
+            key: "config".to_string(),
+            value: "v1".to_string(),
+            create_revision: 1,
+            mod_revision: 1,
+            version: 1,
+        })
+    );
+
+    client.delete("config").await.unwrap();
+    assert_eq!(client.get("config").await, Err(ClientError::NotFound));
+    assert_eq!(
+        client.get_at("config", 3).await.unwrap(),
+        Frame::Entry(KeyValue {
+            key: "config".to_string(),
+            value: "v2".to_string(),
+            create_revision: 1,
+            mod_revision: 3,
+            version: 2,
+        })
+    );
+}
+
+#[tokio::test]
+async fn compacted_revisions_are_gone() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    client.write("key", "a").await.unwrap();
+    client.write("key", "b").await.unwrap();
+    client.write("key", "c").await.unwrap();
+
+    assert_eq!(client.compact(2).await.unwrap(), Frame::Success);
+    assert_eq!(
+        client.get_at("key", 1).await,
+        Err(ClientError::Rejected("revision 1 has been compacted".to_string()))
+    );
+    match client.get_at("key", 2).await.unwrap() {
+        Frame::Entry(kv) => assert_eq!(kv.value, "b"),
+        other => panic!("expected an entry, got {:?}", other),
+    }
+    assert_eq!(
+        client.get_at("key", 10).await,
+        Err(ClientError::Rejected("revision 10 is a future revision".to_string()))
+    );
+}
+
+#[tokio::test]
+async fn write_batch() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+
+    let mut batch = WriteBatch::new().put("first", "one value");
+    for i in 0..500 {
+        batch.push(&format!("bulk/{:04}", i), &format!("value {}", i));
+    }
+    assert_eq!(batch.len(), 501);
+    assert_eq!(client.write_batch(batch).await.unwrap(), Frame::Success);
+
+    assert_eq!(
+        client.read("first").await.unwrap(),
+        Frame::Value("one value".to_string())
+    );
+    // the whole batch is one entry, so every key shares a revision.
+    match client.get("bulk/0499").await.unwrap() {
+        Frame::Entry(kv) => {
+            assert_eq!(kv.value, "value 499");
+            assert_eq!(kv.mod_revision, 1);
+        }
+        other => panic!("expected an entry, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn concurrent_writers_get_distinct_revisions() {
+    let addr = start_server().await;
+
+    let mut tasks = vec![];
+    for i in 0..20 {
+        tasks.push(tokio::spawn(async move {
+            let mut client = Client::connect(addr).await.unwrap();
+            let key = format!("writer/{}", i);
+            client.write(&key, "x").await.unwrap();
+            match client.get(&key).await.unwrap() {
+                Frame::Entry(kv) => kv.mod_revision,
+                other => panic!("expected an entry, got {:?}", other),
+            }
+        }));
+    }
+
+    let mut revisions = vec![];
+    for task in tasks {
+        revisions.push(task.await.unwrap());
+    }
+    revisions.sort();
+    assert_eq!(revisions, (1..=20).collect::<Vec<u64>>());
+}
+
+#[tokio::test]
+async fn handshake_returns_server_identity() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    let remote = connection.handshake(&Hello::client()).await.unwrap();
+    assert_eq!(remote.version, PROTOCOL_VERSION);
+    assert_eq!(remote.node_id, Some(0));
+    assert_eq!(remote.cluster_id, Some("scow".to_string()));
+}
+
+#[tokio::test]
+async fn handshake_rejects_other_cluster() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    let err = connection
+        .handshake(&Hello::node(7, "some-other-cluster"))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("cluster id mismatch"));
+}
+
+#[tokio::test]
+async fn handshake_rejects_old_version() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    let hello = Hello {
+        version: 0,
+        ..Hello::client()
+    };
+    let err = connection.handshake(&hello).await.unwrap_err();
+    assert!(err.to_string().contains("incompatible protocol version"));
+}
+
+#[tokio::test]
+async fn handshake_rejects_newer_version() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    let hello = Hello {
+        version: PROTOCOL_VERSION + 1,
+        ..Hello::client()
+    };
+    let err = connection.handshake(&hello).await.unwrap_err();
+    assert!(err.to_string().contains("incompatible protocol version"));
+}
+
+#[tokio::test]
+async fn response_frames_from_a_client_are_answered_with_errors() {
+    let addr = start_server().await;
+    let mut connection = Connection::new(TcpStream::connect(addr).await.unwrap());
+    connection.handshake(&Hello::client()).await.unwrap();
+
+    for frame in [Frame::Error("x".to_string()), Frame::Value("x".to_string()), Frame::Busy(10)] {
+        connection.write(&frame.to_string()).await.unwrap();
+        let answer = connection.read_frame().await.unwrap();
+        assert_eq!(answer, Some(Frame::Error("unexpected response frame".to_string())));
+    }
+    // the handler is still there.
+    connection.write(&Frame::Ping.to_string()).await.unwrap();
+    assert_eq!(connection.read_frame().await.unwrap(), Some(Frame::Success));
+}
+
+#[test]
+fn retired_vote_frames_are_not_commands() {
+    let err = Frame::decode(b"REQVOTE 1 2 3\r\n").unwrap_err();
+    assert!(err.to_string().contains("unexpected command `REQVOTE`"), "{}", err);
+    assert!(Frame::decode(b"VOTE 1\r\n").is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_clients_see_a_linearizable_history() {
+    let addr = start_server().await;
+    let history = History::new();
+
+    let mut clients = Vec::new();
+    for id in 0..4u32 {
+        let mut client = RecordingClient::new(id, Client::connect(addr).await.unwrap(), history.clone());
+        clients.push(tokio::spawn(async move {
+            // a fixed pseudo-random mix of operations on a couple of keys,
+            // so clients keep running into each other.
+            let mut rng = u64::from(id) + 1;
+            for i in 0..40 {
+                rng = rng.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let key = format!("k{}", (rng >> 33) % 2);
+                let value = format!("{}-{}", id, i);
+                match (rng >> 40) % 3 {
+                    0 => match client.read(&key).await {
+                        Ok(_) | Err(ClientError::NotFound) => {}
+                        Err(err) => panic!("read failed: {}", err),
+                    },
+                    1 => drop(client.write(&key, &value).await.unwrap()),
+                    _ => {
+                        let expected = format!("{}-{}", (rng >> 45) % 4, i.max(1) - 1);
+                        drop(client.compare_and_swap(&key, Some(&expected), &value).await.unwrap())
+                    }
+                }
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    assert!(history.operations().len() >= 150);
+    if let Err(violation) = history.check() {
+        panic!("{}", violation);
+    }
+}
+
+#[tokio::test]
+async fn shutdown_closes_idle_connections_and_returns() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(async move { server::run(listener, stopped).await });
+
+    let mut client = Client::connect(addr).await.unwrap();
+    assert_eq!(client.write("key", "value").await.unwrap(), Frame::Success);
+    let mut idle = Connection::new(TcpStream::connect(addr).await.unwrap());
+    idle.handshake(&Hello::client()).await.unwrap();
+
+    stop.send(()).unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(2), idle.read_frame()).await;
+    assert_eq!(closed.unwrap().unwrap(), None);
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn a_crashed_node_keeps_what_it_acknowledged() {
+    let dir = DataDir::create();
+    let (addr, server) = start_persistent_server(&dir).await;
+    let mut client = Client::connect(addr).await.unwrap();
+    assert_eq!(client.write("a", "1").await.unwrap(), Frame::Success);
+    assert_eq!(client.write("b", "2").await.unwrap(), Frame::Success);
+    assert_eq!(client.delete("a").await.unwrap(), Frame::Bool(true));
+
+    // no chance to shut down: everything acknowledged was already on disk.
+    server.abort();
+    let _ = server.await;
+
+    let (addr, _server) = start_persistent_server(&dir).await;
+    let mut client = Client::connect(addr).await.unwrap();
+    assert_eq!(client.read("b").await.unwrap(), Frame::Value("2".to_string()));
+    assert_eq!(client.exists("a").await.unwrap(), Frame::Bool(false));
+    assert_eq!(client.write("c", "3").await.unwrap(), Frame::Success);
+}
+
+#[tokio::test]
+async fn a_stopped_node_comes_back_with_its_data() {
+    let dir = DataDir::create();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Config {
+        data_dir: Some(dir.path().to_path_buf()),
+        ..Config::default()
+    };
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(server::run_with_config(Listeners::new(listener), config, stopped));
+
+    let mut client = Client::connect(addr).await.unwrap();
+    assert_eq!(client.write("key", "value").await.unwrap(), Frame::Success);
+    drop(client);
+    stop.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap();
+
+    let (addr, _server) = start_persistent_server(&dir).await;
+    let mut client = Client::connect(addr).await.unwrap();
+    assert_eq!(client.read("key").await.unwrap(), Frame::Value("value".to_string()));
+}
+
+async fn start_persistent_server(dir: &DataDir) -> (SocketAddr, tokio::task::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = Config {
+        data_dir: Some(dir.path().to_path_buf()),
+        ..Config::default()
+    };
+
+    let server = tokio::spawn(server::run_with_config(Listeners::new(listener), config, std::future::pending::<()>()));
+    (addr, server)
 }
 
 async fn start_server() -> SocketAddr {
@@ -0,0 +1,429 @@
+// a scow cluster inside turmoil, with knobs for the faults we inject.
+//
+// Every host runs a real node, `server::run_with_disk`, with consensus
+// traffic over simulated UDP, clients over simulated TCP and its log on a
+// simulated disk that survives crashes. Clients on hosts of their own run
+// reads, writes and CAS against whichever node they think leads, recording
+// them in a history, and an observer per node follows that node's store
+// with a WATCH from revision 1.
+//
+// Every step of simulated time is followed by a run of the invariant
+// checker over what the nodes have persisted and applied. A violation (or
+// a node erroring out) panics with the seed. The seed fixes the faults and
+// the workload, but not what the nodes pick at random themselves, like
+// their election timeouts, so a replay may not take exactly the same path.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use scow::clock::SystemClock;
+use scow::command::{CasArgs, Frame, Hello, WatchArgs};
+use scow::config::{Config, Peer};
+use scow::connection::Connection;
+use scow::log::{Log, LogEntry};
+use scow::server::{self, Listeners};
+use tokio::time;
+use turmoil::net::TcpStream;
+use turmoil::Sim;
+
+use crate::common::linearizability::{History, Op, Outcome};
+use crate::disk::SimDisk;
+use crate::invariants::{Checker, Observation};
+use crate::net::{Leaders, SimListener, SimTransport, CLIENT_PORT, PORT};
+
+/// What each node's store has applied, by revision, as its observer saw
+/// it, and how many changes in.
+type Applied = Arc<Mutex<(u64, HashMap<u32, BTreeMap<u64, String>>)>>;
+
+/// Everything observations are made from has changed as often as this
+/// since the last check, unless it is equal: disk and applied changes,
+/// which nodes lead, and which are down.
+type Changes = (Vec<(u32, u64)>, u64, Vec<(u32, u64)>);
+
+/// Clients running operations against the cluster.
+const CLIENTS: u32 = 3;
+/// Keys the clients' operations are spread over.
+const KEYS: u64 = 3;
+/// How long a client waits between operations.
+const PAUSE: Duration = Duration::from_millis(50);
+/// How long a client gives a node to take its connection, and then to
+/// answer. Past that it moves on to the next node.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(200);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Seeds to run each scenario with: SCOW_SIM_SEED if set, otherwise a
+/// fixed spread so CI is reproducible too.
+pub fn seeds() -> Vec<u64> {
+    match std::env::var("SCOW_SIM_SEED") {
+        Ok(seed) => vec![seed.parse().expect("SCOW_SIM_SEED must be a number")],
+        Err(_) => (1..=4).collect(),
+    }
+}
+
+fn host(id: u32) -> String {
+    format!("node{}", id)
+}
+
+fn address(id: u32, port: u16) -> SocketAddr {
+    (turmoil::lookup(host(id)), port).into()
+}
+
+pub struct Cluster<'a> {
+    sim: Sim<'a>,
+    size: u32,
+    seed: u64,
+    disks: HashMap<u32, SimDisk>,
+    leaders: Leaders,
+    applied: Applied,
+    down: HashSet<u32>,
+    /// What was last read off each disk, and how many changes in.
+    persisted: HashMap<u32, (u64, Observation)>,
+    checker: Checker,
+    /// What the checker was last run over.
+    checked: Changes,
+    history: History,
+}
+
+impl<'a> Cluster<'a> {
+    pub fn new(size: u32, seed: u64) -> Cluster<'a> {
+        let mut sim = turmoil::Builder::new()
+            .rng_seed(seed)
+            .simulation_duration(Duration::from_secs(600))
+            .tick_duration(Duration::from_millis(1))
+            .min_message_latency(Duration::from_millis(1))
+            .max_message_latency(Duration::from_millis(5))
+            .enable_random_order()
+            .build();
+        let disks: HashMap<u32, SimDisk> = (1..=size).map(|id| (id, SimDisk::default())).collect();
+        let leaders = Leaders::default();
+        let applied = Applied::default();
+        let history = History::new();
+        for id in 1..=size {
+            let disk = disks[&id].clone();
+            let leaders = leaders.clone();
+            sim.host(host(id), move || node(id, size, disk.clone(), leaders.clone()));
+        }
+        for id in 1..=size {
+            sim.client(format!("observer{}", id), observer(id, applied.clone()));
+        }
+        for id in 1..=CLIENTS {
+            sim.client(format!("client{}", id), client(id, size, history.clone()));
+        }
+        Cluster {
+            sim,
+            size,
+            seed,
+            disks,
+            leaders,
+            applied,
+            down: HashSet::new(),
+            persisted: HashMap::new(),
+            checker: Checker::default(),
+            checked: Changes::default(),
+            history,
+        }
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u32> {
+        1..=self.size
+    }
+
+    /// Runs the simulation for `duration`, checking invariants after every
+    /// step.
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.sim.elapsed() + duration;
+        while self.sim.elapsed() < until {
+            if let Err(err) = self.sim.step() {
+                self.fail(&err.to_string());
+            }
+            // most steps change nothing the checker looks at.
+            let changes = self.changes();
+            if changes == self.checked {
+                continue;
+            }
+            let nodes = self.observe_all();
+            if let Err(violation) = self.checker.check(&nodes) {
+                self.fail(&violation);
+            }
+            self.checked = changes;
+        }
+    }
+
+    /// Runs for `duration` and fails unless the cluster committed
+    /// something new in that time.
+    pub fn assert_progress(&mut self, duration: Duration) {
+        let before = self.highest_commit();
+        self.run_for(duration);
+        if self.highest_commit() <= before {
+            self.fail(&format!("nothing committed past index {}", before));
+        }
+    }
+
+    /// Fails unless every operation the clients ran could have happened in
+    /// some single order, each at an instant while it was in flight.
+    pub fn assert_linearizable(&self) {
+        if let Err(violation) = self.history.check() {
+            self.fail(&violation.to_string());
+        }
+    }
+
+    fn changes(&self) -> Changes {
+        let disks = self.ids().filter(|id| !self.down.contains(id)).map(|id| (id, self.disks[&id].changes())).collect();
+        let mut leaders: Vec<(u32, u64)> = self.leaders.lock().unwrap().iter().map(|(&id, &term)| (id, term)).collect();
+        leaders.sort();
+        (disks, self.applied.lock().unwrap().0, leaders)
+    }
+
+    fn highest_commit(&mut self) -> u64 {
+        self.observe_all().values().map(|o| o.commit_index).max().unwrap_or(0)
+    }
+
+    fn fail(&self, why: &str) -> ! {
+        panic!(
+            "{} after {:?} (rerun with SCOW_SIM_SEED={})",
+            why,
+            self.sim.elapsed(),
+            self.seed
+        );
+    }
+
+    /// What `id` has persisted and applied, or None while it is down.
+    pub fn observe(&mut self, id: u32) -> Option<Observation> {
+        if self.down.contains(&id) {
+            return None;
+        }
+        let disk = &self.disks[&id];
+        let changes = disk.changes();
+        let stale = self.persisted.get(&id).is_none_or(|(seen, _)| *seen != changes);
+        if stale {
+            // opened on a copy: opening drops a torn tail, which is the
+            // node's job, not ours.
+            let log = match Log::with_disk(disk.copy()) {
+                Ok(log) => log,
+                Err(err) => self.fail(&format!("node {} can't open its log: {}", id, err)),
+            };
+            let observation = Observation {
+                term: log.hard_state().term,
+                log: log.entries().iter().map(|e| (e.term, describe(e))).collect(),
+                commit_index: log.commit_index(),
+                ..Observation::default()
+            };
+            self.persisted.insert(id, (changes, observation));
+        }
+        let mut observation = self.persisted[&id].1.clone();
+        observation.leader = self.leaders.lock().unwrap().get(&id) == Some(&observation.term);
+        observation.applied = self.applied.lock().unwrap().1.get(&id).cloned().unwrap_or_default();
+        Some(observation)
+    }
+
+    fn observe_all(&mut self) -> BTreeMap<u32, Observation> {
+        let ids: Vec<u32> = self.ids().collect();
+        ids.into_iter().filter_map(|id| Some((id, self.observe(id)?))).collect()
+    }
+
+    /// Cuts every link between the two groups.
+    pub fn partition(&self, a: &[u32], b: &[u32]) {
+        for &x in a {
+            for &y in b {
+                self.sim.partition(host(x), host(y));
+            }
+        }
+    }
+
+    pub fn isolate(&self, id: u32) {
+        let others: Vec<u32> = self.ids().filter(|&other| other != id).collect();
+        self.partition(&[id], &others);
+    }
+
+    /// Restores every link.
+    pub fn heal(&self) {
+        for a in self.ids() {
+            for b in self.ids().filter(|&b| b > a) {
+                self.sim.repair(host(a), host(b));
+            }
+        }
+    }
+
+    /// Chance of any one message being dropped.
+    pub fn set_loss(&mut self, rate: f64) {
+        self.sim.set_fail_rate(rate);
+    }
+
+    pub fn set_max_latency(&self, latency: Duration) {
+        self.sim.set_max_message_latency(latency);
+    }
+
+    /// Stops `id` dead, part way through writing its log.
+    pub fn crash(&mut self, id: u32) {
+        self.sim.crash(host(id));
+        self.down.insert(id);
+        self.leaders.lock().unwrap().remove(&id);
+        // "log" is the file storage.rs keeps the entries in.
+        self.disks[&id].tear("log");
+    }
+
+    pub fn restart(&mut self, id: u32) {
+        self.sim.bounce(host(id));
+        self.down.remove(&id);
+    }
+}
+
+/// The software on each host: a scow node, with nothing but its disk left
+/// over from before a crash.
+async fn node(id: u32, size: u32, disk: SimDisk, leaders: Leaders) -> turmoil::Result {
+    let others: Vec<u32> = (1..=size).filter(|&p| p != id).collect();
+    let server_id = |id: u32| scow::consensus::ServerId {
+        id,
+        address: address(id, PORT),
+    };
+    let transport = SimTransport::bind(server_id(id), others.iter().map(|&p| server_id(p)).collect(), leaders).await;
+    let listener = SimListener::bind(address(id, CLIENT_PORT)).await;
+    let config = Config {
+        node_id: id,
+        client_addr: address(id, CLIENT_PORT),
+        peer_addr: address(id, PORT),
+        peers: others
+            .iter()
+            .map(|&p| Peer {
+                id: p,
+                address: address(p, PORT),
+                client_addr: address(p, CLIENT_PORT),
+                resp_addr: None,
+                http_addr: None,
+            })
+            .collect(),
+        ..Config::default()
+    };
+    let forever = std::future::pending::<()>();
+    server::run_with_disk(Listeners::new(listener), config, SystemClock, transport, disk, forever).await;
+    Err(format!("node {} stopped", id).into())
+}
+
+async fn connect(id: u32) -> scow::connection::Result<Connection<TcpStream>> {
+    let stream = TcpStream::connect((host(id), CLIENT_PORT)).await?;
+    let mut connection = Connection::new(stream);
+    connection.handshake(&Hello::client()).await?;
+    Ok(connection)
+}
+
+/// Follows node `id`'s store from revision 1, again after every crash,
+/// recording each change under its revision.
+async fn observer(id: u32, applied: Applied) -> turmoil::Result {
+    loop {
+        if let Ok(Ok(mut connection)) = time::timeout(CONNECT_TIMEOUT, connect(id)).await {
+            let watch = WatchArgs {
+                key: String::new(),
+                prefix: true,
+                start_revision: 1,
+            };
+            if connection.write(&Frame::Watch(watch).to_string()).await.is_ok() {
+                // with messages being lost a stream can stall for good, so
+                // one that goes quiet is started over.
+                while let Ok(Ok(Some(frame))) = time::timeout(REPLY_TIMEOUT, connection.read_frame()).await {
+                    match frame {
+                        Frame::Watch(_) => {}
+                        Frame::Event(event) => {
+                            let change = Frame::Event(event.clone()).to_string().trim_end().to_string();
+                            let (changes, applied) = &mut *applied.lock().unwrap();
+                            applied.entry(id).or_default().insert(event.kv.mod_revision, change);
+                            *changes += 1;
+                        }
+                        other => return Err(format!("node {} answered a watch with {:?}", id, other).into()),
+                    }
+                }
+            }
+        }
+        time::sleep(CONNECT_TIMEOUT).await;
+    }
+}
+
+/// Runs operations one at a time, each on the node that answered the last
+/// one, moving on to the next node whenever that one can't help.
+async fn client(id: u32, size: u32, history: History) -> turmoil::Result {
+    // the last value this client saw for each key, for CAS to expect.
+    let mut seen: HashMap<String, Option<String>> = HashMap::new();
+    let mut node = id % size + 1;
+    for round in 0.. {
+        time::sleep(PAUSE).await;
+        let Ok(Ok(mut connection)) = time::timeout(CONNECT_TIMEOUT, connect(node)).await else {
+            node = node % size + 1;
+            continue;
+        };
+        let (op, key, command) = operation(id, round, &seen);
+        let recorded = history.invoke(id, &key, op.clone());
+        let reply = match connection.write(&command.to_string()).await {
+            Ok(()) => match time::timeout(REPLY_TIMEOUT, connection.read_frame()).await {
+                Ok(Ok(frame)) => frame,
+                _ => None,
+            },
+            Err(_) => None,
+        };
+        match outcome(&op, reply) {
+            Some(outcome) => {
+                match &outcome {
+                    Outcome::Value(value) => {
+                        seen.insert(key, value.clone());
+                    }
+                    Outcome::Written | Outcome::Cas(true) => {
+                        if let Op::Write(new) | Op::Cas { new, .. } = &op {
+                            seen.insert(key, Some(new.clone()));
+                        }
+                    }
+                    _ => {}
+                }
+                if outcome == Outcome::Unknown {
+                    node = node % size + 1;
+                }
+                history.complete(recorded, outcome);
+            }
+            None => {
+                history.fail(recorded);
+                node = node % size + 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A read, write or CAS picked from the client and round, so a seed always
+/// replays the same operations. CAS expects whatever this client saw last,
+/// which someone else may have changed since.
+fn operation(client: u32, round: u64, seen: &HashMap<String, Option<String>>) -> (Op, String, Frame) {
+    let key = format!("k{}", (round + u64::from(client)) % KEYS);
+    let value = format!("{}-{}", client, round);
+    match (round * 7 + u64::from(client)) % 3 {
+        0 => (Op::Read, key.clone(), Frame::Read(key)),
+        1 => (Op::Write(value.clone()), key.clone(), Frame::Write(key, value)),
+        _ => {
+            let expected = seen.get(&key).cloned().flatten();
+            let op = Op::Cas {
+                expected: expected.clone(),
+                new: value.clone(),
+            };
+            (op, key.clone(), Frame::CompareAndSwap(CasArgs { key, expected, new: value }))
+        }
+    }
+}
+
+/// How an operation ended, from the node's reply (None if it never came).
+/// None back means it certainly never took effect.
+fn outcome(op: &Op, reply: Option<Frame>) -> Option<Outcome> {
+    match reply {
+        Some(Frame::Value(value)) => Some(Outcome::Value(Some(value))),
+        Some(Frame::Error(e)) if e == "Key not found." => Some(Outcome::Value(None)),
+        Some(Frame::Success) => Some(Outcome::Written),
+        Some(Frame::CasResult(cas)) => Some(Outcome::Cas(cas.succeeded)),
+        // turned away before it reached the log, or overwritten there.
+        Some(Frame::Error(e)) if e == "not the leader" || e.starts_with("proposal lost to a new leader") => None,
+        Some(Frame::Busy(_)) => None,
+        // a read that got no answer changed nothing.
+        _ if *op == Op::Read => None,
+        _ => Some(Outcome::Unknown),
+    }
+}
+
+fn describe(entry: &LogEntry) -> String {
+    entry.command.to_string().trim_end().to_string()
+}
@@ -0,0 +1,74 @@
+// a simulated disk. It lives outside the node's host, so it survives a
+// crash and a restarted node opens its log from it, and the cluster can
+// read what each node has persisted without asking the node.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use scow::disk::Disk;
+
+#[derive(Debug, Clone, Default)]
+pub struct SimDisk {
+    files: Arc<Mutex<Files>>,
+}
+
+#[derive(Debug, Default)]
+struct Files {
+    contents: HashMap<String, Vec<u8>>,
+    /// Bumped by every change, so readers know when to look again.
+    changes: u64,
+}
+
+impl SimDisk {
+    pub fn changes(&self) -> u64 {
+        self.files.lock().unwrap().changes
+    }
+
+    /// A disk of its own with what this one holds now.
+    pub fn copy(&self) -> SimDisk {
+        let files = self.files.lock().unwrap();
+        let copy = Files {
+            contents: files.contents.clone(),
+            changes: files.changes,
+        };
+        SimDisk {
+            files: Arc::new(Mutex::new(copy)),
+        }
+    }
+
+    /// What a crash part way through an append leaves behind: the start of
+    /// a line nobody was told about.
+    pub fn tear(&self, name: &str) {
+        let mut files = self.files.lock().unwrap();
+        files.contents.entry(name.to_string()).or_default().extend_from_slice(b"1 ");
+        files.changes += 1;
+    }
+}
+
+impl Disk for SimDisk {
+    fn read(&mut self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.files.lock().unwrap().contents.get(name).cloned())
+    }
+
+    fn append(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files.contents.entry(name.to_string()).or_default().extend_from_slice(data);
+        files.changes += 1;
+        Ok(())
+    }
+
+    fn truncate(&mut self, name: &str, len: u64) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files.contents.entry(name.to_string()).or_default().truncate(len as usize);
+        files.changes += 1;
+        Ok(())
+    }
+
+    fn replace(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        files.contents.insert(name.to_string(), data.to_vec());
+        files.changes += 1;
+        Ok(())
+    }
+}
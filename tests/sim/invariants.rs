@@ -0,0 +1,117 @@
+// Raft's safety properties (figure 3 of the paper), checked against what
+// every node has persisted and applied after each simulation step. The checker remembers
+// what it has seen, so a violation spread over time is still caught.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// What one node has persisted, and what its store has applied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Observation {
+    pub term: u64,
+    pub leader: bool,
+    /// (term, command) for each log index, starting at 1.
+    pub log: Vec<(u64, String)>,
+    pub commit_index: u64,
+    /// Changes the store has applied, by revision.
+    pub applied: BTreeMap<u64, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Checker {
+    leaders: HashMap<u64, u32>,
+    committed: BTreeMap<u64, (u64, String)>,
+    applied: BTreeMap<u64, String>,
+}
+
+impl Checker {
+    pub fn check(&mut self, nodes: &BTreeMap<u32, Observation>) -> Result<(), String> {
+        self.election_safety(nodes)?;
+        log_matching(nodes)?;
+        self.record_committed(nodes)?;
+        self.leader_completeness(nodes)?;
+        self.state_machine_safety(nodes)
+    }
+
+    /// At most one leader per term.
+    fn election_safety(&mut self, nodes: &BTreeMap<u32, Observation>) -> Result<(), String> {
+        for (&id, node) in nodes.iter().filter(|(_, n)| n.leader) {
+            let leader = *self.leaders.entry(node.term).or_insert(id);
+            if leader != id {
+                return Err(format!("term {} has two leaders: {} and {}", node.term, leader, id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Committed entries never change, whoever reports them.
+    fn record_committed(&mut self, nodes: &BTreeMap<u32, Observation>) -> Result<(), String> {
+        for (&id, node) in nodes {
+            let committed = (node.commit_index as usize).min(node.log.len());
+            for (i, entry) in node.log[..committed].iter().enumerate() {
+                let index = i as u64 + 1;
+                let seen = self.committed.entry(index).or_insert_with(|| entry.clone());
+                if seen != entry {
+                    return Err(format!(
+                        "node {} committed {:?} at index {}, but {:?} was committed there before",
+                        id, entry, index, seen
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A leader holds every entry committed in an earlier term.
+    fn leader_completeness(&self, nodes: &BTreeMap<u32, Observation>) -> Result<(), String> {
+        for (&id, node) in nodes.iter().filter(|(_, n)| n.leader) {
+            for (&index, entry) in self.committed.iter().filter(|(_, (term, _))| *term <= node.term) {
+                if node.log.get(index as usize - 1) != Some(entry) {
+                    return Err(format!(
+                        "leader {} of term {} is missing committed entry {:?} at index {}",
+                        id, node.term, entry, index
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// No two stores ever apply different changes at the same revision.
+    fn state_machine_safety(&mut self, nodes: &BTreeMap<u32, Observation>) -> Result<(), String> {
+        for (&id, node) in nodes {
+            for (&revision, change) in &node.applied {
+                let seen = self.applied.entry(revision).or_insert_with(|| change.clone());
+                if seen != change {
+                    return Err(format!(
+                        "node {} applied {:?} at revision {}, another node applied {:?}",
+                        id, change, revision, seen
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Two logs with an entry of the same index and term agree on everything
+/// up to it.
+fn log_matching(nodes: &BTreeMap<u32, Observation>) -> Result<(), String> {
+    let nodes: Vec<_> = nodes.iter().collect();
+    for (i, (a_id, a)) in nodes.iter().enumerate() {
+        for (b_id, b) in &nodes[i + 1..] {
+            let shared = a.log.len().min(b.log.len());
+            let matching = (0..shared).rev().find(|&j| a.log[j].0 == b.log[j].0);
+            if let Some(j) = matching {
+                if a.log[..=j] != b.log[..=j] {
+                    return Err(format!(
+                        "nodes {} and {} agree on the term at index {} but not on the log before it",
+                        a_id,
+                        b_id,
+                        j + 1
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
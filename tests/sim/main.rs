@@ -0,0 +1,134 @@
+// simulation of whole scow clusters under turmoil: simulated time,
+// partitions, message loss, latency and crashes, with Raft's safety
+// invariants checked after every step and the clients' history checked for
+// linearizability. A failing run prints its seed; rerun it with
+// SCOW_SIM_SEED=<seed> cargo test --test sim.
+
+mod cluster;
+#[path = "../common/mod.rs"]
+mod common;
+mod disk;
+mod invariants;
+mod net;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use cluster::{seeds, Cluster};
+use invariants::{Checker, Observation};
+
+#[test]
+fn three_nodes_with_partitions() {
+    for seed in seeds() {
+        let mut cluster = Cluster::new(3, seed);
+        cluster.run_for(Duration::from_secs(2));
+        cluster.isolate(1);
+        cluster.run_for(Duration::from_secs(2));
+        cluster.heal();
+        cluster.partition(&[1, 2], &[3]);
+        // a majority keeps going on its own.
+        cluster.assert_progress(Duration::from_secs(2));
+        cluster.heal();
+        cluster.assert_progress(Duration::from_secs(2));
+        cluster.assert_linearizable();
+    }
+}
+
+#[test]
+fn five_nodes_with_loss_and_latency() {
+    for seed in seeds() {
+        let mut cluster = Cluster::new(5, seed);
+        cluster.set_loss(0.1);
+        cluster.set_max_latency(Duration::from_millis(50));
+        cluster.run_for(Duration::from_secs(4));
+        cluster.set_loss(0.0);
+        cluster.assert_progress(Duration::from_secs(2));
+        cluster.assert_linearizable();
+    }
+}
+
+#[test]
+fn five_nodes_with_crashes_and_restarts() {
+    for seed in seeds() {
+        let mut cluster = Cluster::new(5, seed);
+        cluster.run_for(Duration::from_secs(1));
+        for id in [1, 3, 5] {
+            cluster.crash(id);
+            assert!(cluster.observe(id).is_none());
+            cluster.run_for(Duration::from_secs(1));
+            cluster.restart(id);
+            cluster.run_for(Duration::from_secs(1));
+            assert!(cluster.observe(id).is_some());
+        }
+        cluster.assert_progress(Duration::from_secs(2));
+        cluster.assert_linearizable();
+    }
+}
+
+// the checker itself has to catch what it claims to.
+
+fn entry(term: u64, command: &str) -> (u64, String) {
+    (term, command.to_string())
+}
+
+fn check(nodes: &[(u32, Observation)]) -> Result<(), String> {
+    Checker::default().check(&nodes.iter().cloned().collect::<BTreeMap<_, _>>())
+}
+
+#[test]
+fn checker_catches_two_leaders_in_a_term() {
+    let leader = Observation {
+        term: 2,
+        leader: true,
+        ..Observation::default()
+    };
+    let err = check(&[(1, leader.clone()), (2, leader)]).unwrap_err();
+    assert!(err.contains("two leaders"), "{}", err);
+}
+
+#[test]
+fn checker_catches_diverging_logs() {
+    let a = Observation {
+        log: vec![entry(1, "x"), entry(2, "y")],
+        ..Observation::default()
+    };
+    let b = Observation {
+        log: vec![entry(1, "z"), entry(2, "y")],
+        ..Observation::default()
+    };
+    let err = check(&[(1, a), (2, b)]).unwrap_err();
+    assert!(err.contains("agree on the term"), "{}", err);
+}
+
+#[test]
+fn checker_catches_a_leader_missing_committed_entries() {
+    let follower = Observation {
+        term: 1,
+        log: vec![entry(1, "x")],
+        commit_index: 1,
+        ..Observation::default()
+    };
+    let leader = Observation {
+        term: 2,
+        leader: true,
+        ..Observation::default()
+    };
+    let err = check(&[(1, follower), (2, leader)]).unwrap_err();
+    assert!(err.contains("missing committed entry"), "{}", err);
+}
+
+#[test]
+fn checker_catches_different_applied_commands() {
+    let mut checker = Checker::default();
+    let applied = |command: &str| {
+        let node = Observation {
+            applied: BTreeMap::from([(1, command.to_string())]),
+            ..Observation::default()
+        };
+        BTreeMap::from([(1, node)])
+    };
+    checker.check(&applied("x")).unwrap();
+    // the same node later claiming otherwise counts too.
+    let err = checker.check(&applied("y")).unwrap_err();
+    assert!(err.contains("applied"), "{}", err);
+}
@@ -0,0 +1,96 @@
+// the network a simulated node sees: a Transport over turmoil's simulated
+// UDP, and a client listener over its simulated TCP. Datagrams suit Raft:
+// they can be lost, delayed or reordered, which is exactly what we want to
+// throw at it, and nothing has to redial after a partition heals.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use scow::command::Frame;
+use scow::consensus::{Message, ServerId};
+use scow::server::Listener;
+use scow::transport::Transport;
+use turmoil::net::{TcpListener, TcpStream, UdpSocket};
+
+/// Where nodes exchange consensus traffic.
+pub const PORT: u16 = 9000;
+/// Where nodes take native clients.
+pub const CLIENT_PORT: u16 = 9001;
+
+/// The term each node last sent AppendEntries in, which only a leader does.
+pub type Leaders = Arc<Mutex<HashMap<u32, u64>>>;
+
+pub struct SimTransport {
+    local: ServerId,
+    socket: UdpSocket,
+    peers: HashMap<SocketAddr, ServerId>,
+    leaders: Leaders,
+}
+
+impl SimTransport {
+    pub async fn bind(local: ServerId, peers: Vec<ServerId>, leaders: Leaders) -> SimTransport {
+        let socket = UdpSocket::bind(("0.0.0.0", PORT)).await.unwrap();
+        SimTransport {
+            local,
+            socket,
+            peers: peers.into_iter().map(|p| (p.address, p)).collect(),
+            leaders,
+        }
+    }
+}
+
+impl Transport for SimTransport {
+    fn local(&self) -> ServerId {
+        self.local
+    }
+
+    fn send(&self, to: ServerId, message: Message) {
+        if let Message::AppendEntries { term, .. } = &message {
+            self.leaders.lock().unwrap().insert(self.local.id, *term);
+        }
+        let frame = Frame::Raft(message).to_string();
+        // a full buffer is just another lost datagram.
+        let _ = self.socket.try_send_to(frame.as_bytes(), to.address);
+    }
+
+    async fn recv(&mut self) -> Option<(ServerId, Message)> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let (len, from) = self.socket.recv_from(&mut buf).await.ok()?;
+            let Some(peer) = self.peers.get(&from) else {
+                continue;
+            };
+            if let Ok(Frame::Raft(message)) = Frame::decode(&buf[..len]) {
+                return Some((*peer, message));
+            }
+        }
+    }
+}
+
+/// A node's client port. It answers with the address the other nodes
+/// know it by, not the wildcard it is bound to.
+pub struct SimListener {
+    listener: TcpListener,
+    address: SocketAddr,
+}
+
+impl SimListener {
+    pub async fn bind(address: SocketAddr) -> SimListener {
+        let listener = TcpListener::bind(("0.0.0.0", address.port())).await.unwrap();
+        SimListener { listener, address }
+    }
+}
+
+impl Listener for SimListener {
+    type Stream = TcpStream;
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+
+    async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.listener.accept().await
+    }
+}
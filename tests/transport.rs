@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+use scow::command::Hello;
+use scow::consensus::{Message, ServerId};
+use scow::peer::{self, Backoff};
+use scow::transport::{ChannelNetwork, TcpTransport, Transport};
+
+fn server(id: u32, port: u16) -> ServerId {
+    ServerId {
+        id,
+        address: ([127, 0, 0, 1], port).into(),
+    }
+}
+
+fn vote(term: u64) -> Message {
+    Message::Vote { term, granted: true }
+}
+
+/// A asks B for a vote and B answers, whatever carries the messages.
+async fn round_trip<T: Transport>(a: &mut T, b: &mut T) {
+    a.send(
+        b.local(),
+        Message::RequestVote {
+            term: 3,
+            last_log_index: 10,
+            last_log_term: 2,
+        },
+    );
+    let (from, message) = timeout(Duration::from_secs(5), b.recv()).await.unwrap().unwrap();
+    assert_eq!(from, a.local());
+    assert!(matches!(message, Message::RequestVote { term: 3, .. }));
+
+    b.send(from, vote(3));
+    let (from, message) = timeout(Duration::from_secs(5), a.recv()).await.unwrap().unwrap();
+    assert_eq!((from, message), (b.local(), vote(3)));
+}
+
+#[tokio::test]
+async fn channel_transport_delivers() {
+    let network = ChannelNetwork::new();
+    let mut a = network.join(server(1, 1));
+    let mut b = network.join(server(2, 2));
+    round_trip(&mut a, &mut b).await;
+}
+
+#[tokio::test]
+async fn channel_transport_drops_messages_for_departed_nodes() {
+    let network = ChannelNetwork::new();
+    let mut a = network.join(server(1, 1));
+    let b = network.join(server(2, 2));
+    drop(b);
+
+    a.send(server(2, 2), vote(1));
+    let mut b = network.join(server(2, 2));
+    b.send(server(1, 1), vote(2));
+
+    // only the message sent after b came back arrives anywhere.
+    assert_eq!(a.recv().await.unwrap().1, vote(2));
+    assert!(timeout(Duration::from_millis(50), b.recv()).await.is_err());
+}
+
+#[tokio::test]
+async fn tcp_transport_delivers() {
+    let listener_a = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listener_b = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let a_id = server(1, listener_a.local_addr().unwrap().port());
+    let b_id = server(2, listener_b.local_addr().unwrap().port());
+
+    let mut a = transport(a_id, b_id);
+    let mut b = transport(b_id, a_id);
+    tokio::spawn(peer::serve(listener_a, Hello::node(1, "scow"), HashSet::from([2]), a.inbox()));
+    tokio::spawn(peer::serve(listener_b, Hello::node(2, "scow"), HashSet::from([1]), b.inbox()));
+
+    for _ in 0..200 {
+        if a.pool().is_connected(&b_id) && b.pool().is_connected(&a_id) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    round_trip(&mut a, &mut b).await;
+}
+
+fn transport(local: ServerId, peer: ServerId) -> TcpTransport {
+    let backoff = Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(100),
+    };
+    TcpTransport::new(
+        local,
+        Hello::node(local.id, "scow"),
+        vec![peer],
+        backoff,
+        Duration::from_secs(1),
+    )
+}
@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+use scow::client::Client;
+use scow::command::{Compare, EventKind, Frame, Txn, TxnOp, TxnResponse, TxnResult};
+use scow::server;
+
+#[tokio::test]
+async fn move_between_queues() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+    client.write("queue/in/1", "job").await.unwrap();
+
+    let txn = Txn::new()
+        .when(Compare::exists("queue/in/1", true))
+        .and_then(TxnOp::delete("queue/in/1"))
+        .and_then(TxnOp::put("queue/done/1", "job"))
+        .or_else(TxnOp::get("queue/done/1"));
+    let result = client.txn(txn.clone()).await.unwrap();
+    assert_eq!(
+        result,
+        Frame::TxnResult(TxnResult {
+            succeeded: true,
+            responses: vec![TxnResponse::Delete(true), TxnResponse::Put(2)],
+        })
+    );
+
+    // running it again takes the failure branch.
+    match client.txn(txn).await.unwrap() {
+        Frame::TxnResult(result) => {
+            assert!(!result.succeeded);
+            match &result.responses[..] {
+                [TxnResponse::Get(Some(kv))] => assert_eq!(kv.key, "queue/done/1"),
+                other => panic!("unexpected responses {:?}", other),
+            }
+        }
+        other => panic!("expected a txn result, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn compares_value_and_version() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+    client.write("config", "v1").await.unwrap();
+    client.write("config", "v2").await.unwrap();
+
+    let stale = Txn::new()
+        .when(Compare::value("config", "v2"))
+        .when(Compare::version("config", 1))
+        .and_then(TxnOp::put("config", "v3"));
+    assert_eq!(
+        client.txn(stale).await.unwrap(),
+        Frame::TxnResult(TxnResult {
+            succeeded: false,
+            responses: vec![],
+        })
+    );
+
+    let fresh = Txn::new()
+        .when(Compare::value("config", "v2"))
+        .when(Compare::version("config", 2))
+        .and_then(TxnOp::put("config", "v3 with spaces"));
+    match client.txn(fresh).await.unwrap() {
+        Frame::TxnResult(result) => assert!(result.succeeded),
+        other => panic!("expected a txn result, got {:?}", other),
+    }
+    assert_eq!(
+        client.read("config").await.unwrap(),
+        Frame::Value("v3 with spaces".to_string())
+    );
+}
+
+#[tokio::test]
+async fn watchers_see_every_change_in_a_txn() {
+    let addr = start_server().await;
+    let mut client = Client::connect(addr).await.unwrap();
+    let mut watcher = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("queue/", true, 0)
+        .await
+        .unwrap();
+
+    let txn = Txn::new()
+        .and_then(TxnOp::put("queue/a", "1"))
+        .and_then(TxnOp::put("queue/b", "2"))
+        .and_then(TxnOp::delete("queue/a"));
+    client.txn(txn).await.unwrap();
+
+    let mut kinds = vec![];
+    for _ in 0..3 {
+        let event = watcher.next_event().await.unwrap().unwrap();
+        assert_eq!(event.kv.mod_revision, 1);
+        kinds.push((event.kind, event.kv.key));
+    }
+    assert_eq!(
+        kinds,
+        vec![
+            (EventKind::Put, "queue/a".to_string()),
+            (EventKind::Put, "queue/b".to_string()),
+            (EventKind::Delete, "queue/a".to_string()),
+        ]
+    );
+}
+
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move { server::run(listener, tokio::signal::ctrl_c()).await });
+    addr
+}
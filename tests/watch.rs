@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time;
+
+use scow::client::{Client, ClientError, WriteBatch};
+use scow::command::EventKind;
+use scow::server;
+
+#[tokio::test]
+async fn replays_history_then_streams_live() {
+    let addr = start_server().await;
+    let mut writer = Client::connect(addr).await.unwrap();
+    writer.write("key", "one").await.unwrap();
+    writer.write("key", "two").await.unwrap();
+
+    let mut watcher = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("key", false, 1)
+        .await
+        .unwrap();
+
+    let first = watcher.next_event().await.unwrap().unwrap();
+    assert_eq!((first.kind, first.kv.value.as_str()), (EventKind::Put, "one"));
+    let second = watcher.next_event().await.unwrap().unwrap();
+    assert_eq!((second.kind, second.kv.value.as_str()), (EventKind::Put, "two"));
+
+    writer.delete("key").await.unwrap();
+    let deleted = watcher.next_event().await.unwrap().unwrap();
+    assert_eq!(deleted.kind, EventKind::Delete);
+    assert_eq!(deleted.kv.mod_revision, 3);
+    assert_eq!(watcher.last_revision(), 3);
+}
+
+#[tokio::test]
+async fn prefix_watch_only_sees_matching_keys() {
+    let addr = start_server().await;
+    let mut writer = Client::connect(addr).await.unwrap();
+    let mut watcher = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("services/", true, 0)
+        .await
+        .unwrap();
+
+    writer.write("other/a", "x").await.unwrap();
+    writer.write("services/web", "10.0.0.1").await.unwrap();
+
+    let event = watcher.next_event().await.unwrap().unwrap();
+    assert_eq!(event.kv.key, "services/web");
+    assert_eq!(event.kv.mod_revision, 2);
+}
+
+#[tokio::test]
+async fn resume_carries_on_after_last_event() {
+    let addr = start_server().await;
+    let mut writer = Client::connect(addr).await.unwrap();
+    writer.write("key", "one").await.unwrap();
+
+    let mut watcher = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("key", false, 1)
+        .await
+        .unwrap();
+    assert_eq!(watcher.next_event().await.unwrap().unwrap().kv.value, "one");
+
+    writer.write("key", "two").await.unwrap();
+    let mut watcher = watcher.resume(addr).await.unwrap();
+    let next = watcher.next_event().await.unwrap().unwrap();
+    assert_eq!(next.kv.value, "two");
+}
+
+#[tokio::test]
+async fn resume_before_any_event_starts_where_the_watch_did() {
+    let addr = start_server().await;
+    let watcher = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("key", false, 0)
+        .await
+        .unwrap();
+    assert_eq!(watcher.last_revision(), 1);
+
+    let mut writer = Client::connect(addr).await.unwrap();
+    writer.write("key", "one").await.unwrap();
+    let mut watcher = watcher.resume(addr).await.unwrap();
+    let next = watcher.next_event().await.unwrap().unwrap();
+    assert_eq!(next.kv.value, "one");
+}
+
+#[tokio::test]
+async fn a_watch_from_now_that_lags_before_its_first_event_catches_up() {
+    let addr = start_server().await;
+    let mut watcher = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("key/", true, 0)
+        .await
+        .unwrap();
+
+    // more events at once than the live channel holds, so the watch lags
+    // before it has sent any.
+    let mut batch = WriteBatch::new();
+    for i in 0..3000 {
+        batch.push(&format!("key/{}", i), "x");
+    }
+    let mut writer = Client::connect(addr).await.unwrap();
+    writer.write_batch(batch).await.unwrap();
+
+    let mut keys = HashSet::new();
+    for _ in 0..3000 {
+        let event = time::timeout(Duration::from_secs(5), watcher.next_event())
+            .await
+            .expect("the lagged events were dropped")
+            .unwrap()
+            .unwrap();
+        assert!(keys.insert(event.kv.key.clone()), "{} delivered twice", event.kv.key);
+    }
+}
+
+#[tokio::test]
+async fn compacted_start_is_rejected() {
+    let addr = start_server().await;
+    let mut writer = Client::connect(addr).await.unwrap();
+    writer.write("key", "one").await.unwrap();
+    writer.write("key", "two").await.unwrap();
+    writer.compact(2).await.unwrap();
+
+    let err = Client::connect(addr)
+        .await
+        .unwrap()
+        .watch("key", false, 1)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err, ClientError::Rejected("revision 1 has been compacted".to_string()));
+}
+
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move { server::run(listener, tokio::signal::ctrl_c()).await });
+    addr
+}